@@ -0,0 +1,66 @@
+//! Concurrent connections updating the `SpanStore`: a single shard stands
+//! for the former design, one `Mutex<HashMap>` shared by every connection.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use haproxy_spoa_rust::store::{Retain, SpanStore, StoreSettings};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const CONNECTIONS: usize = 8;
+const REQUESTS: usize = 1000;
+const EVENTS: usize = 3;
+
+/// Start `REQUESTS` requests, then send them their events in turn, the last
+/// one removing them.
+fn connection(store: &SpanStore<Vec<usize>>, connection: usize, iteration: u64) {
+    let keys: Vec<String> = (0..REQUESTS)
+        .map(|i| format!("{}-{}-{}", iteration, connection, i))
+        .collect();
+    for event in 0..EVENTS {
+        for key in &keys {
+            let retain = if event + 1 == EVENTS {
+                Retain::Remove
+            } else {
+                Retain::Keep
+            };
+            store
+                .update(key, "opentracing:event", |events| {
+                    events.push(event);
+                    ((), retain)
+                })
+                .unwrap();
+        }
+    }
+}
+
+fn concurrent_updates(c: &mut Criterion) {
+    let mut group = c.benchmark_group("concurrent_updates");
+    group.throughput(Throughput::Elements(
+        (CONNECTIONS * REQUESTS * EVENTS) as u64,
+    ));
+    for shards in [1, StoreSettings::default().shards] {
+        let store = SpanStore::new(StoreSettings {
+            shards,
+            ..StoreSettings::default()
+        });
+        group.bench_with_input(BenchmarkId::new("shards", shards), &store, |b, store| {
+            b.iter_custom(|iterations| {
+                let mut elapsed = Duration::ZERO;
+                for iteration in 0..iterations {
+                    let start = Instant::now();
+                    thread::scope(|scope| {
+                        for i in 0..CONNECTIONS {
+                            scope.spawn(move || connection(store, i, iteration));
+                        }
+                    });
+                    elapsed += start.elapsed();
+                }
+                elapsed
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, concurrent_updates);
+criterion_main!(benches);
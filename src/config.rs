@@ -0,0 +1,557 @@
+//! Configuration of the agent.
+//!
+//! The settings are read, by increasing precedence, from a configuration
+//! file, the environment variables and the command line arguments, those
+//! given nowhere keep their default. The file is written in TOML, or in YAML
+//! if its extension is `.yaml` or `.yml`, its keys are those of `Config`, in
+//! kebab-case:
+//!
+//! ```toml
+//! service-name = "spoa"
+//! listen = ["0.0.0.0:7000", "unix@/run/spoa.sock"]
+//!
+//! [unix-socket]
+//! mode = "660"
+//!
+//! [spop]
+//! max-frame-size = 16380
+//! async = true
+//!
+//! [tracing]
+//! messages = "opentracing:*"
+//! propagators = ["tracecontext", "baggage"]
+//!
+//! [exporter]
+//! type = "otlp"
+//! endpoint = "http://collector:4317"
+//! ```
+//!
+//! The environment variables are those the agent read before the file was
+//! supported, e.g. `PORT` or `OTEL_TRACES_EXPORTER`, see `Config::apply_env`.
+//! The durations are given in milliseconds. `Config::settings` checks every
+//! setting, `--check-config` stops there.
+
+use crate::connection::ConnectionSettings;
+use crate::exporter::{
+    parse_headers, Compression, Exporter, ExporterSettings, MetricsExporter, MetricsSettings,
+};
+use crate::listener::{ListenAddr, UnixSocketSettings};
+use crate::logging::{LogFormat, LogSettings};
+use crate::negotiation::MIN_FRAME_SIZE;
+use crate::otel::{IdFallback, OtelSettings};
+use crate::propagation::{parse_propagators, Propagator, DEFAULT_PROPAGATORS};
+use crate::router::UnknownMessages;
+use crate::store::Eviction;
+
+use clap::Parser;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+const DEFAULT_SERVICE_NAME: &str = "spoa";
+const DEFAULT_LISTEN: &str = "0.0.0.0:7000";
+const DEFAULT_MESSAGES: &str = "opentracing:*";
+
+/// Command line arguments of the agent.
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "HAProxy SPOA tracing the requests with OpenTelemetry"
+)]
+pub struct Args {
+    /// Configuration file, in TOML, or in YAML (`.yaml`, `.yml`).
+    #[arg(short, long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Address of a SPOP listener, `ip:port` or `unix@/path`, repeated to
+    /// listen on several ones.
+    #[arg(long, value_name = "ADDR")]
+    pub listen: Vec<String>,
+
+    /// Service name of the spans.
+    #[arg(long, value_name = "NAME")]
+    pub service_name: Option<String>,
+
+    /// Log filter, e.g. `info` or `warn,haproxy_spoa_rust::server=debug`.
+    #[arg(long, value_name = "FILTER")]
+    pub log_level: Option<String>,
+
+    /// Check the configuration, and exit.
+    #[arg(long)]
+    pub check_config: bool,
+}
+
+impl Args {
+    /// Configuration given by the file, the environment variables, read by
+    /// `var`, and the arguments.
+    pub fn config(&self, var: impl Fn(&str) -> Option<String>) -> Result<Config, String> {
+        let mut config = match &self.config {
+            Some(path) => Config::from_file(path)?,
+            None => Config::default(),
+        };
+        config.apply_env(var)?;
+        if !self.listen.is_empty() {
+            config.listen = self.listen.clone();
+        }
+        if let Some(service_name) = &self.service_name {
+            config.service_name = Some(service_name.clone());
+        }
+        if let Some(level) = &self.log_level {
+            config.log.level = Some(level.clone());
+        }
+        Ok(config)
+    }
+}
+
+/// Settings as given by the configuration file, `None` if absent.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// Service name of the spans, `spoa` by default.
+    pub service_name: Option<String>,
+
+    /// Addresses of the SPOP listeners, `ip:port` or `unix@/path`, a single
+    /// one or a list, `0.0.0.0:7000` by default.
+    #[serde(deserialize_with = "one_or_many")]
+    pub listen: Vec<String>,
+
+    pub unix_socket: UnixSocketConfig,
+    pub spop: SpopConfig,
+    pub tracing: TracingConfig,
+    pub exporter: ExporterConfig,
+    pub log: LogConfig,
+    pub metrics: MetricsConfig,
+}
+
+/// Permissions of the Unix domain sockets, see `UnixSocketSettings`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct UnixSocketConfig {
+    /// Permission bits, in octal, e.g. `660`.
+    pub mode: Option<String>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+/// Protocol limits and capabilities, see `ConnectionSettings`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SpopConfig {
+    pub max_frame_size: Option<u32>,
+    pub fragmentation: Option<bool>,
+    pub max_reassembled_size: Option<usize>,
+    #[serde(rename = "async")]
+    pub asynchronous: Option<bool>,
+    pub max_in_flight: Option<usize>,
+    pub drain_timeout_ms: Option<u64>,
+}
+
+/// How the messages are turned into spans, see `OtelSettings`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct TracingConfig {
+    /// Messages traced, a name or a prefix followed by `*`.
+    pub messages: Option<String>,
+    pub unknown_messages: Option<String>,
+    pub propagators: Option<Vec<String>>,
+    pub baggage_attributes: Option<bool>,
+    /// An empty name identifies the requests by their stream.
+    pub request_id_arg: Option<String>,
+    pub request_id_fallback: Option<String>,
+    pub engine_scoped_ids: Option<bool>,
+    pub recorded_headers: Option<Vec<String>>,
+    pub span_store: SpanStoreConfig,
+}
+
+/// See `StoreSettings`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SpanStoreConfig {
+    pub ttl_ms: Option<u64>,
+    pub capacity: Option<usize>,
+    pub eviction: Option<String>,
+    pub shards: Option<usize>,
+}
+
+/// Exporter of the spans, see `ExporterSettings`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ExporterConfig {
+    #[serde(rename = "type")]
+    pub exporter: Option<String>,
+    pub endpoint: Option<String>,
+    pub headers: Option<BTreeMap<String, String>>,
+    pub timeout_ms: Option<u64>,
+    pub compression: Option<String>,
+    pub batch: BatchConfig,
+}
+
+/// See `BatchSettings`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct BatchConfig {
+    pub max_queue_size: Option<usize>,
+    pub max_export_batch_size: Option<usize>,
+    pub schedule_delay_ms: Option<u64>,
+    pub export_timeout_ms: Option<u64>,
+}
+
+/// See `LogSettings`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct LogConfig {
+    pub level: Option<String>,
+    pub format: Option<String>,
+}
+
+/// Prometheus listener, and exporter of the RED metrics, see
+/// `MetricsSettings`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct MetricsConfig {
+    /// Address serving the Prometheus metrics, none by default.
+    pub listen: Option<String>,
+    pub exporter: Option<String>,
+    pub endpoint: Option<String>,
+    pub interval_ms: Option<u64>,
+    pub timeout_ms: Option<u64>,
+    pub attribute_limit: Option<usize>,
+}
+
+/// Settings of the agent, checked.
+#[derive(Clone, Debug)]
+pub struct Settings {
+    pub service_name: String,
+    pub listen: Vec<ListenAddr>,
+    pub unix_socket: UnixSocketSettings,
+    pub connection: ConnectionSettings,
+    pub messages: String,
+    pub unknown_messages: UnknownMessages,
+    pub propagators: Vec<Propagator>,
+    pub exporter: ExporterSettings,
+    pub otel: OtelSettings,
+    pub log: LogSettings,
+    pub metrics: MetricsSettings,
+    pub metrics_listen: Option<SocketAddr>,
+}
+
+impl Config {
+    /// Read the file at `path`, in YAML if its extension is `.yaml` or
+    /// `.yml`, in TOML otherwise.
+    pub fn from_file(path: &Path) -> Result<Config, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|err| format!("unable to read {}: {}", path.display(), err))?;
+        let config = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("yaml") | Some("yml") => Config::from_yaml(&content),
+            _ => Config::from_toml(&content),
+        };
+        config.map_err(|err| format!("invalid configuration {}: {}", path.display(), err))
+    }
+
+    pub fn from_toml(content: &str) -> Result<Config, String> {
+        toml::from_str(content).map_err(|err| err.to_string())
+    }
+
+    pub fn from_yaml(content: &str) -> Result<Config, String> {
+        serde_yaml::from_str(content).map_err(|err| err.to_string())
+    }
+
+    /// Override the settings given by the environment variables, read by
+    /// `var`.
+    pub fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), String> {
+        let var = &var;
+        if let Some(port) = var("PORT") {
+            let port: u16 = parse("PORT", &port)?;
+            self.listen = vec![format!("0.0.0.0:{}", port)];
+        }
+        env(var, "SERVICE_NAME", &mut self.service_name)?;
+
+        let spop = &mut self.spop;
+        env(var, "MAX_FRAME_SIZE", &mut spop.max_frame_size)?;
+        env(var, "FRAGMENTATION", &mut spop.fragmentation)?;
+        env(var, "MAX_REASSEMBLED_SIZE", &mut spop.max_reassembled_size)?;
+        env(var, "ASYNC", &mut spop.asynchronous)?;
+        env(var, "MAX_IN_FLIGHT", &mut spop.max_in_flight)?;
+        env(var, "DRAIN_TIMEOUT_MS", &mut spop.drain_timeout_ms)?;
+
+        let tracing = &mut self.tracing;
+        env(var, "UNKNOWN_MESSAGES", &mut tracing.unknown_messages)?;
+        if let Some(propagators) = var("OTEL_PROPAGATORS") {
+            tracing.propagators = Some(vec![propagators]);
+        }
+        env(var, "BAGGAGE_ATTRIBUTES", &mut tracing.baggage_attributes)?;
+        env(var, "REQUEST_ID_ARG", &mut tracing.request_id_arg)?;
+        env(var, "REQUEST_ID_FALLBACK", &mut tracing.request_id_fallback)?;
+        env(var, "ENGINE_SCOPED_IDS", &mut tracing.engine_scoped_ids)?;
+        let store = &mut tracing.span_store;
+        env(var, "SPAN_TTL_MS", &mut store.ttl_ms)?;
+        env(var, "SPAN_STORE_CAPACITY", &mut store.capacity)?;
+        env(var, "SPAN_STORE_EVICTION", &mut store.eviction)?;
+        env(var, "SPAN_STORE_SHARDS", &mut store.shards)?;
+
+        let exporter = &mut self.exporter;
+        env(var, "OTEL_TRACES_EXPORTER", &mut exporter.exporter)?;
+        env(var, "OTEL_EXPORTER_ENDPOINT", &mut exporter.endpoint)?;
+        if let Some(headers) = var("OTEL_EXPORTER_HEADERS") {
+            let headers = parse_headers(&headers)
+                .map_err(|err| format!("invalid OTEL_EXPORTER_HEADERS: {}", err))?;
+            exporter.headers = Some(headers.into_iter().collect());
+        }
+        env(var, "OTEL_EXPORTER_TIMEOUT_MS", &mut exporter.timeout_ms)?;
+        env(var, "OTEL_EXPORTER_COMPRESSION", &mut exporter.compression)?;
+        let batch = &mut exporter.batch;
+        env(var, "OTEL_BSP_MAX_QUEUE_SIZE", &mut batch.max_queue_size)?;
+        env(
+            var,
+            "OTEL_BSP_MAX_EXPORT_BATCH_SIZE",
+            &mut batch.max_export_batch_size,
+        )?;
+        env(var, "OTEL_BSP_SCHEDULE_DELAY", &mut batch.schedule_delay_ms)?;
+        env(var, "OTEL_BSP_EXPORT_TIMEOUT", &mut batch.export_timeout_ms)?;
+
+        env(var, "LOG_LEVEL", &mut self.log.level)?;
+        env(var, "LOG_FORMAT", &mut self.log.format)?;
+
+        let metrics = &mut self.metrics;
+        if let Some(port) = var("METRICS_PORT") {
+            let port: u16 = parse("METRICS_PORT", &port)?;
+            metrics.listen = Some(format!("0.0.0.0:{}", port));
+        }
+        env(var, "OTEL_METRICS_EXPORTER", &mut metrics.exporter)?;
+        env(var, "OTEL_METRICS_ENDPOINT", &mut metrics.endpoint)?;
+        env(var, "OTEL_METRIC_EXPORT_INTERVAL", &mut metrics.interval_ms)?;
+        env(var, "OTEL_METRIC_EXPORT_TIMEOUT", &mut metrics.timeout_ms)?;
+        env(var, "METRIC_ATTRIBUTE_LIMIT", &mut metrics.attribute_limit)?;
+        Ok(())
+    }
+
+    /// Settings of the agent, or the first invalid one.
+    pub fn settings(&self) -> Result<Settings, String> {
+        let spop = &self.spop;
+        let mut connection = ConnectionSettings::default();
+        set(&mut connection.max_frame_size, spop.max_frame_size);
+        if connection.max_frame_size < MIN_FRAME_SIZE {
+            return Err(format!(
+                "invalid spop.max-frame-size {}: below the minimum of {}",
+                connection.max_frame_size, MIN_FRAME_SIZE
+            ));
+        }
+        set(&mut connection.fragmentation, spop.fragmentation);
+        set(
+            &mut connection.max_reassembled_size,
+            spop.max_reassembled_size,
+        );
+        set(&mut connection.asynchronous, spop.asynchronous);
+        non_zero("spop.max-in-flight", spop.max_in_flight)?;
+        set(&mut connection.max_in_flight, spop.max_in_flight);
+        set_ms(&mut connection.drain_timeout, spop.drain_timeout_ms);
+
+        let tracing = &self.tracing;
+        let mut otel = OtelSettings::default();
+        set(&mut otel.baggage_attributes, tracing.baggage_attributes);
+        if let Some(id_arg) = &tracing.request_id_arg {
+            otel.id_arg = Some(id_arg.clone()).filter(|arg| !arg.is_empty());
+        }
+        parse_to::<IdFallback>(
+            &mut otel.id_fallback,
+            "tracing.request-id-fallback",
+            &tracing.request_id_fallback,
+        )?;
+        set(&mut otel.engine_scoped, tracing.engine_scoped_ids);
+        if let Some(headers) = &tracing.recorded_headers {
+            otel.recorded_headers = headers.iter().map(|h| h.to_lowercase()).collect();
+        }
+        set(
+            &mut otel.metric_attribute_limit,
+            self.metrics.attribute_limit,
+        );
+        let store = &tracing.span_store;
+        set_ms(&mut otel.store.ttl, store.ttl_ms);
+        non_zero("tracing.span-store.capacity", store.capacity)?;
+        set(&mut otel.store.capacity, store.capacity);
+        parse_to::<Eviction>(
+            &mut otel.store.eviction,
+            "tracing.span-store.eviction",
+            &store.eviction,
+        )?;
+        set(&mut otel.store.shards, store.shards);
+
+        let mut unknown_messages = UnknownMessages::Log;
+        parse_to(
+            &mut unknown_messages,
+            "tracing.unknown-messages",
+            &tracing.unknown_messages,
+        )?;
+        let propagators = match &tracing.propagators {
+            Some(list) => parse_propagators(&list.join(","))
+                .map_err(|err| format!("invalid tracing.propagators: {}", err))?,
+            None => DEFAULT_PROPAGATORS.to_vec(),
+        };
+
+        let mut exporter = ExporterSettings::default();
+        let config = &self.exporter;
+        parse_to::<Exporter>(&mut exporter.exporter, "exporter.type", &config.exporter)?;
+        exporter.endpoint = config.endpoint.clone();
+        if let Some(headers) = &config.headers {
+            exporter.headers = headers.clone().into_iter().collect();
+        }
+        exporter.timeout = config.timeout_ms.map(Duration::from_millis);
+        parse_to::<Compression>(
+            &mut exporter.compression,
+            "exporter.compression",
+            &config.compression,
+        )?;
+        let batch = &config.batch;
+        non_zero("exporter.batch.max-queue-size", batch.max_queue_size)?;
+        non_zero(
+            "exporter.batch.max-export-batch-size",
+            batch.max_export_batch_size,
+        )?;
+        non_zero("exporter.batch.schedule-delay-ms", batch.schedule_delay_ms)?;
+        set(&mut exporter.batch.max_queue_size, batch.max_queue_size);
+        set(
+            &mut exporter.batch.max_export_batch_size,
+            batch.max_export_batch_size,
+        );
+        set_ms(&mut exporter.batch.scheduled_delay, batch.schedule_delay_ms);
+        set_ms(
+            &mut exporter.batch.max_export_timeout,
+            batch.export_timeout_ms,
+        );
+        exporter.validate()?;
+
+        let mut log = LogSettings::default();
+        set(&mut log.filter, self.log.level.clone());
+        parse_to::<LogFormat>(&mut log.format, "log.format", &self.log.format)?;
+        log.validate()?;
+
+        let listen = if self.listen.is_empty() {
+            vec![parse("listen", DEFAULT_LISTEN)?]
+        } else {
+            self.listen
+                .iter()
+                .map(|listen| parse("listen", listen))
+                .collect::<Result<_, _>>()?
+        };
+        let unix_socket = UnixSocketSettings {
+            mode: match &self.unix_socket.mode {
+                Some(mode) => Some(
+                    u32::from_str_radix(mode, 8)
+                        .map_err(|err| format!("invalid unix-socket.mode {}: {}", mode, err))?,
+                ),
+                None => None,
+            },
+            uid: self.unix_socket.uid,
+            gid: self.unix_socket.gid,
+        };
+
+        let config = &self.metrics;
+        let mut metrics = MetricsSettings::default();
+        parse_to::<MetricsExporter>(&mut metrics.exporter, "metrics.exporter", &config.exporter)?;
+        metrics.endpoint = config.endpoint.clone();
+        non_zero("metrics.interval-ms", config.interval_ms)?;
+        set_ms(&mut metrics.interval, config.interval_ms);
+        set_ms(&mut metrics.timeout, config.timeout_ms);
+        let metrics_listen = match &config.listen {
+            Some(listen) => Some(parse("metrics.listen", listen)?),
+            None => None,
+        };
+
+        Ok(Settings {
+            service_name: self
+                .service_name
+                .clone()
+                .unwrap_or_else(|| DEFAULT_SERVICE_NAME.to_string()),
+            listen,
+            unix_socket,
+            connection,
+            messages: tracing
+                .messages
+                .clone()
+                .unwrap_or_else(|| DEFAULT_MESSAGES.to_string()),
+            unknown_messages,
+            propagators,
+            exporter,
+            otel,
+            log,
+            metrics,
+            metrics_listen,
+        })
+    }
+}
+
+/// Deserialize a single string as a list of one.
+fn one_or_many<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+fn parse<T: FromStr>(name: &str, value: &str) -> Result<T, String>
+where
+    T::Err: Display,
+{
+    value
+        .parse()
+        .map_err(|err| format!("invalid {} {}: {}", name, value, err))
+}
+
+/// Set `setting` to the value of the variable `name`, if it is defined.
+fn env<T: FromStr>(
+    var: &impl Fn(&str) -> Option<String>,
+    name: &str,
+    setting: &mut Option<T>,
+) -> Result<(), String>
+where
+    T::Err: Display,
+{
+    if let Some(value) = var(name) {
+        *setting = Some(parse(name, &value)?);
+    }
+    Ok(())
+}
+
+/// Reject a setting given as 0, the agent would not start.
+fn non_zero<T: Default + PartialEq + Display>(name: &str, value: Option<T>) -> Result<(), String> {
+    match value {
+        Some(value) if value == T::default() => {
+            Err(format!("invalid {} {}: must be at least 1", name, value))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn set<T>(setting: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *setting = value;
+    }
+}
+
+fn set_ms(setting: &mut Duration, ms: Option<u64>) {
+    set(setting, ms.map(Duration::from_millis));
+}
+
+fn parse_to<T: FromStr<Err = String>>(
+    setting: &mut T,
+    name: &str,
+    value: &Option<String>,
+) -> Result<(), String> {
+    if let Some(value) = value {
+        *setting = value
+            .parse()
+            .map_err(|err| format!("invalid {}: {}", name, err))?;
+    }
+    Ok(())
+}
@@ -1,15 +1,76 @@
-use crate::frame::{Error, Frame};
+use crate::fragment::Reassembler;
+use crate::frame::{Error, FragmentError, Frame};
+use crate::negotiation::Negotiated;
 
-use bytes::{BytesMut};
+use bytes::{Buf, BytesMut};
 use std::io::Cursor;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
 use tokio::net::TcpStream;
+use tracing::{trace, Level};
+
+/// Protocol features and limits applied to every connection of the agent.
+#[derive(Clone, Debug)]
+pub struct ConnectionSettings {
+    /// Largest frame the agent accepts, the value actually used is the
+    /// smallest of this one and the one announced by HAProxy.
+    pub max_frame_size: u32,
+
+    /// Accept fragmented NOTIFY frames, and advertise the `fragmentation`
+    /// capability in the AGENT-HELLO.
+    pub fragmentation: bool,
+
+    /// Maximum number of bytes buffered for partially received frames.
+    pub max_reassembled_size: usize,
+
+    /// Process NOTIFY frames concurrently and send their ACK as soon as they
+    /// are ready, possibly out of order. The `async` capability is
+    /// advertised in the AGENT-HELLO.
+    pub asynchronous: bool,
+
+    /// Maximum number of NOTIFY frames processed at once on a connection,
+    /// no more frames are read until one of them is acknowledged.
+    pub max_in_flight: usize,
+
+    /// Time given to the NOTIFY frames in progress to be acknowledged when
+    /// the agent shuts down, before the AGENT-DISCONNECT frame is sent.
+    pub drain_timeout: Duration,
+}
+
+impl Default for ConnectionSettings {
+    fn default() -> Self {
+        ConnectionSettings {
+            max_frame_size: 16380,
+            fragmentation: true,
+            max_reassembled_size: 1024 * 1024,
+            asynchronous: true,
+            max_in_flight: 64,
+            drain_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+impl ConnectionSettings {
+    /// Capabilities supported by the agent given these settings.
+    pub fn capabilities(&self) -> Vec<&'static str> {
+        let mut capabilities = vec!["pipelining"];
+        if self.fragmentation {
+            capabilities.push("fragmentation");
+        }
+        if self.asynchronous {
+            capabilities.push("async");
+        }
+        capabilities
+    }
+}
 
 /// Send and receive `Frame` values from a remote peer.
 ///
 /// When implementing networking protocols, a message on that protocol is
 /// often composed of several smaller messages known as frames. The purpose of
-/// `Connection` is to read and write frames on the underlying `TcpStream`.
+/// `Connection` is to read and write frames on the underlying stream, a
+/// `TcpStream` or a `UnixStream`.
 ///
 /// To read frames, the `Connection` uses an internal buffer, which is filled
 /// up until there are enough bytes to create a full frame. Once this happens,
@@ -18,20 +79,36 @@ use tokio::net::TcpStream;
 /// When sending frames, the frame is first encoded into the write buffer.
 /// The contents of the write buffer are then written to the socket.
 #[derive(Debug)]
-pub struct Connection {
-    // The `TcpStream`. It is decorated with a `BufWriter`, which provides write
+pub struct Connection<S = TcpStream> {
+    // The stream. It is decorated with a `BufWriter`, which provides write
     // level buffering. The `BufWriter` implementation provided by Tokio is
     // sufficient for our needs.
-    stream: BufWriter<TcpStream>,
+    stream: BufWriter<S>,
 
     // The buffer for reading frames.
     buffer: BytesMut,
+
+    settings: ConnectionSettings,
+
+    // The fragments of the frames that are not complete yet.
+    fragments: Reassembler,
+
+    // The parameters agreed upon during the HELLO handshake, `None` until
+    // the handshake is done. It is shared with the tasks processing the
+    // NOTIFY frames.
+    negotiated: Option<Arc<Negotiated>>,
 }
 
-impl Connection {
-    /// Create a new `Connection`, backed by `socket`. Read and write buffers
-    /// are initialized.
-    pub fn new(socket: TcpStream) -> Connection {
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    /// Create a new `Connection`, backed by `socket`, with the default
+    /// settings. Read and write buffers are initialized.
+    pub fn new(socket: S) -> Connection<S> {
+        Connection::with_settings(socket, ConnectionSettings::default())
+    }
+
+    /// Create a new `Connection`, backed by `socket`, using the given
+    /// `settings`.
+    pub fn with_settings(socket: S, settings: ConnectionSettings) -> Connection<S> {
         Connection {
             stream: BufWriter::new(socket),
             // Default to a 4KB read buffer. For the use case of mini redis,
@@ -39,9 +116,32 @@ impl Connection {
             // value to their specific use case. There is a high likelihood that
             // a larger read buffer will work better.
             buffer: BytesMut::with_capacity(4 * 1024),
+            fragments: Reassembler::new(settings.max_reassembled_size),
+            settings,
+            negotiated: None,
         }
     }
 
+    pub fn settings(&self) -> &ConnectionSettings {
+        &self.settings
+    }
+
+    pub fn negotiated(&self) -> Option<&Arc<Negotiated>> {
+        self.negotiated.as_ref()
+    }
+
+    pub fn set_negotiated(&mut self, negotiated: Negotiated) {
+        self.negotiated = Some(Arc::new(negotiated));
+    }
+
+    /// Largest frame accepted by both sides: the negotiated max-frame-size,
+    /// or the local one before the HELLO handshake.
+    fn max_frame_size(&self) -> usize {
+        self.negotiated
+            .as_ref()
+            .map_or(self.settings.max_frame_size, |n| n.max_frame_size) as usize
+    }
+
     /// Read a single `Frame` value from the underlying stream.
     ///
     /// The function waits until it has retrieved enough data to parse a frame.
@@ -50,7 +150,7 @@ impl Connection {
     ///
     /// # Returns
     ///
-    /// On success, the received frame is returned. If the `TcpStream`
+    /// On success, the received frame is returned. If the stream
     /// is closed in a way that doesn't break a frame in half, it returns
     /// `None`. Otherwise, an error is returned.
     pub async fn read_frame(&mut self) -> Result<Option<Frame>, Error> {
@@ -80,17 +180,53 @@ impl Connection {
         }
     }
 
+    /// Tries to parse a frame from the buffer. Fragments are handed over to
+    /// the reassembler until the whole frame they belong to is available,
+    /// the buffer is then searched for the next frame. If not enough data
+    /// has been buffered yet, `Ok(None)` is returned.
+    fn parse_frame(&mut self) -> Result<Option<Frame>, Error> {
+        loop {
+            match self.parse_single_frame()? {
+                Some(Frame::Fragment { header, payload }) => {
+                    if !self.settings.fragmentation {
+                        return Err(Error::InvalidFragment(FragmentError::NotSupported));
+                    }
+                    if let Some(frame) = self.fragments.push(header, payload)? {
+                        return Ok(Some(frame));
+                    }
+                }
+                other => return Ok(other),
+            }
+        }
+    }
+
     /// Tries to parse a frame from the buffer. If the buffer contains enough
     /// data, the frame is returned and the data removed from the buffer. If not
     /// enough data has been buffered yet, `Ok(None)` is returned. If the
     /// buffered data does not represent a valid frame, `Err` is returned.
-    fn parse_frame(&mut self) -> Result<Option<Frame>, Error> {
+    #[allow(clippy::useless_conversion)]
+    fn parse_single_frame(&mut self) -> Result<Option<Frame>, Error> {
         // Cursor is used to track the "current" location in the
         // buffer. Cursor also implements `Buf` from the `bytes` crate
         // which provides a number of helpful utilities for working
         // with bytes.
         let mut buf = Cursor::new(&self.buffer[..]);
 
+        // The length prefix is checked against the max-frame-size as soon as
+        // it is received, so that an oversized frame is rejected before its
+        // payload is buffered. The prefix is not part of the frame size.
+        if buf.remaining() >= 4 {
+            let size = buf.get_u32() as usize;
+            let max_frame_size = self.max_frame_size();
+            if size > max_frame_size {
+                return Err(Error::FrameTooBig {
+                    size,
+                    max: max_frame_size,
+                });
+            }
+            buf.set_position(0);
+        }
+
         // The first step is to check if enough data has been buffered to parse
         // a single frame. This step is usually much faster than doing a full
         // parse of the frame, and allows us to skip allocating data structures
@@ -107,10 +243,12 @@ impl Connection {
                 // grab only the frame part of the buffer
                 // this will provide only required data for the parse
                 // This will also discard the frame data from the read buffer.
-                let frame_bytes = self.buffer.split_to(len);
-                let mut frame_buffer = Cursor::new(&frame_bytes[..]);
+                // The bytes are frozen, so that the binary data of the frame
+                // share them instead of being copied.
+                let frame_bytes = self.buffer.split_to(len).freeze();
+                let mut frame_buffer = Cursor::new(&frame_bytes);
 
-                println!("<<< {:x?}", &frame_bytes.as_ref());
+                trace!("<<< {:x?}", &frame_bytes.as_ref());
 
                 // Parse the frame from the buffer. This allocates the necessary
                 // structures to represent the frame and returns the frame
@@ -147,21 +285,37 @@ impl Connection {
     /// syscalls. However, it is fine to call these functions on a *buffered*
     /// write stream. The data will be written to the buffer. Once the buffer is
     /// full, it is flushed to the underlying socket.
+    ///
+    /// A frame larger than the max-frame-size is not written, and
+    /// `Error::FrameTooBig` is returned instead: HAProxy would close the
+    /// connection on receiving it.
+    #[allow(clippy::redundant_closure)]
     pub async fn write_frame(&mut self, frame: &Frame) -> Result<(), Error> {
         let mut full = BytesMut::new();
-        frame.write_to(&mut full).unwrap();
+        frame.write_to(&mut full)?;
+
+        // The length prefix is not part of the frame size.
+        let max_frame_size = self.max_frame_size();
+        if full.len() - 4 > max_frame_size {
+            return Err(Error::FrameTooBig {
+                size: full.len() - 4,
+                max: max_frame_size,
+            });
+        }
 
         self.stream
             .write_all(&full[..])
             .await
-            .map_err(|io| Error::IO(io))
-            .unwrap();
-
-        println!(">>> {:x?}", &full[..]);
-        // reparse to check ;)
-        let mut frame_buffer = Cursor::new(&full[..]);
-        let frame = Frame::parse(&mut frame_buffer)?;
-        println!(">>> {:?}", frame);
+            .map_err(|io| Error::IO(io))?;
+
+        if tracing::enabled!(Level::TRACE) {
+            trace!(">>> {:x?}", &full[..]);
+            // reparse to check ;)
+            let full = full.freeze();
+            let mut frame_buffer = Cursor::new(&full);
+            let frame = Frame::parse(&mut frame_buffer)?;
+            trace!(">>> {:?}", frame);
+        }
 
         // Ensure the encoded frame is written to the socket. The calls above
         // are to the buffered stream and writes. Calling `flush` writes the
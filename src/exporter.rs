@@ -0,0 +1,531 @@
+//! Exporters of the spans ended by the agent.
+//!
+//! The spans are sent either to an OpenTelemetry collector over OTLP, gRPC
+//! or HTTP/protobuf, to Jaeger, through its agent (UDP) or its collector
+//! (HTTP), or printed on the standard output.
+//!
+//! Ending a span only queues it: the spans are exported in batches by a
+//! background task of the tokio runtime, `tracer_provider` must be called
+//! from within the runtime. When the queue is full, the spans ended are
+//! dropped and counted in `DroppedSpans`.
+//!
+//! The metrics recorded by the agent are pushed periodically, over OTLP/gRPC
+//! or to the standard output, see `meter_controller`.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use http::header::{HeaderName, HeaderValue, CONTENT_ENCODING};
+use http::{HeaderMap, Request, Response};
+use opentelemetry::metrics::MetricsError;
+use opentelemetry::runtime::{self, Runtime};
+use opentelemetry::sdk::export::metrics::stdout as stdout_metrics;
+use opentelemetry::sdk::export::trace::stdout;
+use opentelemetry::sdk::export::trace::SpanExporter;
+use opentelemetry::sdk::metrics::PushController;
+use opentelemetry::sdk::trace::{
+    self as sdktrace, BatchMessage, BatchSpanProcessor, TraceRuntime, TracerProvider, TrySend,
+};
+use opentelemetry::sdk::Resource;
+use opentelemetry::trace::TraceError;
+use opentelemetry::util::tokio_interval_stream;
+use opentelemetry::KeyValue;
+use opentelemetry_http::{HttpClient, HttpError};
+use opentelemetry_otlp::{SpanExporterBuilder, WithExportConfig};
+use std::convert::TryFrom;
+use std::fmt;
+use std::future::Future;
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::error::TrySendError;
+
+/// Exporters the agent supports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exporter {
+    /// OTLP over gRPC, `http://localhost:4317` by default.
+    OtlpGrpc,
+    /// OTLP over HTTP/protobuf, `http://localhost:4318/v1/traces` by default.
+    OtlpHttp,
+    /// Jaeger agent, over UDP, `localhost:6831` by default.
+    JaegerAgent,
+    /// Jaeger collector, over HTTP, `http://localhost:14268/api/traces` by
+    /// default.
+    JaegerCollector,
+    /// Standard output, one line per span.
+    Stdout,
+}
+
+/// Compression of the exported spans.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+}
+
+#[derive(Clone, Debug)]
+pub struct ExporterSettings {
+    pub exporter: Exporter,
+
+    /// Where to send the spans, the default endpoint of the exporter if
+    /// `None`.
+    pub endpoint: Option<String>,
+
+    /// Headers, or gRPC metadata, sent along with the spans.
+    pub headers: Vec<(String, String)>,
+
+    /// Maximum duration of a request to the collector, `DEFAULT_TIMEOUT` if
+    /// `None`. Not supported by the Jaeger agent exporter, sending the spans
+    /// over UDP, nor by the stdout one.
+    pub timeout: Option<Duration>,
+
+    /// Only supported by the OTLP/HTTP exporter.
+    pub compression: Compression,
+
+    pub batch: BatchSettings,
+}
+
+/// Options of the batch span processor, see the `OTEL_BSP_*` variables of
+/// the OpenTelemetry specification.
+#[derive(Clone, Debug)]
+pub struct BatchSettings {
+    /// Spans ended and not exported yet, the following ones are dropped.
+    pub max_queue_size: usize,
+
+    /// Spans exported at once.
+    pub max_export_batch_size: usize,
+
+    /// Delay between two exports.
+    pub scheduled_delay: Duration,
+
+    /// Maximum duration of an export, retries included.
+    pub max_export_timeout: Duration,
+}
+
+impl BatchSettings {
+    /// Check that the batch span processor can be built from these
+    /// settings, it panics on an empty queue or a zero delay.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.max_queue_size == 0 {
+            return Err("batch max queue size must be at least 1".to_string());
+        }
+        if self.max_export_batch_size == 0 {
+            return Err("batch max export batch size must be at least 1".to_string());
+        }
+        if self.scheduled_delay.is_zero() {
+            return Err("batch schedule delay must be at least 1 ms".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Default for BatchSettings {
+    fn default() -> Self {
+        BatchSettings {
+            max_queue_size: 2048,
+            max_export_batch_size: 512,
+            scheduled_delay: Duration::from_millis(5000),
+            max_export_timeout: Duration::from_millis(30000),
+        }
+    }
+}
+
+/// Exporters of the metrics the agent supports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetricsExporter {
+    /// The metrics are not recorded.
+    None,
+    /// OTLP over gRPC, `http://localhost:4317` by default.
+    Otlp,
+    /// Standard output.
+    Stdout,
+}
+
+#[derive(Clone, Debug)]
+pub struct MetricsSettings {
+    pub exporter: MetricsExporter,
+
+    /// Where to send the metrics, the default endpoint of the exporter if
+    /// `None`.
+    pub endpoint: Option<String>,
+
+    /// Delay between two exports.
+    pub interval: Duration,
+
+    /// Maximum duration of an export.
+    pub timeout: Duration,
+}
+
+impl Default for MetricsSettings {
+    fn default() -> Self {
+        MetricsSettings {
+            exporter: MetricsExporter::None,
+            endpoint: None,
+            interval: Duration::from_secs(60),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Number of spans dropped because the queue of the batch span processor was
+/// full.
+#[derive(Clone, Debug, Default)]
+pub struct DroppedSpans(Arc<AtomicU64>);
+
+impl DroppedSpans {
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for ExporterSettings {
+    fn default() -> Self {
+        ExporterSettings {
+            exporter: Exporter::JaegerAgent,
+            endpoint: None,
+            headers: vec![],
+            timeout: None,
+            compression: Compression::None,
+            batch: BatchSettings::default(),
+        }
+    }
+}
+
+impl ExporterSettings {
+    /// Check that the exporter supports the options given.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.compression != Compression::None && self.exporter != Exporter::OtlpHttp {
+            return Err(format!("compression is not supported by {}", self.exporter));
+        }
+        if !self.headers.is_empty()
+            && matches!(self.exporter, Exporter::JaegerAgent | Exporter::Stdout)
+        {
+            return Err(format!("headers are not supported by {}", self.exporter));
+        }
+        if self.timeout.is_some()
+            && matches!(self.exporter, Exporter::JaegerAgent | Exporter::Stdout)
+        {
+            return Err(format!("timeout is not supported by {}", self.exporter));
+        }
+        header_map(&self.headers).map_err(|err| err.to_string())?;
+        self.batch.validate()
+    }
+}
+
+/// Error reported for every span dropped.
+pub(crate) const QUEUE_FULL: &str = "span queue is full";
+
+/// Maximum duration of a request to the collector, by default.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const OTLP_HTTP_ENDPOINT: &str = "http://localhost:4318/v1/traces";
+const JAEGER_COLLECTOR_ENDPOINT: &str = "http://localhost:14268/api/traces";
+
+/// Parse a comma separated list of headers, e.g. `api-key=secret,tenant=a`.
+pub fn parse_headers(list: &str) -> Result<Vec<(String, String)>, String> {
+    list.split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|header| match header.split_once('=') {
+            Some((name, value)) => Ok((name.trim().to_string(), value.trim().to_string())),
+            None => Err(format!("invalid header: {}", header)),
+        })
+        .collect()
+}
+
+/// Provider of the tracers of `service_name`, exporting their spans as
+/// configured by `settings`.
+pub fn tracer_provider(
+    service_name: String,
+    settings: &ExporterSettings,
+) -> Result<(TracerProvider, DroppedSpans), TraceError> {
+    settings.validate()?;
+
+    let dropped = DroppedSpans::default();
+    let batch = &settings.batch;
+    let processor = match settings.exporter {
+        Exporter::OtlpGrpc => batch_processor(otlp_grpc(settings)?, batch, &dropped),
+        Exporter::OtlpHttp => batch_processor(otlp_http(settings)?, batch, &dropped),
+        Exporter::JaegerAgent => {
+            let mut pipeline =
+                opentelemetry_jaeger::new_pipeline().with_service_name(service_name.clone());
+            if let Some(endpoint) = &settings.endpoint {
+                pipeline = pipeline.with_agent_endpoint(endpoint);
+            }
+            let exporter = pipeline.init_async_exporter(runtime::Tokio)?;
+            batch_processor(exporter, batch, &dropped)
+        }
+        Exporter::JaegerCollector => {
+            let exporter = opentelemetry_jaeger::new_pipeline()
+                .with_service_name(service_name.clone())
+                .with_collector_endpoint(endpoint_or(settings, JAEGER_COLLECTOR_ENDPOINT))
+                .with_http_client(ExportClient::new(settings)?)
+                .init_async_exporter(runtime::Tokio)?;
+            batch_processor(exporter, batch, &dropped)
+        }
+        Exporter::Stdout => {
+            batch_processor(stdout::Exporter::new(io::stdout(), false), batch, &dropped)
+        }
+    };
+
+    let config = sdktrace::config().with_resource(Resource::new(vec![KeyValue::new(
+        opentelemetry_semantic_conventions::resource::SERVICE_NAME,
+        service_name,
+    )]));
+    let provider = TracerProvider::builder()
+        .with_config(config)
+        .with_span_processor(processor)
+        .build();
+    Ok((provider, dropped))
+}
+
+/// Controller pushing the metrics of `service_name` as configured by
+/// `settings`, `None` if the metrics are not exported. The controller must be
+/// kept until the agent stops, its meter provider is installed globally.
+pub fn meter_controller(
+    service_name: String,
+    settings: &MetricsSettings,
+) -> Result<Option<PushController>, MetricsError> {
+    let controller = match settings.exporter {
+        MetricsExporter::None => return Ok(None),
+        MetricsExporter::Otlp => {
+            let mut exporter = opentelemetry_otlp::new_exporter()
+                .tonic()
+                .with_timeout(settings.timeout);
+            if let Some(endpoint) = &settings.endpoint {
+                exporter = exporter.with_endpoint(endpoint);
+            }
+            opentelemetry_otlp::new_pipeline()
+                .metrics(tokio::spawn, tokio_interval_stream)
+                .with_exporter(exporter)
+                .with_period(settings.interval)
+                .with_timeout(settings.timeout)
+                .with_resource(vec![KeyValue::new(
+                    opentelemetry_semantic_conventions::resource::SERVICE_NAME,
+                    service_name,
+                )])
+                .build()?
+        }
+        MetricsExporter::Stdout => stdout_metrics(tokio::spawn, tokio_interval_stream)
+            .with_period(settings.interval)
+            .init(),
+    };
+    Ok(Some(controller))
+}
+
+fn batch_processor<E: SpanExporter + 'static>(
+    exporter: E,
+    settings: &BatchSettings,
+    dropped: &DroppedSpans,
+) -> BatchSpanProcessor<CountingTokio> {
+    let runtime = CountingTokio {
+        dropped: dropped.clone(),
+    };
+    BatchSpanProcessor::builder(exporter, runtime)
+        .with_max_queue_size(settings.max_queue_size)
+        .with_max_export_batch_size(settings.max_export_batch_size)
+        .with_scheduled_delay(settings.scheduled_delay)
+        .with_max_timeout(settings.max_export_timeout)
+        .build()
+}
+
+/// `Tokio` runtime whose batch message channel counts the spans it drops.
+#[derive(Clone, Debug)]
+struct CountingTokio {
+    dropped: DroppedSpans,
+}
+
+impl Runtime for CountingTokio {
+    type Interval = <runtime::Tokio as Runtime>::Interval;
+    type Delay = <runtime::Tokio as Runtime>::Delay;
+
+    fn interval(&self, duration: Duration) -> Self::Interval {
+        runtime::Tokio.interval(duration)
+    }
+
+    fn spawn(&self, future: Pin<Box<dyn Future<Output = ()> + Send + 'static>>) {
+        runtime::Tokio.spawn(future)
+    }
+
+    fn delay(&self, duration: Duration) -> Self::Delay {
+        runtime::Tokio.delay(duration)
+    }
+}
+
+impl TraceRuntime for CountingTokio {
+    type Receiver = <runtime::Tokio as TraceRuntime>::Receiver;
+    type Sender = CountingSender;
+
+    fn batch_message_channel(&self, capacity: usize) -> (Self::Sender, Self::Receiver) {
+        let (sender, receiver) = runtime::Tokio.batch_message_channel(capacity);
+        let sender = CountingSender {
+            sender,
+            dropped: self.dropped.clone(),
+        };
+        (sender, receiver)
+    }
+}
+
+#[derive(Debug)]
+struct CountingSender {
+    sender: <runtime::Tokio as TraceRuntime>::Sender,
+    dropped: DroppedSpans,
+}
+
+impl TrySend for CountingSender {
+    fn try_send(&self, item: BatchMessage) -> Result<(), TraceError> {
+        match self.sender.try_send(item) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(message)) => {
+                if let BatchMessage::ExportSpan(_) = message {
+                    (self.dropped.0).fetch_add(1, Ordering::Relaxed);
+                }
+                Err(QUEUE_FULL.into())
+            }
+            Err(TrySendError::Closed(_)) => Err("span queue is closed".into()),
+        }
+    }
+}
+
+fn endpoint_or(settings: &ExporterSettings, default: &str) -> String {
+    settings
+        .endpoint
+        .clone()
+        .unwrap_or_else(|| default.to_string())
+}
+
+fn otlp_grpc(settings: &ExporterSettings) -> Result<opentelemetry_otlp::SpanExporter, TraceError> {
+    let mut builder = opentelemetry_otlp::new_exporter()
+        .tonic()
+        .with_timeout(settings.timeout.unwrap_or(DEFAULT_TIMEOUT));
+    if let Some(endpoint) = &settings.endpoint {
+        builder = builder.with_endpoint(endpoint);
+    }
+    if !settings.headers.is_empty() {
+        let headers = header_map(&settings.headers)?;
+        builder = builder.with_metadata(tonic::metadata::MetadataMap::from_headers(headers));
+    }
+    SpanExporterBuilder::from(builder).build_span_exporter()
+}
+
+fn otlp_http(settings: &ExporterSettings) -> Result<opentelemetry_otlp::SpanExporter, TraceError> {
+    let builder = opentelemetry_otlp::new_exporter()
+        .http()
+        .with_endpoint(endpoint_or(settings, OTLP_HTTP_ENDPOINT))
+        .with_timeout(settings.timeout.unwrap_or(DEFAULT_TIMEOUT))
+        .with_http_client(ExportClient::new(settings)?);
+    SpanExporterBuilder::from(builder).build_span_exporter()
+}
+
+fn header_map(headers: &[(String, String)]) -> Result<HeaderMap, TraceError> {
+    let mut map = HeaderMap::with_capacity(headers.len());
+    for (name, value) in headers {
+        let name = HeaderName::try_from(name.as_str())
+            .map_err(|e| format!("invalid header name {}: {}", name, e))?;
+        let value = HeaderValue::try_from(value.as_str())
+            .map_err(|e| format!("invalid value of header {}: {}", name, e))?;
+        map.insert(name, value);
+    }
+    Ok(map)
+}
+
+/// HTTP client of the exporters, sending the headers of the settings,
+/// compressing the spans if requested, and failing on error statuses, which
+/// the exporters would otherwise ignore.
+#[derive(Debug)]
+struct ExportClient {
+    client: reqwest::Client,
+    gzip: bool,
+}
+
+impl ExportClient {
+    fn new(settings: &ExporterSettings) -> Result<ExportClient, TraceError> {
+        let client = reqwest::Client::builder()
+            .timeout(settings.timeout.unwrap_or(DEFAULT_TIMEOUT))
+            .default_headers(header_map(&settings.headers)?)
+            .build()
+            .map_err(|e| format!("unable to build http client: {}", e))?;
+        Ok(ExportClient {
+            client,
+            gzip: settings.compression == Compression::Gzip,
+        })
+    }
+}
+
+#[async_trait]
+impl HttpClient for ExportClient {
+    async fn send(&self, request: Request<Vec<u8>>) -> Result<Response<Bytes>, HttpError> {
+        let request = if self.gzip { gzip(request)? } else { request };
+        let response = self.client.send(request).await?;
+        if !response.status().is_success() {
+            return Err(format!("export failed with status {}", response.status()).into());
+        }
+        Ok(response)
+    }
+}
+
+fn gzip(request: Request<Vec<u8>>) -> io::Result<Request<Vec<u8>>> {
+    let (mut parts, body) = request.into_parts();
+    let mut encoder = GzEncoder::new(vec![], flate2::Compression::default());
+    encoder.write_all(&body)?;
+    parts
+        .headers
+        .insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+    Ok(Request::from_parts(parts, encoder.finish()?))
+}
+
+impl FromStr for Exporter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "otlp" => Ok(Exporter::OtlpGrpc),
+            "otlp-http" => Ok(Exporter::OtlpHttp),
+            "jaeger" => Ok(Exporter::JaegerAgent),
+            "jaeger-collector" => Ok(Exporter::JaegerCollector),
+            "stdout" => Ok(Exporter::Stdout),
+            _ => Err(format!("unsupported exporter: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Exporter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Exporter::OtlpGrpc => "otlp",
+            Exporter::OtlpHttp => "otlp-http",
+            Exporter::JaegerAgent => "jaeger",
+            Exporter::JaegerCollector => "jaeger-collector",
+            Exporter::Stdout => "stdout",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for MetricsExporter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(MetricsExporter::None),
+            "otlp" => Ok(MetricsExporter::Otlp),
+            "stdout" => Ok(MetricsExporter::Stdout),
+            _ => Err(format!("unsupported metrics exporter: {}", s)),
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            _ => Err(format!("unsupported compression: {}", s)),
+        }
+    }
+}
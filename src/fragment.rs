@@ -0,0 +1,127 @@
+//! Reassembly of fragmented NOTIFY frames.
+//!
+//! When the `fragmentation` capability is negotiated, HAProxy may split a
+//! NOTIFY frame: the first fragment is a NOTIFY frame without the FIN flag,
+//! the following ones are UNSET frames sharing the same stream-id and
+//! frame-id, the last one carrying the FIN flag. Fragments are split at
+//! arbitrary positions, so payloads are buffered raw and only parsed once
+//! the frame is complete. HAProxy may give up on a fragmented frame by
+//! sending an UNSET frame with the ABORT flag set; in that case the partial
+//! payload is discarded and no ACK is expected.
+
+use crate::frame::{Error, FragmentError, Frame, FrameFlags, FrameHeader, FrameType};
+
+use bytes::{Bytes, BytesMut};
+use std::collections::HashMap;
+use std::io::Cursor;
+
+/// A fragmented frame for which the FIN fragment has not been received yet.
+#[derive(Debug)]
+struct PendingFrame {
+    // Header of the first fragment, it gives the type of the whole frame.
+    header: FrameHeader,
+    payload: BytesMut,
+}
+
+/// Collects the fragments of the frames in flight on a single connection,
+/// keyed by `(stream_id, frame_id)`.
+#[derive(Debug)]
+pub struct Reassembler {
+    pending: HashMap<(u64, u64), PendingFrame>,
+
+    // Sum of the payload sizes of every pending frame.
+    size: usize,
+
+    // Upper bound of `size`, a fragment exceeding it is rejected.
+    max_size: usize,
+}
+
+impl Reassembler {
+    /// Create a new `Reassembler`, accepting at most `max_size` bytes of
+    /// partial payloads at once.
+    pub fn new(max_size: usize) -> Reassembler {
+        Reassembler {
+            pending: HashMap::new(),
+            size: 0,
+            max_size,
+        }
+    }
+
+    /// Add a fragment to the frame it belongs to.
+    ///
+    /// # Returns
+    ///
+    /// The whole frame once its last fragment has been received, `None` if
+    /// more fragments are expected or if the frame has been aborted.
+    pub fn push(&mut self, header: FrameHeader, payload: Bytes) -> Result<Option<Frame>, Error> {
+        let key = (header.stream_id, header.frame_id);
+
+        if header.r#type != FrameType::UNSET {
+            // first fragment of a new frame
+            if header.r#type != FrameType::NOTIFY {
+                return Err(Error::InvalidFragment(FragmentError::UnexpectedFrameType(
+                    header.r#type,
+                )));
+            }
+            if self.pending.contains_key(&key) {
+                return Err(Error::InvalidFragment(FragmentError::AlreadyStarted(
+                    key.0, key.1,
+                )));
+            }
+            self.reserve(payload.len())?;
+            self.pending.insert(
+                key,
+                PendingFrame {
+                    header,
+                    payload: BytesMut::from(&payload[..]),
+                },
+            );
+            return Ok(None);
+        }
+
+        if header.flags.is_abort() {
+            // HAProxy gave up on this frame, it may have never been started
+            // on our side if it was aborted right after the first fragment
+            // was rejected.
+            if let Some(aborted) = self.pending.remove(&key) {
+                self.size -= aborted.payload.len();
+            }
+            return Ok(None);
+        }
+
+        if !self.pending.contains_key(&key) {
+            return Err(Error::InvalidFragment(FragmentError::NotStarted(
+                key.0, key.1,
+            )));
+        }
+        self.reserve(payload.len())?;
+        let pending = self.pending.get_mut(&key).unwrap();
+        pending.payload.extend_from_slice(&payload[..]);
+
+        if !header.flags.is_fin() {
+            return Ok(None);
+        }
+
+        let PendingFrame {
+            header: first,
+            payload,
+        } = self.pending.remove(&key).unwrap();
+        self.size -= payload.len();
+
+        let header = FrameHeader {
+            flags: FrameFlags::new(true, false),
+            ..first
+        };
+        let payload = payload.freeze();
+        let mut src = Cursor::new(&payload);
+        Frame::parse_payload(&mut src, &header).map(Some)
+    }
+
+    fn reserve(&mut self, len: usize) -> Result<(), Error> {
+        if self.size + len > self.max_size {
+            return Err(Error::InvalidFragment(FragmentError::TooBig(self.max_size)));
+        }
+        self.size += len;
+        Ok(())
+    }
+}
@@ -1,7 +1,6 @@
 //! Provides a type representing a SPOE protocol frame as well as utilities for
 //! parsing frames from a byte array; a write frame as a byte array.
 
-use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::io::Cursor;
 use std::net::{Ipv4Addr, Ipv6Addr};
@@ -9,13 +8,19 @@ use std::num::TryFromIntError;
 use std::string::FromUtf8Error;
 use std::{fmt, io};
 
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
+use crate::negotiation::NegotiationError;
+
 const U32_LENGTH: usize = std::mem::size_of::<u32>();
 
+/// Longest encoding of a 64 bits varint.
+const MAX_VARINT_LENGTH: usize = 10;
+
 pub type KVList = Vec<(String, TypedData)>;
-pub type ListOfMessages = HashMap<String, KVList>;
+/// Messages of a NOTIFY frame, in frame order.
+pub type ListOfMessages = Vec<(String, KVList)>;
 
 /// A frame in the SPOP protocol.
 #[derive(Clone, Debug)]
@@ -38,11 +43,16 @@ pub enum Frame {
     },
     AgentDisconnect {
         header: FrameHeader,
+        content: KVList,
     },
     Ack {
         header: FrameHeader,
         actions: Vec<Action>,
     },
+    /// Part of a fragmented frame: either the first NOTIFY frame without the
+    /// FIN flag or one of the following UNSET frames. The payload is kept raw
+    /// until every fragment has been received.
+    Fragment { header: FrameHeader, payload: Bytes },
 }
 
 #[derive(IntoPrimitive, TryFromPrimitive, PartialEq, Eq, Clone, Debug)]
@@ -91,6 +101,52 @@ pub enum FrameType {
     ACK = 103,
 }
 
+/// Status codes carried by the DISCONNECT frames, as defined by the SPOP
+/// specification.
+#[allow(non_camel_case_types)]
+#[derive(TryFromPrimitive, IntoPrimitive, Copy, Clone, PartialEq, Debug)]
+#[repr(u32)]
+pub enum StatusCode {
+    NORMAL = 0,
+    IO_ERROR = 1,
+    TIMEOUT = 2,
+    FRAME_TOO_BIG = 3,
+    INVALID_FRAME = 4,
+    VERSION_NOT_FOUND = 5,
+    MAX_FRAME_SIZE_NOT_FOUND = 6,
+    CAPABILITIES_NOT_FOUND = 7,
+    UNSUPPORTED_VERSION = 8,
+    INVALID_MAX_FRAME_SIZE = 9,
+    FRAGMENTATION_NOT_SUPPORTED = 10,
+    INVALID_INTERLACED_FRAMES = 11,
+    FRAME_ID_NOT_FOUND = 12,
+    RESOURCE_ALLOCATION_ERROR = 13,
+    UNKNOWN = 99,
+}
+
+impl StatusCode {
+    /// Default message associated to the status code.
+    pub fn message(&self) -> &'static str {
+        match self {
+            StatusCode::NORMAL => "normal",
+            StatusCode::IO_ERROR => "I/O error",
+            StatusCode::TIMEOUT => "a timeout occurred",
+            StatusCode::FRAME_TOO_BIG => "frame is too big",
+            StatusCode::INVALID_FRAME => "invalid frame received",
+            StatusCode::VERSION_NOT_FOUND => "version value not found",
+            StatusCode::MAX_FRAME_SIZE_NOT_FOUND => "max-frame-size value not found",
+            StatusCode::CAPABILITIES_NOT_FOUND => "capabilities value not found",
+            StatusCode::UNSUPPORTED_VERSION => "unsupported version",
+            StatusCode::INVALID_MAX_FRAME_SIZE => "max-frame-size too big or too small",
+            StatusCode::FRAGMENTATION_NOT_SUPPORTED => "payload fragmentation is not supported",
+            StatusCode::INVALID_INTERLACED_FRAMES => "invalid interlaced frames",
+            StatusCode::FRAME_ID_NOT_FOUND => "frame-id not found",
+            StatusCode::RESOURCE_ALLOCATION_ERROR => "resource allocation error",
+            StatusCode::UNKNOWN => "an unknown error occurred",
+        }
+    }
+}
+
 impl FrameType {
     pub fn write_to(self, dst: &mut BytesMut) -> Result<(), Error> {
         dst.put_u8(self.into());
@@ -162,7 +218,34 @@ pub enum TypedData {
     IPV4(Ipv4Addr),
     IPV6(Ipv6Addr),
     STRING(String),
-    BINARY(Vec<u8>),
+    BINARY(Bytes),
+}
+
+/// HTTP headers, as serialized by HAProxy's `req.hdrs_bin` and `res.hdrs_bin`
+/// sample fetches, in their original order. The same header may appear
+/// several times.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Headers(Vec<(String, String)>);
+
+impl Headers {
+    /// Every value of the header `name`, compared case-insensitively.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.0
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+impl TryFrom<&TypedData> for Headers {
+    type Error = HeadersError;
+
+    fn try_from(value: &TypedData) -> Result<Self, Self::Error> {
+        match value {
+            TypedData::BINARY(bin) => parse_headers(&mut Cursor::new(&bin[..])),
+            _ => Err(HeadersError::NotBinary),
+        }
+    }
 }
 
 #[derive(TryFromPrimitive, IntoPrimitive, PartialEq, Debug)]
@@ -183,6 +266,8 @@ pub enum TypedDataType {
 #[derive(Debug)]
 pub enum VarintError {
     InsufficientBytes,
+    /// More than `MAX_VARINT_LENGTH` bytes, or a value beyond 64 bits.
+    Overflow,
 }
 
 #[derive(Debug)]
@@ -200,6 +285,19 @@ pub enum StringError {
     Utf8Error(String),
 }
 
+#[derive(Debug)]
+pub enum BinaryError {
+    InsufficientBytes,
+    InvalidSize(VarintError),
+}
+
+#[derive(Debug)]
+pub enum HeadersError {
+    NotBinary,
+    InsufficientBytes,
+    InvalidSize(VarintError),
+}
+
 #[derive(Debug)]
 pub enum Ipv4Error {
     InsufficientBytes,
@@ -215,11 +313,11 @@ pub enum TypedDataError {
     InsufficientBytes,
     InvalidType(u8),
     InvalidString(StringError),
+    InvalidBinary(BinaryError),
     NumberConversionError(TypedDataType, u64),
     NumberParsingError(TypedDataType, VarintError),
     InvalidIpv4(Ipv4Error),
     InvalidIpv6(Ipv6Error),
-    NotSupported,
 }
 
 #[derive(Debug)]
@@ -262,6 +360,15 @@ pub enum FramePayloadError {
     NotSupported(FrameType),
 }
 
+#[derive(Debug)]
+pub enum FragmentError {
+    NotSupported,
+    UnexpectedFrameType(FrameType),
+    AlreadyStarted(u64, u64),
+    NotStarted(u64, u64),
+    TooBig(usize),
+}
+
 #[derive(Debug)]
 pub enum FrameError {
     InsufficientBytes,
@@ -280,21 +387,33 @@ pub enum Error {
         remaining: usize,
     },
 
-    /// Only full payload os supported for now
-    FragmentedModeNotSupported,
+    /// Fragmented frame that cannot be reassembled
+    InvalidFragment(FragmentError),
 
-    ///
+    /// No agreement with the peer during the HELLO handshake
+    Negotiation(NegotiationError),
+
+    /// Frame larger than the negotiated max-frame-size
+    FrameTooBig {
+        size: usize,
+        max: usize,
+    },
+
+    /// Frame type or feature not handled by the agent
     NotSupported,
     Disconnect,
 
+    /// NOTIFY message no handler is registered for
+    UnknownMessage(String),
+
     /// Invalid message encoding
     InvalidFrame(FrameError),
 
-    ///
+    /// Underlying transport or generic error
     IO(io::Error),
     Other(String),
 
-    ///
+    /// Placeholder, no actual error
     None,
 }
 
@@ -302,6 +421,7 @@ impl Frame {
     /// Checks if an entire message can be decoded from `src`
     /// It must "consume" the cursor, in order to give to the caller
     /// the length of the frame.
+    #[allow(clippy::needless_return)]
     pub fn check(src: &mut Cursor<&[u8]>) -> Result<(), Error> {
         if src.remaining() < U32_LENGTH {
             return Err(Error::Incomplete);
@@ -316,7 +436,10 @@ impl Frame {
     }
 
     /// The message has already been validated with `check`.
-    pub fn parse(src: &mut Cursor<&[u8]>) -> Result<Frame, Error> {
+    pub fn parse(src: &mut Cursor<&Bytes>) -> Result<Frame, Error> {
+        if src.remaining() < U32_LENGTH {
+            return Err(Error::InvalidFrame(FrameError::InsufficientBytes));
+        }
         let len = src.get_u32() as usize;
         if len != src.remaining() {
             return Err(Error::InvalidCursor {
@@ -327,17 +450,26 @@ impl Frame {
         let frame_header: FrameHeader = parse_frame_header(src)
             .map_err(|e| Error::InvalidFrame(FrameError::InvalidFrameHeader(e)))?;
 
-        if !frame_header.flags.is_fin() {
-            return Err(Error::FragmentedModeNotSupported);
+        if frame_header.r#type == FrameType::UNSET || !frame_header.flags.is_fin() {
+            return Ok(Frame::Fragment {
+                header: frame_header,
+                payload: split_bytes(src, src.remaining()),
+            });
         }
 
-        parse_frame_payload(src, &frame_header)
+        Frame::parse_payload(src, &frame_header)
+    }
+
+    /// Parse the payload of a frame whose header has already been read.
+    /// This is also used on the payload of a reassembled fragmented frame.
+    pub fn parse_payload(src: &mut Cursor<&Bytes>, header: &FrameHeader) -> Result<Frame, Error> {
+        parse_frame_payload(src, header)
             .map_err(|e| Error::InvalidFrame(FrameError::InvalidFramePayload(e)))
     }
 
     pub fn write_to(&self, full: &mut BytesMut) -> Result<(), Error> {
         let mut buff = BytesMut::new();
-        self.write_frame_to(&mut buff).unwrap();
+        self.write_frame_to(&mut buff)?;
 
         full.put_u32(buff.len() as u32);
         full.put_slice(&buff[..]);
@@ -345,20 +477,81 @@ impl Frame {
     }
     fn write_frame_to(&self, dst: &mut BytesMut) -> Result<(), Error> {
         match &self {
+            Frame::HAProxyHello { header, content } => {
+                write_frame_header(dst, header)?;
+                write_kv_list(dst, content)
+            }
+            Frame::HAProxyDisconnect { header, content } => {
+                write_frame_header(dst, header)?;
+                write_kv_list(dst, content)
+            }
+            Frame::Notify { header, messages } => {
+                write_frame_header(dst, header)?;
+                write_list_of_messages(dst, messages)
+            }
             Frame::AgentHello { header, content } => {
                 write_frame_header(dst, header).unwrap();
                 write_kv_list(dst, content).unwrap();
                 Ok(())
             }
+            Frame::AgentDisconnect { header, content } => {
+                write_frame_header(dst, header)?;
+                write_kv_list(dst, content)
+            }
             Frame::Ack { header, actions } => {
                 write_frame_header(dst, header).unwrap();
                 write_list_of_actions(dst, actions).unwrap();
                 Ok(())
             }
-            _ => Err(Error::NotSupported),
+            Frame::Fragment { header, payload } => {
+                write_frame_header(dst, header)?;
+                dst.put_slice(&payload[..]);
+                Ok(())
+            }
         }
     }
 
+    /// Build an AGENT-DISCONNECT frame. DISCONNECT frames are not bound to
+    /// any stream, so both stream-id and frame-id are 0.
+    pub fn agent_disconnect(status_code: StatusCode, message: &str) -> Frame {
+        Frame::AgentDisconnect {
+            header: FrameHeader {
+                r#type: FrameType::AGENT_DISCONNECT,
+                flags: FrameFlags::new(true, false),
+                stream_id: 0,
+                frame_id: 0,
+            },
+            content: vec![
+                (
+                    "status-code".to_string(),
+                    TypedData::UINT32(status_code.into()),
+                ),
+                (
+                    "message".to_string(),
+                    TypedData::STRING(message.to_string()),
+                ),
+            ],
+        }
+    }
+
+    /// The `status-code` and `message` of a DISCONNECT frame, if any.
+    pub fn disconnect_status(&self) -> Option<(u32, String)> {
+        let content = match self {
+            Frame::HAProxyDisconnect { header: _, content } => content,
+            Frame::AgentDisconnect { header: _, content } => content,
+            _ => return None,
+        };
+        let status_code = match content.iter().find(|(k, _)| k == "status-code") {
+            Some((_, TypedData::UINT32(v))) => *v,
+            _ => StatusCode::UNKNOWN.into(),
+        };
+        let message = match content.iter().find(|(k, _)| k == "message") {
+            Some((_, TypedData::STRING(s))) => s.to_owned(),
+            _ => "".to_string(),
+        };
+        Some((status_code, message))
+    }
+
     pub fn frame_header(&self) -> &FrameHeader {
         match self {
             Frame::HAProxyHello { header, content: _ } => header,
@@ -368,14 +561,16 @@ impl Frame {
                 messages: _,
             } => header,
             Frame::AgentHello { header, content: _ } => header,
-            Frame::AgentDisconnect { header } => header,
+            Frame::AgentDisconnect { header, content: _ } => header,
             Frame::Ack { header, actions: _ } => header,
+            Frame::Fragment { header, payload: _ } => header,
         }
     }
 }
 
+#[allow(clippy::redundant_closure)]
 pub fn parse_frame_payload(
-    src: &mut Cursor<&[u8]>,
+    src: &mut Cursor<&Bytes>,
     frame_header: &FrameHeader,
 ) -> Result<Frame, FramePayloadError> {
     match frame_header.r#type {
@@ -400,6 +595,13 @@ pub fn parse_frame_payload(
                 content: body,
             })
         }
+        FrameType::AGENT_DISCONNECT => {
+            let body = parse_kv_list(src).map_err(FramePayloadError::InvalidKVList)?;
+            Ok(Frame::AgentDisconnect {
+                header: frame_header.to_owned(),
+                content: body,
+            })
+        }
         FrameType::NOTIFY => {
             let body = parse_list_of_messages(src)
                 .map_err(|err| FramePayloadError::InvalidListOfMessages(err))?;
@@ -420,6 +622,7 @@ pub fn parse_frame_payload(
     }
 }
 
+#[allow(clippy::needless_borrow)]
 pub fn write_list_of_actions(dst: &mut BytesMut, actions: &Vec<Action>) -> Result<(), Error> {
     for action in actions {
         write_action(dst, &action).unwrap();
@@ -446,7 +649,8 @@ pub fn write_action(dst: &mut BytesMut, action: &Action) -> Result<(), Error> {
     Ok(())
 }
 
-pub fn parse_list_of_actions(src: &mut Cursor<&[u8]>) -> Result<Vec<Action>, ListOfActionsError> {
+#[allow(clippy::redundant_closure)]
+pub fn parse_list_of_actions(src: &mut Cursor<&Bytes>) -> Result<Vec<Action>, ListOfActionsError> {
     let mut actions: Vec<Action> = vec![];
 
     while src.has_remaining() {
@@ -457,8 +661,12 @@ pub fn parse_list_of_actions(src: &mut Cursor<&[u8]>) -> Result<Vec<Action>, Lis
     Ok(actions)
 }
 
-pub fn parse_action(src: &mut Cursor<&[u8]>) -> Result<Action, ActionError> {
+#[allow(clippy::redundant_closure)]
+pub fn parse_action(src: &mut Cursor<&Bytes>) -> Result<Action, ActionError> {
     let r#type = parse_action_type(src)?;
+    if !src.has_remaining() {
+        return Err(ActionError::InsufficientBytes);
+    }
     let nb_args = src.get_u8();
     match r#type {
         ActionType::SET_VAR => {
@@ -494,25 +702,35 @@ pub fn parse_action(src: &mut Cursor<&[u8]>) -> Result<Action, ActionError> {
     }
 }
 
-pub fn parse_action_type(src: &mut Cursor<&[u8]>) -> Result<ActionType, ActionError> {
+pub fn parse_action_type(src: &mut Cursor<&Bytes>) -> Result<ActionType, ActionError> {
+    if !src.has_remaining() {
+        return Err(ActionError::InsufficientBytes);
+    }
     let raw = src.get_u8();
     let r#type = ActionType::try_from(raw).map_err(|_| ActionError::InvalidActionType(raw))?;
     Ok(r#type)
 }
 
-pub fn parse_action_scope(src: &mut Cursor<&[u8]>) -> Result<ActionVarScope, ActionError> {
+pub fn parse_action_scope(src: &mut Cursor<&Bytes>) -> Result<ActionVarScope, ActionError> {
+    if !src.has_remaining() {
+        return Err(ActionError::InsufficientBytes);
+    }
     let raw = src.get_u8();
     let r#type = ActionVarScope::try_from(raw).map_err(|_| ActionError::InvalidActionScope(raw))?;
     Ok(r#type)
 }
 
+#[allow(clippy::redundant_closure)]
 pub fn parse_list_of_messages(
-    src: &mut Cursor<&[u8]>,
-) -> Result<HashMap<String, KVList>, ListOfMessagesError> {
-    let mut messages = HashMap::<String, KVList>::new();
+    src: &mut Cursor<&Bytes>,
+) -> Result<ListOfMessages, ListOfMessagesError> {
+    let mut messages = ListOfMessages::new();
     while src.has_remaining() {
         let message_name =
             parse_string(src).map_err(|e| ListOfMessagesError::InvalidMessageName(e))?;
+        if !src.has_remaining() {
+            return Err(ListOfMessagesError::InsufficientBytes);
+        }
         let nb_args = src.get_u8();
 
         let mut message_content = KVList::new();
@@ -523,12 +741,22 @@ pub fn parse_list_of_messages(
             message_content.push((name, value));
         }
 
-        messages.insert(message_name, message_content);
+        messages.push((message_name, message_content));
     }
     Ok(messages)
 }
 
-pub fn parse_kv_list(src: &mut Cursor<&[u8]>) -> Result<KVList, KVListError> {
+pub fn write_list_of_messages(dst: &mut BytesMut, messages: &ListOfMessages) -> Result<(), Error> {
+    for (name, args) in messages {
+        write_string(dst, name).unwrap();
+        dst.put_u8(u8::try_from(args.len())?);
+        write_kv_list(dst, args).unwrap();
+    }
+    Ok(())
+}
+
+#[allow(clippy::redundant_closure)]
+pub fn parse_kv_list(src: &mut Cursor<&Bytes>) -> Result<KVList, KVListError> {
     let mut body = KVList::new();
     while src.has_remaining() {
         let name = parse_string(src).map_err(|e| KVListError::InvalidKVListName(e))?;
@@ -546,7 +774,11 @@ pub fn write_kv_list(dst: &mut BytesMut, list: &KVList) -> Result<(), Error> {
     Ok(())
 }
 
-pub fn parse_typed_data(src: &mut Cursor<&[u8]>) -> Result<TypedData, TypedDataError> {
+#[allow(clippy::redundant_closure)]
+pub fn parse_typed_data(src: &mut Cursor<&Bytes>) -> Result<TypedData, TypedDataError> {
+    if !src.has_remaining() {
+        return Err(TypedDataError::InsufficientBytes);
+    }
     let raw = src.get_u8();
     let r#type: TypedDataType =
         TypedDataType::try_from(raw & 0x0F_u8).map_err(|_| TypedDataError::InvalidType(raw))?;
@@ -614,13 +846,15 @@ pub fn parse_typed_data(src: &mut Cursor<&[u8]>) -> Result<TypedData, TypedDataE
             TypedData::STRING(value)
         }
         TypedDataType::BINARY => {
-            return Err(TypedDataError::NotSupported);
+            let value = parse_binary(src).map_err(TypedDataError::InvalidBinary)?;
+            TypedData::BINARY(value)
         }
     };
 
     Ok(value)
 }
 
+#[allow(clippy::bool_comparison, clippy::unnecessary_cast)]
 pub fn write_typed_data(dst: &mut BytesMut, value: &TypedData) -> Result<(), Error> {
     match value {
         TypedData::NULL => {
@@ -665,14 +899,15 @@ pub fn write_typed_data(dst: &mut BytesMut, value: &TypedData) -> Result<(), Err
             dst.put_u8(0b_0000_1000_u8);
             write_string(dst, v)
         }
-        TypedData::BINARY(_) => {
+        TypedData::BINARY(v) => {
             dst.put_u8(0b_0000_1001_u8);
-            Err(Error::NotSupported)
+            write_binary(dst, v)
         }
     }
 }
 
-pub fn parse_string(src: &mut Cursor<&[u8]>) -> Result<String, StringError> {
+#[allow(clippy::redundant_closure)]
+pub fn parse_string(src: &mut Cursor<&Bytes>) -> Result<String, StringError> {
     let len = parse_varint(src).map_err(|e| StringError::InvalidSize(e))?;
     let val = if len == 0 {
         "".to_string()
@@ -683,8 +918,7 @@ pub fn parse_string(src: &mut Cursor<&[u8]>) -> Result<String, StringError> {
         }
         let bytes = src.copy_to_bytes(str_len);
         std::str::from_utf8(&bytes[..])
-            .map_err(|e| StringError::Utf8Error(e.to_string()))
-            .unwrap()
+            .map_err(|e| StringError::Utf8Error(e.to_string()))?
             .to_string()
     };
     Ok(val)
@@ -698,7 +932,63 @@ pub fn write_string(dst: &mut BytesMut, value: &String) -> Result<(), Error> {
     Ok(())
 }
 
-pub fn parse_frame_header(src: &mut Cursor<&[u8]>) -> Result<FrameHeader, FrameHeaderError> {
+/// Binary data are not copied, the resulting `Bytes` shares the buffer of
+/// the frame.
+pub fn parse_binary(src: &mut Cursor<&Bytes>) -> Result<Bytes, BinaryError> {
+    let len = parse_varint(src).map_err(BinaryError::InvalidSize)? as usize;
+    if len > src.remaining() {
+        return Err(BinaryError::InsufficientBytes);
+    }
+    Ok(split_bytes(src, len))
+}
+
+/// The next `len` bytes of `src`, sharing its buffer.
+fn split_bytes(src: &mut Cursor<&Bytes>, len: usize) -> Bytes {
+    let start = src.position() as usize;
+    src.advance(len);
+    src.get_ref().slice(start..start + len)
+}
+
+pub fn write_binary(dst: &mut BytesMut, value: &Bytes) -> Result<(), Error> {
+    write_varint(dst, value.len() as u64).unwrap();
+    dst.put_slice(&value[..]);
+    Ok(())
+}
+
+/// Decode HAProxy's binary headers serialization: a list of varint-prefixed
+/// name and value pairs, terminated by an empty name and an empty value.
+pub fn parse_headers(src: &mut Cursor<&[u8]>) -> Result<Headers, HeadersError> {
+    let mut headers = vec![];
+    loop {
+        let name = parse_header_part(src)?;
+        let value = parse_header_part(src)?;
+        if name.is_empty() && value.is_empty() {
+            return Ok(Headers(headers));
+        }
+        headers.push((name, value));
+    }
+}
+
+fn parse_header_part(src: &mut Cursor<&[u8]>) -> Result<String, HeadersError> {
+    if !src.has_remaining() {
+        // the end marker is missing
+        return Err(HeadersError::InsufficientBytes);
+    }
+    let len = parse_varint(src).map_err(HeadersError::InvalidSize)? as usize;
+    if len > src.remaining() {
+        return Err(HeadersError::InsufficientBytes);
+    }
+    let bytes = src.copy_to_bytes(len);
+    // header values are not guaranteed to be valid UTF-8
+    Ok(String::from_utf8_lossy(&bytes[..]).into_owned())
+}
+
+#[allow(clippy::redundant_closure)]
+pub fn parse_frame_header(src: &mut Cursor<&Bytes>) -> Result<FrameHeader, FrameHeaderError> {
+    // frame type and flags
+    if src.remaining() < 1 + U32_LENGTH {
+        return Err(FrameHeaderError::InsufficientBytes);
+    }
     let raw = src.get_u8();
     let r#type = FrameType::try_from(raw).map_err(|_| FrameHeaderError::InvalidFrameType(raw))?;
     let raw = src.get_u32();
@@ -724,20 +1014,30 @@ pub fn write_frame_header(dst: &mut BytesMut, frame_header: &FrameHeader) -> Res
     Ok(())
 }
 
-pub fn parse_varint(src: &mut Cursor<&[u8]>) -> Result<u64, VarintError> {
+pub fn parse_varint<B: Buf>(src: &mut B) -> Result<u64, VarintError> {
     if src.remaining() < 1 {
         return Err(VarintError::InsufficientBytes);
     }
 
     let mut res = src.get_u8() as u64;
     if res >= 240 {
-        let mut bit_offset: u8 = 4;
+        let mut bit_offset: u32 = 4;
+        let mut length = 1;
         loop {
+            if length == MAX_VARINT_LENGTH {
+                return Err(VarintError::Overflow);
+            }
             if src.remaining() < 1 {
                 return Err(VarintError::InsufficientBytes);
             }
-            let b = src.get_u8();
-            res += (b as u64) << bit_offset;
+            let b = u64::from(src.get_u8());
+            length += 1;
+            // the bits shifted beyond 64 bits are lost
+            let value = b
+                .checked_shl(bit_offset)
+                .filter(|value| value >> bit_offset == b)
+                .ok_or(VarintError::Overflow)?;
+            res = res.checked_add(value).ok_or(VarintError::Overflow)?;
             bit_offset += 7;
             if b < 128 {
                 break;
@@ -747,6 +1047,7 @@ pub fn parse_varint(src: &mut Cursor<&[u8]>) -> Result<u64, VarintError> {
     Ok(res)
 }
 
+#[allow(clippy::precedence)]
 pub fn write_varint(dst: &mut BytesMut, value: u64) -> Result<(), Error> {
     if value < 240 {
         dst.put_u8(value as u8);
@@ -766,7 +1067,57 @@ pub fn write_varint(dst: &mut BytesMut, value: u64) -> Result<(), Error> {
     Ok(())
 }
 
+impl Error {
+    /// Status code reported to the peer in the AGENT-DISCONNECT frame sent
+    /// when the connection is closed because of this error.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Error::Disconnect => StatusCode::NORMAL,
+            Error::Incomplete | Error::InvalidCursor { .. } | Error::InvalidFrame(_) => {
+                StatusCode::INVALID_FRAME
+            }
+            Error::InvalidFragment(err) => match err {
+                FragmentError::NotSupported => StatusCode::FRAGMENTATION_NOT_SUPPORTED,
+                FragmentError::UnexpectedFrameType(_) => StatusCode::INVALID_FRAME,
+                FragmentError::AlreadyStarted(_, _) => StatusCode::INVALID_INTERLACED_FRAMES,
+                FragmentError::NotStarted(_, _) => StatusCode::FRAME_ID_NOT_FOUND,
+                FragmentError::TooBig(_) => StatusCode::FRAME_TOO_BIG,
+            },
+            Error::Negotiation(err) => err.status_code(),
+            Error::FrameTooBig { .. } => StatusCode::FRAME_TOO_BIG,
+            Error::IO(_) => StatusCode::IO_ERROR,
+            Error::NotSupported | Error::UnknownMessage(_) | Error::Other(_) | Error::None => {
+                StatusCode::UNKNOWN
+            }
+        }
+    }
+
+    /// Name of the kind of error, parsing errors are named after their
+    /// `FrameError`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Error::Incomplete => "incomplete",
+            Error::InvalidCursor { .. } => "invalid_cursor",
+            Error::InvalidFragment(_) => "invalid_fragment",
+            Error::Negotiation(_) => "negotiation",
+            Error::FrameTooBig { .. } => "frame_too_big",
+            Error::NotSupported => "not_supported",
+            Error::Disconnect => "disconnect",
+            Error::UnknownMessage(_) => "unknown_message",
+            Error::InvalidFrame(err) => match err {
+                FrameError::InsufficientBytes => "insufficient_bytes",
+                FrameError::InvalidFrameHeader(_) => "invalid_frame_header",
+                FrameError::InvalidFramePayload(_) => "invalid_frame_payload",
+            },
+            Error::IO(_) => "io",
+            Error::Other(_) => "other",
+            Error::None => "none",
+        }
+    }
+}
+
 impl From<String> for Error {
+    #[allow(clippy::useless_conversion)]
     fn from(src: String) -> Error {
         Error::Other(src.into())
     }
@@ -802,9 +1153,14 @@ impl fmt::Display for Error {
                 "InvalidCursor expected: {}, remaining: {}",
                 expected, remaining
             ),
-            Error::FragmentedModeNotSupported => write!(f, "FragmentedModeNotSupported"),
+            Error::InvalidFragment(err) => write!(f, "InvalidFragment {}", err),
+            Error::Negotiation(err) => write!(f, "Negotiation {}", err),
+            Error::FrameTooBig { size, max } => {
+                write!(f, "FrameTooBig size: {}, max: {}", size, max)
+            }
             Error::NotSupported => write!(f, "NotSupported"),
             Error::Disconnect => write!(f, "Disconnect"),
+            Error::UnknownMessage(name) => write!(f, "UnknownMessage {}", name),
             Error::InvalidFrame(err) => write!(f, "InvalidFrame {}", err),
             Error::None => write!(f, "<none>"),
             Error::IO(err) => err.fmt(f),
@@ -825,7 +1181,7 @@ impl fmt::Display for TypedData {
             TypedData::IPV4(v) => write!(f, "{}", v),
             TypedData::IPV6(v) => write!(f, "{}", v),
             TypedData::STRING(v) => write!(f, "{}", v),
-            TypedData::BINARY(_) => write!(f, "<binary>"),
+            TypedData::BINARY(v) => write!(f, "<binary {} bytes>", v.len()),
         }
     }
 }
@@ -846,10 +1202,31 @@ impl fmt::Display for FrameError {
     }
 }
 
+impl fmt::Display for FragmentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FragmentError::NotSupported => write!(f, "FragmentError::NotSupported"),
+            FragmentError::UnexpectedFrameType(r#type) => {
+                write!(f, "FragmentError::UnexpectedFrameType {}", r#type)
+            }
+            FragmentError::AlreadyStarted(stream_id, frame_id) => write!(
+                f,
+                "FragmentError::AlreadyStarted ({}, {})",
+                stream_id, frame_id
+            ),
+            FragmentError::NotStarted(stream_id, frame_id) => {
+                write!(f, "FragmentError::NotStarted ({}, {})", stream_id, frame_id)
+            }
+            FragmentError::TooBig(max) => write!(f, "FragmentError::TooBig (max: {})", max),
+        }
+    }
+}
+
 impl fmt::Display for VarintError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             VarintError::InsufficientBytes => write!(f, "VarintError::InsufficientBytes"),
+            VarintError::Overflow => write!(f, "VarintError::Overflow"),
         }
     }
 }
@@ -995,7 +1372,9 @@ impl fmt::Display for TypedDataError {
             }
             TypedDataError::InvalidIpv4(err) => write!(f, "TypedDataError::InvalidIpv4 {}", err),
             TypedDataError::InvalidIpv6(err) => write!(f, "TypedDataError::InvalidIpv6 {}", err),
-            TypedDataError::NotSupported => write!(f, "TypedDataError::NotSupported"),
+            TypedDataError::InvalidBinary(err) => {
+                write!(f, "TypedDataError::InvalidBinary {}", err)
+            }
             TypedDataError::NumberConversionError(data_type, r#u64) => write!(
                 f,
                 "TypedDataError::NumberConversionError ({}, {})",
@@ -1025,6 +1404,25 @@ impl fmt::Display for TypedDataType {
     }
 }
 
+impl fmt::Display for BinaryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BinaryError::InsufficientBytes => write!(f, "BinaryError::InsufficientBytes"),
+            BinaryError::InvalidSize(err) => write!(f, "BinaryError::InvalidSize {}", err),
+        }
+    }
+}
+
+impl fmt::Display for HeadersError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeadersError::NotBinary => write!(f, "HeadersError::NotBinary"),
+            HeadersError::InsufficientBytes => write!(f, "HeadersError::InsufficientBytes"),
+            HeadersError::InvalidSize(err) => write!(f, "HeadersError::InvalidSize {}", err),
+        }
+    }
+}
+
 impl fmt::Display for Ipv4Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
@@ -0,0 +1,45 @@
+//! Extension point of the agent.
+//!
+//! The `Server` takes care of the SPOP protocol itself: handshake, frame
+//! reassembly, pipelining and disconnection. What the agent actually does
+//! with the messages sent by HAProxy is delegated to a `SpoaHandler`.
+
+use crate::frame::{Action, Error, FrameHeader, ListOfMessages};
+use crate::negotiation::Negotiated;
+
+use async_trait::async_trait;
+
+/// Callbacks invoked by the `Server` during the lifetime of the connections.
+///
+/// A single handler is shared by every connection, and NOTIFY frames may be
+/// processed concurrently when the `async` capability is negotiated: the
+/// handler is responsible for synchronizing its own state.
+///
+/// Healthcheck connections never reach the handler.
+#[async_trait]
+pub trait SpoaHandler: Send + Sync + 'static {
+    /// Called once the HELLO handshake of a connection succeeded.
+    async fn hello(&self, _negotiated: &Negotiated) {}
+
+    /// Process the messages of a NOTIFY frame. The returned actions are sent
+    /// back to HAProxy in the ACK frame.
+    ///
+    /// An error is logged, and the ACK only sets the error variable of the
+    /// request, holding the error, see `Server::error_var`.
+    async fn notify(
+        &self,
+        negotiated: &Negotiated,
+        header: &FrameHeader,
+        messages: &ListOfMessages,
+    ) -> Result<Vec<Action>, Error>;
+
+    /// Called when HAProxy closes the connection with a HAPROXY-DISCONNECT
+    /// frame.
+    async fn disconnect(&self, _negotiated: &Negotiated, _status_code: u32, _message: &str) {}
+
+    /// Called once a connection is closed, whatever the reason.
+    async fn closed(&self, _negotiated: &Negotiated) {}
+
+    /// Called once the server shut down, after every connection is closed.
+    async fn shutdown(&self) {}
+}
@@ -1,6 +1,23 @@
-// this file is only for integration_tests
-// that do not seem to work with binary
-// https://github.com/rust-lang/cargo/issues/7885
+//! Building blocks of a HAProxy Stream Processing Offload Agent (SPOA).
+//!
+//! The `server::Server` runs the SPOP protocol and hands the messages sent by
+//! HAProxy over to a `handler::SpoaHandler`. The agent binary routes the
+//! `opentracing:*` messages to `otel::OtelHandler` through a
+//! `router::Router`.
+pub mod config;
 pub mod connection;
+pub mod exporter;
+pub mod fragment;
 pub mod frame;
+pub mod handler;
+pub mod listener;
+pub mod logging;
+pub mod metrics;
+pub mod negotiation;
 pub mod otel;
+pub mod propagation;
+pub mod red;
+pub mod router;
+pub mod server;
+pub mod shutdown;
+pub mod store;
@@ -0,0 +1,126 @@
+//! Sockets the agent listens on: TCP addresses, and Unix domain sockets
+//! reached by HAProxy with `server agent unix@/path`, which spares the TCP
+//! overhead when the agent runs next to it.
+
+use std::fmt;
+use std::fs::{self, Permissions};
+use std::io;
+use std::net::SocketAddr;
+use std::os::unix::fs::{chown, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tokio::net::{TcpListener, UnixListener};
+
+const UNIX_PREFIX: &str = "unix@";
+
+/// Address of a listener, `ip:port` or `unix@/path`, as in the HAProxy
+/// configuration.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix(UNIX_PREFIX) {
+            Some("") => Err("missing socket path".to_string()),
+            Some(path) => Ok(ListenAddr::Unix(PathBuf::from(path))),
+            None => s
+                .parse()
+                .map(ListenAddr::Tcp)
+                .map_err(|err| format!("{}, expected ip:port or {}/path", err, UNIX_PREFIX)),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+/// Permissions and ownership of the Unix domain sockets, those of the agent
+/// process when `None`. HAProxy must be allowed to write to the socket.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UnixSocketSettings {
+    /// Permission bits, e.g. `0o660`.
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+/// A bound listener. The file of a Unix domain socket is removed once the
+/// listener is dropped.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Bind `addr`. The file of a Unix domain socket left over by a previous
+    /// agent is replaced, unless an agent still accepts connections on it.
+    pub async fn bind(addr: &ListenAddr, unix: &UnixSocketSettings) -> io::Result<Listener> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+            ListenAddr::Unix(path) => {
+                remove_stale_socket(path)?;
+                let listener = UnixListener::bind(path)?;
+                // removes the file if the settings can't be applied
+                let listener = Listener::Unix(listener, path.clone());
+                if let Some(mode) = unix.mode {
+                    fs::set_permissions(path, Permissions::from_mode(mode))?;
+                }
+                if unix.uid.is_some() || unix.gid.is_some() {
+                    chown(path, unix.uid, unix.gid)?;
+                }
+                Ok(listener)
+            }
+        }
+    }
+
+    /// Address the listener is bound to, with the actual port of a TCP
+    /// listener bound to port 0.
+    pub fn local_addr(&self) -> io::Result<ListenAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().map(ListenAddr::Tcp),
+            Listener::Unix(_, path) => Ok(ListenAddr::Unix(path.clone())),
+        }
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Listener::Tcp(listener)
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is in use", path.display()),
+                ));
+            }
+            fs::remove_file(path)
+        }
+        // anything else is left to `bind` to fail on
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,165 @@
+//! Logs of the agent, written to the standard output.
+//!
+//! The logs are structured with `tracing`: the events of a connection are
+//! logged within its `connection` span, giving the address of HAProxy and,
+//! once the HELLO handshake is done, its engine-id. The events are written
+//! as text, or as JSON objects, one per line.
+//!
+//! The frames are only dumped, in hex, at the `trace` level. The level may be
+//! changed while the agent runs: SIGUSR1 raises it, SIGUSR2 lowers it,
+//! discarding the directives given for specific targets.
+
+use std::io::{self, IsTerminal};
+use std::str::FromStr;
+use std::sync::Mutex;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task::JoinHandle;
+use tracing::level_filters::LevelFilter;
+use tracing::{error, info};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, reload, EnvFilter, Registry};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Debug)]
+pub struct LogSettings {
+    /// Filter directives, as in `RUST_LOG`, e.g. `info` or
+    /// `warn,haproxy_spoa_rust::server=debug`.
+    pub filter: String,
+
+    pub format: LogFormat,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        LogSettings {
+            filter: "info".to_string(),
+            format: LogFormat::Text,
+        }
+    }
+}
+
+impl LogSettings {
+    /// Check that the filter is valid.
+    pub fn validate(&self) -> Result<(), String> {
+        self.env_filter().map(|_| ())
+    }
+
+    fn env_filter(&self) -> Result<EnvFilter, String> {
+        EnvFilter::try_new(&self.filter)
+            .map_err(|err| format!("invalid log filter {}: {}", self.filter, err))
+    }
+}
+
+/// Levels, from the least to the most verbose.
+const LEVELS: &[LevelFilter] = &[
+    LevelFilter::OFF,
+    LevelFilter::ERROR,
+    LevelFilter::WARN,
+    LevelFilter::INFO,
+    LevelFilter::DEBUG,
+    LevelFilter::TRACE,
+];
+
+/// Changes the level of the logs installed by `init`.
+pub struct LogHandle {
+    handle: reload::Handle<EnvFilter, Registry>,
+    level: Mutex<LevelFilter>,
+}
+
+/// Install the subscriber writing the logs as configured by `settings`.
+pub fn init(settings: &LogSettings) -> Result<LogHandle, String> {
+    let filter = settings.env_filter()?;
+    let level = filter.max_level_hint().unwrap_or(LevelFilter::TRACE);
+    let (filter, handle) = reload::Layer::new(filter);
+
+    let (text, json) = match settings.format {
+        LogFormat::Text => (
+            Some(fmt::layer().with_ansi(io::stdout().is_terminal())),
+            None,
+        ),
+        LogFormat::Json => (None, Some(fmt::layer().json())),
+    };
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(text)
+        .with(json)
+        .try_init()
+        .map_err(|err| err.to_string())?;
+
+    Ok(LogHandle {
+        handle,
+        level: Mutex::new(level),
+    })
+}
+
+impl LogHandle {
+    pub fn level(&self) -> LevelFilter {
+        *self.level.lock().unwrap()
+    }
+
+    /// Log every target at `level`.
+    pub fn set_level(&self, level: LevelFilter) -> Result<(), String> {
+        let mut current = self.level.lock().unwrap();
+        self.handle
+            .reload(EnvFilter::new(level.to_string()))
+            .map_err(|err| err.to_string())?;
+        *current = level;
+        Ok(())
+    }
+
+    /// Switch to the next more verbose level, if any.
+    pub fn raise(&self) -> Result<LevelFilter, String> {
+        self.step(1)
+    }
+
+    /// Switch to the next less verbose level, if any.
+    pub fn lower(&self) -> Result<LevelFilter, String> {
+        self.step(-1)
+    }
+
+    fn step(&self, step: isize) -> Result<LevelFilter, String> {
+        let current = self.level();
+        let index = LEVELS.iter().position(|l| *l == current).unwrap_or(0) as isize;
+        let index = (index + step).clamp(0, LEVELS.len() as isize - 1);
+        let level = LEVELS[index as usize];
+        self.set_level(level)?;
+        Ok(level)
+    }
+
+    /// Spawn the task raising the level on SIGUSR1, and lowering it on
+    /// SIGUSR2.
+    pub fn spawn_signal_handler(self) -> std::io::Result<JoinHandle<()>> {
+        let mut raise = signal(SignalKind::user_defined1())?;
+        let mut lower = signal(SignalKind::user_defined2())?;
+        Ok(tokio::spawn(async move {
+            loop {
+                let level = tokio::select! {
+                    _ = raise.recv() => self.raise(),
+                    _ = lower.recv() => self.lower(),
+                };
+                match level {
+                    Ok(level) => info!(%level, "log level changed"),
+                    Err(err) => error!("unable to change the log level {}", err),
+                }
+            }
+        }))
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("invalid log format: {}", s)),
+        }
+    }
+}
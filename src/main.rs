@@ -1,133 +1,121 @@
+use clap::Parser;
 use std::env;
-use tokio::net::{TcpListener, TcpStream};
+use std::process;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
 
-pub use connection::Connection;
-use frame::{Action, Error, Frame, FrameHeader, FrameType, KVList, ListOfMessages, TypedData};
-
-mod connection;
-mod frame;
-mod otel;
-
-use otel::{handle_notify as otel_spoa_notify, init_tracer, new_otel_context, OtelContext};
+use haproxy_spoa_rust::config::Args;
+use haproxy_spoa_rust::exporter::DroppedSpans;
+use haproxy_spoa_rust::listener::Listener;
+use haproxy_spoa_rust::logging;
+use haproxy_spoa_rust::metrics;
+use haproxy_spoa_rust::otel::{init_meter, init_tracer, shutdown_tracer, OtelHandler, ERROR_VAR};
+use haproxy_spoa_rust::router::Router;
+use haproxy_spoa_rust::server::Server;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let port = match env::var("PORT") {
-        Ok(v) => v.parse::<u32>(),
-        Err(e) => panic!("No port defined: {}", e),
-    }
-    .unwrap();
-    let service_name = match env::var("SERVICE_NAME") {
-        Ok(v) => v,
-        Err(_) => "spoa".to_string(),
+    let args = Args::parse();
+    let settings = match args
+        .config(|name| env::var(name).ok())
+        .and_then(|config| config.settings())
+    {
+        Ok(settings) => settings,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        }
     };
+    if args.check_config {
+        println!("configuration is valid");
+        return Ok(());
+    }
 
-    let addr = format!("0.0.0.0:{}", port);
-    println!("Starting Agent on {}", addr);
-    let listener = TcpListener::bind(addr).await?;
-
-    let _ = init_tracer(service_name);
-    let otel_ctx = new_otel_context();
-    loop {
-        let (socket, addr) = listener.accept().await?;
-        println!("New socket opened from {:?}", addr);
+    logging::init(&settings.log)?.spawn_signal_handler()?;
 
-        let otel_ctx: OtelContext = otel_ctx.clone();
-        tokio::spawn(async move {
-            // Process each socket concurrently.
-            process(socket, otel_ctx, handle_notify).await
+    if let Some(addr) = settings.metrics_listen {
+        info!("serving metrics on {}", addr);
+        let listener = TcpListener::bind(addr).await?;
+        tokio::spawn(async {
+            if let Err(err) = metrics::serve(listener, std::future::pending()).await {
+                error!("unable to serve metrics {}", err);
+            }
         });
     }
-}
 
-type NotifyHandler = fn(
-    otel_ctx: &OtelContext,
-    header: &FrameHeader,
-    messages: &ListOfMessages,
-) -> Result<Option<Frame>, Error>;
-
-pub fn handle_notify(
-    otel_ctx: &OtelContext,
-    header: &FrameHeader,
-    messages: &ListOfMessages,
-) -> Result<Option<Frame>, Error> {
-    otel_spoa_notify(otel_ctx, header, messages).map(|actions_opt| {
-        actions_opt.map(|actions| Frame::Ack {
-            header: header.reply_header(&FrameType::ACK),
-            actions,
-        })
-    })
-}
+    let mut listeners = Vec::new();
+    for addr in &settings.listen {
+        info!("starting agent on {}", addr);
+        let listener = Listener::bind(addr, &settings.unix_socket)
+            .await
+            .map_err(|err| format!("unable to listen on {}: {}", addr, err))?;
+        listeners.push(listener);
+    }
 
-fn handle_frame(
-    frame: &Frame,
-    otel_ctx: &OtelContext,
-    notify_handler: NotifyHandler,
-) -> Result<Frame, Error> {
-    match frame {
-        Frame::HAProxyHello { header, content: _ } => {
-            // TODO: consider provided supported versions...
-            // let supported_versions = content.get("supported-versions").unwrap();
+    // the metrics are pushed until the controller is dropped
+    let meter = match init_meter(settings.service_name.clone(), &settings.metrics) {
+        Ok(controller) => controller,
+        Err(err) => {
+            error!("unable to initialize the meter {}", err);
+            None
+        }
+    };
+    match init_tracer(
+        settings.service_name,
+        &settings.propagators,
+        &settings.exporter,
+    ) {
+        Ok(dropped) => {
+            tokio::spawn(report_dropped_spans(dropped));
+        }
+        Err(err) => error!("unable to initialize the tracer {}", err),
+    }
 
-            let mut response_content = KVList::new();
-            response_content.push(("version".to_string(), TypedData::STRING("2.0".to_string())));
-            response_content.push(("max-frame-size".to_string(), TypedData::UINT32(16380_u32)));
-            response_content.push((
-                "capabilities".to_string(),
-                TypedData::STRING("pipelining".to_string()),
-            ));
+    let otel = OtelHandler::with_settings(settings.otel);
+    otel.spawn_sweeper();
 
-            Ok(Frame::AgentHello {
-                header: header.reply_header(&FrameType::AGENT_HELLO),
-                content: response_content,
-            })
-        }
-        Frame::Notify { header, messages } => {
-            notify_handler(otel_ctx, header, messages).map(|rep| {
-                match rep {
-                    Some(response_frame) => response_frame,
-                    None => {
-                        // basic ACK without action
-                        let no_actions: Vec<Action> = vec![];
-                        Frame::Ack {
-                            header: header.reply_header(&FrameType::ACK),
-                            actions: no_actions,
-                        }
-                    }
-                }
-            })
+    let router = Router::new()
+        .route(&settings.messages, otel)
+        .unknown_messages(settings.unknown_messages);
+    let server = Server::new(settings.connection, router).error_var(ERROR_VAR);
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let shutdown = async move {
+        tokio::select! {
+            _ = interrupt.recv() => {}
+            _ = terminate.recv() => {}
         }
-        Frame::HAProxyDisconnect {
-            header: _,
-            content: _,
-        } => Err(Error::Disconnect),
-        _ => Err(Error::NotSupported),
+        info!("shutting down");
+    };
+    let result = server.run(listeners, shutdown).await;
+    if let Err(err) = &result {
+        error!("unable to accept connections {}", err);
     }
-}
 
-async fn process(socket: TcpStream, otel_ctx: OtelContext, notify_handler: NotifyHandler) {
-    // The `Connection` lets us read/write redis **frames** instead of
-    // byte streams. The `Connection` type is defined by mini-redis.
-    let mut connection = Connection::new(socket);
+    // the spans in progress are ended by now, flush them along with the
+    // metrics
+    drop(meter);
+    shutdown_tracer().await;
+    info!("agent stopped");
 
-    loop {
-        if let Some(frame) = connection.read_frame().await.unwrap() {
-            println!("GOT: {:?}", frame);
+    if result.is_err() {
+        process::exit(1);
+    }
+    Ok(())
+}
 
-            match handle_frame(&frame, &otel_ctx, notify_handler) {
-                Ok(response) => {
-                    println!("REP: {:?}", response);
-                    connection.write_frame(&response).await.unwrap();
-                }
-                Err(Error::Disconnect) => {
-                    println!("Disconnecting");
-                    // break the loop
-                    return;
-                }
-                Err(err) => {
-                    println!("ERR: {:?}", err);
-                }
-            }
+/// Log the spans dropped since the previous report, every 10 seconds.
+async fn report_dropped_spans(dropped: DroppedSpans) {
+    let mut reported = 0;
+    let mut interval = tokio::time::interval(Duration::from_secs(10));
+    loop {
+        interval.tick().await;
+        let count = dropped.get();
+        if count > reported {
+            warn!("{} spans dropped, the span queue is full", count - reported);
+            reported = count;
         }
     }
 }
@@ -0,0 +1,202 @@
+//! Metrics of the agent itself, exposed in the Prometheus text format.
+//!
+//! The metrics are recorded in a registry shared by the whole process, see
+//! `metrics()`, and served over HTTP on `/metrics` by `serve`:
+//!
+//! - `spoa_connections`, `spoa_connections_total`: SPOP connections open,
+//!   and opened since the start, once their HELLO handshake succeeded,
+//!   healthchecks aside,
+//! - `spoa_frames_total{direction, type}`: frames received and sent,
+//! - `spoa_messages_total{message}`: messages received, by name; the names
+//!   are sent by the peer, at most `MESSAGE_LABEL_LIMIT` of them are kept,
+//!   the following ones are replaced by `other`,
+//! - `spoa_actions_total{type}`: actions sent back in the ACK frames,
+//! - `spoa_errors_total{kind}`: errors, see `Error::kind`,
+//! - `spoa_notify_duration_seconds`: time spent processing a NOTIFY frame,
+//!   to compare with the `timeout processing` of HAProxy,
+//! - `spoa_span_store_requests`: requests whose spans are in progress.
+
+use crate::frame::{Action, Error, Frame};
+
+use hyper::server::conn::AddrIncoming;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{header, Body, Method, Request, Response, Server, StatusCode};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+use std::collections::HashSet;
+use std::convert::Infallible;
+use std::future::Future;
+use std::sync::{Mutex, OnceLock};
+use tokio::net::TcpListener;
+
+/// Upper bounds of the `spoa_notify_duration_seconds` buckets, HAProxy
+/// waits for an ACK frame for `timeout processing`, a few tens of
+/// milliseconds.
+const NOTIFY_DURATION_BUCKETS: &[f64] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+];
+
+/// Distinct message names counted by `spoa_messages_total`, as many as the
+/// values of each attribute of the RED metrics, see `red`.
+pub const MESSAGE_LABEL_LIMIT: usize = 100;
+
+/// Label replacing the message names beyond `MESSAGE_LABEL_LIMIT`.
+const OTHER_MESSAGE: &str = "other";
+
+pub struct Metrics {
+    registry: Registry,
+    pub connections: IntGauge,
+    pub connections_total: IntCounter,
+    pub frames: IntCounterVec,
+    pub messages: IntCounterVec,
+    pub actions: IntCounterVec,
+    pub errors: IntCounterVec,
+    pub notify_duration: Histogram,
+    pub span_store_requests: IntGauge,
+    message_names: Mutex<HashSet<String>>,
+}
+
+/// Metrics of the process.
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+impl Metrics {
+    /// Metrics recorded in a registry of their own, those of the process
+    /// are given by `metrics()`.
+    pub fn new() -> Metrics {
+        let registry = Registry::new();
+        let metrics = Metrics {
+            connections: IntGauge::new("spoa_connections", "SPOP connections open").unwrap(),
+            connections_total: IntCounter::new("spoa_connections_total", "SPOP connections opened")
+                .unwrap(),
+            frames: IntCounterVec::new(
+                Opts::new("spoa_frames_total", "SPOP frames received and sent"),
+                &["direction", "type"],
+            )
+            .unwrap(),
+            messages: IntCounterVec::new(
+                Opts::new("spoa_messages_total", "Messages received in NOTIFY frames"),
+                &["message"],
+            )
+            .unwrap(),
+            actions: IntCounterVec::new(
+                Opts::new("spoa_actions_total", "Actions sent in ACK frames"),
+                &["type"],
+            )
+            .unwrap(),
+            errors: IntCounterVec::new(Opts::new("spoa_errors_total", "Errors"), &["kind"])
+                .unwrap(),
+            notify_duration: Histogram::with_opts(
+                HistogramOpts::new(
+                    "spoa_notify_duration_seconds",
+                    "Time spent processing a NOTIFY frame",
+                )
+                .buckets(NOTIFY_DURATION_BUCKETS.to_vec()),
+            )
+            .unwrap(),
+            span_store_requests: IntGauge::new(
+                "spoa_span_store_requests",
+                "Requests whose spans are in progress",
+            )
+            .unwrap(),
+            message_names: Mutex::new(HashSet::new()),
+            registry,
+        };
+        metrics.register();
+        metrics
+    }
+
+    fn register(&self) {
+        let collectors: Vec<Box<dyn prometheus::core::Collector>> = vec![
+            Box::new(self.connections.clone()),
+            Box::new(self.connections_total.clone()),
+            Box::new(self.frames.clone()),
+            Box::new(self.messages.clone()),
+            Box::new(self.actions.clone()),
+            Box::new(self.errors.clone()),
+            Box::new(self.notify_duration.clone()),
+            Box::new(self.span_store_requests.clone()),
+        ];
+        for collector in collectors {
+            self.registry.register(collector).unwrap();
+        }
+    }
+
+    pub fn frame_received(&self, frame: &Frame) {
+        let r#type = frame.frame_header().r#type.to_string();
+        self.frames.with_label_values(&["received", &r#type]).inc();
+    }
+
+    pub fn frame_sent(&self, frame: &Frame) {
+        let r#type = frame.frame_header().r#type.to_string();
+        self.frames.with_label_values(&["sent", &r#type]).inc();
+    }
+
+    pub fn message_received(&self, name: &str) {
+        let mut names = self.message_names.lock().unwrap();
+        let label = if names.contains(name) {
+            name
+        } else if names.len() < MESSAGE_LABEL_LIMIT {
+            names.insert(name.to_string());
+            name
+        } else {
+            OTHER_MESSAGE
+        };
+        self.messages.with_label_values(&[label]).inc();
+    }
+
+    pub fn actions_sent(&self, actions: &[Action]) {
+        for action in actions {
+            let r#type = match action {
+                Action::SetVar { .. } => "SET_VAR",
+                Action::UnsetVar { .. } => "UNSET_VAR",
+            };
+            self.actions.with_label_values(&[r#type]).inc();
+        }
+    }
+
+    pub fn error(&self, err: &Error) {
+        self.errors.with_label_values(&[err.kind()]).inc();
+    }
+
+    /// Metrics in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+}
+
+/// Serve the metrics on `listener` until `shutdown` completes.
+pub async fn serve(listener: TcpListener, shutdown: impl Future<Output = ()>) -> hyper::Result<()> {
+    let incoming = AddrIncoming::from_listener(listener)?;
+    let service = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(respond)) });
+    Server::builder(incoming)
+        .serve(service)
+        .with_graceful_shutdown(shutdown)
+        .await
+}
+
+async fn respond(request: Request<Body>) -> Result<Response<Body>, Infallible> {
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => Response::builder()
+            .header(header::CONTENT_TYPE, TextEncoder::new().format_type())
+            .body(Body::from(metrics().encode())),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty()),
+    };
+    Ok(response.unwrap())
+}
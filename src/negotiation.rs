@@ -0,0 +1,188 @@
+//! Negotiation of the protocol parameters during the HELLO handshake.
+//!
+//! HAProxy opens every connection with a HAPROXY-HELLO frame listing its
+//! `supported-versions`, `max-frame-size` and `capabilities`. The agent picks
+//! the highest version both sides support, the smallest of both maximum
+//! frame sizes and the capabilities both sides support, and replies with an
+//! AGENT-HELLO frame. If no agreement can be reached, the connection must be
+//! closed with an AGENT-DISCONNECT frame carrying the matching status code.
+//!
+//! When `option spop-check` is used, HAProxy sends a HAPROXY-HELLO frame with
+//! the `healthcheck` item set: the agent answers with its AGENT-HELLO frame
+//! and the connection is closed right away.
+
+use crate::connection::ConnectionSettings;
+use crate::frame::{Frame, FrameHeader, FrameType, KVList, StatusCode, TypedData};
+
+use std::fmt;
+
+/// Versions of the SPOP protocol implemented by the agent.
+pub const SUPPORTED_VERSIONS: &[&str] = &["2.0"];
+
+/// Smallest `max-frame-size` allowed by the SPOP specification.
+pub const MIN_FRAME_SIZE: u32 = 256;
+
+/// Parameters agreed upon with HAProxy for the lifetime of a connection.
+#[derive(Clone, Debug)]
+pub struct Negotiated {
+    pub version: String,
+    pub max_frame_size: u32,
+    pub capabilities: Vec<String>,
+    pub engine_id: Option<String>,
+    pub healthcheck: bool,
+}
+
+#[derive(Debug)]
+pub enum NegotiationError {
+    VersionNotFound,
+    MaxFrameSizeNotFound,
+    CapabilitiesNotFound,
+    UnsupportedVersion(String),
+    InvalidMaxFrameSize(u64),
+}
+
+impl NegotiationError {
+    /// Status code sent in the AGENT-DISCONNECT frame, as defined by the
+    /// SPOP specification.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            NegotiationError::VersionNotFound => StatusCode::VERSION_NOT_FOUND,
+            NegotiationError::MaxFrameSizeNotFound => StatusCode::MAX_FRAME_SIZE_NOT_FOUND,
+            NegotiationError::CapabilitiesNotFound => StatusCode::CAPABILITIES_NOT_FOUND,
+            NegotiationError::UnsupportedVersion(_) => StatusCode::UNSUPPORTED_VERSION,
+            NegotiationError::InvalidMaxFrameSize(_) => StatusCode::INVALID_MAX_FRAME_SIZE,
+        }
+    }
+}
+
+impl Negotiated {
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+
+    /// Build the AGENT-HELLO frame answering the HAPROXY-HELLO frame
+    /// identified by `header`.
+    pub fn agent_hello(&self, header: &FrameHeader) -> Frame {
+        let content: KVList = vec![
+            (
+                "version".to_string(),
+                TypedData::STRING(self.version.clone()),
+            ),
+            (
+                "max-frame-size".to_string(),
+                TypedData::UINT32(self.max_frame_size),
+            ),
+            (
+                "capabilities".to_string(),
+                TypedData::STRING(self.capabilities.join(",")),
+            ),
+        ];
+        Frame::AgentHello {
+            header: header.reply_header(&FrameType::AGENT_HELLO),
+            content,
+        }
+    }
+}
+
+/// Negotiate the connection parameters from the content of a HAPROXY-HELLO
+/// frame.
+pub fn negotiate(
+    settings: &ConnectionSettings,
+    hello: &KVList,
+) -> Result<Negotiated, NegotiationError> {
+    let supported_versions = match find(hello, "supported-versions") {
+        Some(TypedData::STRING(s)) => s,
+        _ => return Err(NegotiationError::VersionNotFound),
+    };
+    let version = select_version(supported_versions)
+        .ok_or_else(|| NegotiationError::UnsupportedVersion(supported_versions.to_owned()))?;
+
+    let peer_max_frame_size = match find(hello, "max-frame-size") {
+        Some(TypedData::UINT32(v)) => *v as u64,
+        Some(TypedData::UINT64(v)) => *v,
+        Some(TypedData::INT32(v)) if *v >= 0 => *v as u64,
+        Some(TypedData::INT64(v)) if *v >= 0 => *v as u64,
+        _ => return Err(NegotiationError::MaxFrameSizeNotFound),
+    };
+    if peer_max_frame_size < MIN_FRAME_SIZE as u64 {
+        return Err(NegotiationError::InvalidMaxFrameSize(peer_max_frame_size));
+    }
+    let max_frame_size = peer_max_frame_size.min(settings.max_frame_size as u64) as u32;
+
+    let peer_capabilities = match find(hello, "capabilities") {
+        Some(TypedData::STRING(s)) => split_list(s),
+        _ => return Err(NegotiationError::CapabilitiesNotFound),
+    };
+    let capabilities = settings
+        .capabilities()
+        .into_iter()
+        .filter(|c| peer_capabilities.iter().any(|p| p == c))
+        .map(|c| c.to_string())
+        .collect();
+
+    let engine_id = match find(hello, "engine-id") {
+        Some(TypedData::STRING(s)) => Some(s.to_owned()),
+        _ => None,
+    };
+
+    Ok(Negotiated {
+        version,
+        max_frame_size,
+        capabilities,
+        engine_id,
+        healthcheck: is_healthcheck(hello),
+    })
+}
+
+/// Whether the HAPROXY-HELLO frame comes from a healthcheck.
+pub fn is_healthcheck(hello: &KVList) -> bool {
+    matches!(find(hello, "healthcheck"), Some(TypedData::BOOL(true)))
+}
+
+/// Highest version of `SUPPORTED_VERSIONS` also listed by the peer.
+fn select_version(peer_versions: &str) -> Option<String> {
+    let peer_versions: Vec<(u32, u32)> = split_list(peer_versions)
+        .iter()
+        .filter_map(|v| parse_version(v))
+        .collect();
+    SUPPORTED_VERSIONS
+        .iter()
+        .filter_map(|v| parse_version(v))
+        .filter(|v| peer_versions.contains(v))
+        .max()
+        .map(|(major, minor)| format!("{}.{}", major, minor))
+}
+
+fn parse_version(version: &str) -> Option<(u32, u32)> {
+    let mut parts = version.splitn(2, '.');
+    let major = parts.next()?.parse::<u32>().ok()?;
+    let minor = parts.next().unwrap_or("0").parse::<u32>().ok()?;
+    Some((major, minor))
+}
+
+fn split_list(raw: &str) -> Vec<&str> {
+    raw.split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .collect()
+}
+
+fn find<'a>(content: &'a KVList, key: &str) -> Option<&'a TypedData> {
+    content.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+}
+
+impl fmt::Display for NegotiationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NegotiationError::VersionNotFound => write!(f, "version value not found"),
+            NegotiationError::MaxFrameSizeNotFound => write!(f, "max-frame-size value not found"),
+            NegotiationError::CapabilitiesNotFound => write!(f, "capabilities value not found"),
+            NegotiationError::UnsupportedVersion(versions) => {
+                write!(f, "unsupported version (supported by peer: {})", versions)
+            }
+            NegotiationError::InvalidMaxFrameSize(size) => {
+                write!(f, "max-frame-size too big or too small ({})", size)
+            }
+        }
+    }
+}
@@ -1,115 +1,728 @@
-use crate::frame::{Action, ActionVarScope, Error, FrameHeader, KVList, ListOfMessages, TypedData};
-use opentelemetry::global::BoxedSpan;
-use opentelemetry::sdk::Resource;
-use opentelemetry::trace::{Span, TraceError, TraceFlags};
-use opentelemetry::{global, sdk, sdk::trace as sdktrace, trace::Tracer, Key, KeyValue};
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+//! Tracing of the requests reported by the `opentracing:*` messages.
+//!
+//! Every message describes spans of a request, identified by its unique-id
+//! in the `id` argument (see `OtelSettings::id_arg`), through its arguments,
+//! read in order:
+//!
+//! - `span=<name>` starts the span `<name>`, or selects it if it is already
+//!   started; the following arguments apply to it,
+//! - `child-of=<name>` and `follows-from=<name>` reference a span of the same
+//!   request started earlier,
+//! - `tag=<key>` sets an attribute, its value being given by the next unnamed
+//!   arguments, concatenated if there are several of them,
+//! - `baggage=<key>` adds an item to the baggage of the request, its value
+//!   being given as for `tag`; the baggage is propagated along with the
+//!   context of the spans,
+//! - `headers=req.hdrs_bin` records some of the request headers,
+//! - `finish=<name>` ends the span `<name>`, `finish=*` ends all of them.
+//!
+//! Other named arguments are recorded as attributes of the selected span,
+//! except those named after a field of the propagator (`traceparent`,
+//! `tracestate`...): along with the `headers`, they give the context of the
+//! incoming trace. The spans started without any reference continue it.
+//!
+//! HAProxy balances the messages over all the connections to the agent: the
+//! requests are identified by their id alone, wherever their messages come
+//! from, and the spans are shared by all the connections. HAProxy processes
+//! not generating unique-ids unique among them are told apart by their
+//! engine-id, see `OtelSettings::engine_scoped`.
+//!
+//! A message is not traced when it has no id, or when its request is new
+//! and the full store rejects it (see `Eviction::Reject`): the ACK frame
+//! then only sets the `otel_error` variable, telling why. With
+//! `IdFallback::Stream`, a message without id is traced anyway, its request
+//! being keyed by its HAProxy stream id; the frame id changes with every
+//! message and is not part of the key.
+//!
+//! The requests are kept in a `SpanStore` until their last span ends. The
+//! spans of a request expired, or evicted from a full store, are ended with
+//! an error status and the `haproxy.last_event` attribute, so are those of
+//! the requests still in progress when the agent shuts down. Either way, the
+//! request is then recorded in the RED metrics, see `red`.
 
+use crate::exporter::{
+    meter_controller, tracer_provider, DroppedSpans, ExporterSettings, MetricsSettings, QUEUE_FULL,
+};
+use crate::frame::{Action, ActionVarScope, Error, FrameHeader, Headers, KVList, TypedData};
+use crate::metrics::metrics;
+use crate::negotiation::Negotiated;
+use crate::propagation::{set_vars, text_map_propagator, Propagator};
+use crate::red::{RequestAttributes, RequestMetrics, DEFAULT_ATTRIBUTE_LIMIT, METER_NAME};
+use crate::router::MessageHandler;
+use crate::store::{Expired, Retain, SpanStore, StoreSettings};
+use async_trait::async_trait;
+use opentelemetry::baggage::BaggageExt;
+use opentelemetry::global::{BoxedSpan, BoxedTracer};
+use opentelemetry::metrics::MetricsError;
+use opentelemetry::sdk::metrics::PushController;
+use opentelemetry::trace::{
+    Link, Span, SpanContext, StatusCode, TraceContextExt, TraceError, Tracer,
+};
+use opentelemetry::{global, Context, Key, KeyValue};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
+
+/// Spans of a single request, identified by their name.
+#[derive(Default)]
 pub struct OtelSpanContext {
-    span: BoxedSpan,
+    // Spans started and not finished yet.
+    active: HashMap<String, BoxedSpan>,
+
+    // Context of every span started so far, finished or not, so that the
+    // following spans can reference them.
+    contexts: HashMap<String, SpanContext>,
+
+    // Context of the incoming trace, if any.
+    remote: Option<SpanContext>,
+
+    // Baggage of the request, incoming items included.
+    baggage: BTreeMap<String, String>,
+
+    // Receipt of the first message of the request.
+    received: Option<Instant>,
+
+    // Attributes of the request in the RED metrics.
+    attributes: RequestAttributes,
+}
+
+pub type OtelContext = Arc<SpanStore<OtelSpanContext>>;
+
+/// Install the propagators and the exporter of the spans globally.
+pub fn init_tracer(
+    service_name: String,
+    propagators: &[Propagator],
+    exporter: &ExporterSettings,
+) -> Result<DroppedSpans, TraceError> {
+    global::set_text_map_propagator(text_map_propagator(propagators));
+    // the spans dropped are reported by their count, not one by one
+    let _ = global::set_error_handler(|err| {
+        if !err.to_string().ends_with(QUEUE_FULL) {
+            error!("{}", err);
+        }
+    });
+    let (provider, dropped) = tracer_provider(service_name, exporter)?;
+    let _ = global::set_tracer_provider(provider);
+    Ok(dropped)
+}
+
+/// Export the spans ended and not exported yet, then stop the exporter
+/// installed by `init_tracer`.
+pub async fn shutdown_tracer() {
+    // the batch span processor blocks until its queue is exported
+    let _ = tokio::task::spawn_blocking(global::shutdown_tracer_provider).await;
 }
 
-pub type OtelContext = Arc<Mutex<HashMap<String, OtelSpanContext>>>;
+/// Install the exporter of the metrics globally, the returned controller
+/// must be kept until the agent stops.
+pub fn init_meter(
+    service_name: String,
+    settings: &MetricsSettings,
+) -> Result<Option<PushController>, MetricsError> {
+    let controller = meter_controller(service_name, settings)?;
+    if let Some(controller) = &controller {
+        global::set_meter_provider(controller.provider());
+    }
+    Ok(controller)
+}
+
+/// What to do with a message without id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdFallback {
+    /// The message is not traced.
+    Error,
+    /// The request is identified by its stream.
+    Stream,
+}
+
+/// Options of the `OtelHandler`.
+#[derive(Clone, Debug)]
+pub struct OtelSettings {
+    /// Record the baggage items as attributes of the spans started once they
+    /// are known.
+    pub baggage_attributes: bool,
+
+    pub store: StoreSettings,
 
-pub fn init_tracer(service_name: String) -> Result<sdk::trace::Tracer, TraceError> {
-    global::set_text_map_propagator(opentelemetry_jaeger::Propagator::new());
-    opentelemetry_jaeger::new_pipeline()
-        //.with_agent_endpoint("http://localhost:14268/api/traces")
-        .with_trace_config(
-            sdktrace::config().with_resource(Resource::new(vec![KeyValue::new(
-                opentelemetry_semantic_conventions::resource::SERVICE_NAME,
-                service_name,
-            )])),
+    /// Argument holding the id of the request, the requests are identified
+    /// by their stream without it.
+    pub id_arg: Option<String>,
+
+    pub id_fallback: IdFallback,
+
+    /// Values kept for each attribute of the RED metrics.
+    pub metric_attribute_limit: usize,
+
+    /// Identify the requests by their id within the engine-id sent by
+    /// HAProxy in its HELLO frame, rather than by their id alone.
+    pub engine_scoped: bool,
+
+    /// Request headers recorded as span attributes, when the `headers`
+    /// argument gives them.
+    pub recorded_headers: Vec<String>,
+}
+
+impl Default for OtelSettings {
+    fn default() -> Self {
+        OtelSettings {
+            baggage_attributes: false,
+            store: StoreSettings::default(),
+            id_arg: Some(DEFAULT_ID_ARG.to_string()),
+            id_fallback: IdFallback::Error,
+            metric_attribute_limit: DEFAULT_ATTRIBUTE_LIMIT,
+            engine_scoped: false,
+            recorded_headers: DEFAULT_RECORDED_HEADERS
+                .iter()
+                .map(|h| h.to_string())
+                .collect(),
+        }
+    }
+}
+
+/// The request a message applies to is unknown.
+#[derive(Debug, PartialEq, Eq)]
+pub enum KeyError {
+    /// The id argument is missing.
+    MissingId(String),
+    /// The id argument is null, HAProxy was unable to fetch it, or empty.
+    EmptyId(String),
+}
+
+/// `MessageHandler` tracing the requests reported by the `opentracing:*`
+/// messages, the spans in progress are kept in its `OtelContext`.
+#[derive(Clone, Default)]
+pub struct OtelHandler {
+    ctx: OtelContext,
+    settings: OtelSettings,
+    red: Arc<RequestMetrics>,
+}
+
+impl OtelHandler {
+    pub fn new() -> OtelHandler {
+        OtelHandler::default()
+    }
+
+    pub fn with_settings(settings: OtelSettings) -> OtelHandler {
+        // the meter provider is installed by `init_meter` beforehand
+        let meter = global::meter(METER_NAME);
+        OtelHandler {
+            ctx: Arc::new(SpanStore::new(settings.store.clone())),
+            red: Arc::new(RequestMetrics::new(&meter, settings.metric_attribute_limit)),
+            settings,
+        }
+    }
+
+    /// Spans in progress, by request.
+    pub fn context(&self) -> &OtelContext {
+        &self.ctx
+    }
+
+    /// Spawn the task ending the spans of the expired requests.
+    pub fn spawn_sweeper(&self) -> JoinHandle<()> {
+        let ctx = self.ctx.clone();
+        let red = self.red.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(ctx.settings().sweep_interval);
+            loop {
+                interval.tick().await;
+                sweep(&ctx, &red, Instant::now());
+                metrics().span_store_requests.set(ctx.len() as i64);
+            }
+        })
+    }
+}
+
+#[async_trait]
+impl MessageHandler for OtelHandler {
+    async fn handle(
+        &self,
+        negotiated: &Negotiated,
+        header: &FrameHeader,
+        name: &str,
+        args: &KVList,
+    ) -> Result<Vec<Action>, Error> {
+        handle_message(
+            &self.ctx,
+            &self.settings,
+            &self.red,
+            negotiated,
+            header,
+            name,
+            args,
         )
-        .install_simple()
+    }
+
+    /// End the spans of the requests still in progress.
+    async fn shutdown(&self) {
+        let count = drain(&self.ctx, &self.red, Instant::now());
+        metrics().span_store_requests.set(0);
+        if count > 0 {
+            info!("{} requests in progress ended on shutdown", count);
+        }
+    }
 }
 
-pub fn new_otel_context() -> OtelContext {
-    Arc::new(Mutex::new(HashMap::new()))
+const TRACER_NAME: &str = "haproxy-spoa";
+
+/// Variable set when a NOTIFY frame, or one of its messages, is not traced,
+/// holding the reason. The agent's `Server` sets it as well when a NOTIFY
+/// frame is not processed, see `Server::error_var`.
+pub const ERROR_VAR: &str = "otel_error";
+
+const SPAN_ARG: &str = "span";
+const CHILD_OF_ARG: &str = "child-of";
+const FOLLOWS_FROM_ARG: &str = "follows-from";
+const TAG_ARG: &str = "tag";
+const FINISH_ARG: &str = "finish";
+
+/// Name given to `finish` to end every span of the request.
+const FINISH_ALL: &str = "*";
+
+/// Argument holding the unique-id of the request, by default.
+const DEFAULT_ID_ARG: &str = "id";
+
+/// Attribute of the spans ended by the agent, naming the last message
+/// received for their request.
+const LAST_EVENT_ATTRIBUTE: &str = "haproxy.last_event";
+
+/// Argument holding the request headers, as sent by `req.hdrs_bin`.
+const HEADERS_ARG: &str = "headers";
+
+/// Argument holding a baggage item, its value being given by the next
+/// unnamed arguments.
+const BAGGAGE_ARG: &str = "baggage";
+
+/// Request headers recorded as span attributes, by default.
+pub const DEFAULT_RECORDED_HEADERS: &[&str] = &[
+    "host",
+    "user-agent",
+    "content-type",
+    "content-length",
+    "x-forwarded-for",
+    "x-request-id",
+];
+
+/// A span described by a message.
+#[derive(Debug, Default)]
+struct SpanSpec {
+    name: String,
+    child_of: Vec<String>,
+    follows_from: Vec<String>,
+    attributes: Vec<KeyValue>,
 }
 
-const TRACEPARENT_HEADER: &str = "traceparent";
-const TRACESTATE_HEADER: &str = "tracestate";
+/// What a message asks for: spans are started or updated in order, then the
+/// `finish` spans are ended.
+#[derive(Debug, Default)]
+struct Operations {
+    spans: Vec<SpanSpec>,
+    finish: Vec<String>,
+    baggage: Vec<(String, String)>,
+
+    // Propagation fields found in the arguments, by lowercase name.
+    carrier: HashMap<String, String>,
+}
 
-pub fn handle_notify(
+pub fn handle_message(
     db: &OtelContext,
+    settings: &OtelSettings,
+    red: &RequestMetrics,
+    negotiated: &Negotiated,
     header: &FrameHeader,
-    messages: &ListOfMessages,
-) -> Result<Option<Vec<Action>>, Error> {
-    for (k, _v) in messages {
-        println!("======================");
-        println!("MSG: {}", k);
-        println!("======================");
-    }
-
-    let mut actions: Vec<Action> = vec![];
-
-    if let Some(details) = messages.get("opentracing:frontend_tcp_request") {
-        let tracer = global::tracer("my_service");
-        let mut span = tracer.start("my_span");
-        for (k, v) in details {
-            let key = Key::new(k.to_owned());
-            let attr = v.as_value(key);
-            span.set_attribute(attr);
-        }
-
-        let span_context = span.span_context();
-        if span_context.is_valid() {
-            let header_value = format!(
-                "{:02x}-{:032x}-{:016x}-{:02x}",
-                0, //SUPPORTED_VERSION,
-                span_context.trace_id(),
-                span_context.span_id(),
-                span_context.trace_flags() & TraceFlags::SAMPLED
-            );
-
-            actions.push(Action::SetVar {
-                scope: ActionVarScope::REQUEST,
-                name: TRACEPARENT_HEADER.to_string(),
-                value: TypedData::STRING(header_value),
-            });
-            actions.push(Action::SetVar {
-                scope: ActionVarScope::REQUEST,
-                name: TRACESTATE_HEADER.to_string(),
-                value: TypedData::STRING(span_context.trace_state().header()),
-            });
-        }
-
-        let mut db = db.lock().unwrap();
-        let key = key_of(header, details);
-        println!("*** USING KEY {}", key);
-        db.insert(key, OtelSpanContext { span });
-
-        // span is no longer active after this point...
-    } else if let Some(details) = messages.get("opentracing:http_response") {
-        let mut db = db.lock().unwrap();
-        let id: String = key_of(header, details);
-        if let Some(ctx) = db.remove(&id) {
-            println!("---------------------------");
-            println!("Terminating span ???");
-            println!("---------------------------");
-            let mut span = ctx.span;
-            span.end();
+    name: &str,
+    details: &KVList,
+) -> Result<Vec<Action>, Error> {
+    debug!("MSG: {}", name);
+
+    let fields: Vec<String> =
+        global::get_text_map_propagator(|p| p.fields().map(|f| f.to_lowercase()).collect());
+    let key = match key_of(negotiated, header, details, settings) {
+        Ok(key) => key,
+        Err(err) => {
+            warn!("message {} not traced, {}", name, err);
+            return Ok(vec![error_var(err)]);
+        }
+    };
+    debug!("using key {}", key);
+    let operations = operations_of(details, &fields, settings);
+    let incoming = extract(&operations.carrier);
+    let tracer = global::tracer(TRACER_NAME);
+
+    let now = Instant::now();
+    let updated = db.update(&key, name, |request| {
+        request.received.get_or_insert(now);
+        if let Some(cx) = incoming {
+            let span_context = cx.span().span_context().clone();
+            if request.remote.is_none() && span_context.is_valid() {
+                request.remote = Some(span_context);
+            }
+            for (k, (v, _)) in cx.baggage() {
+                request
+                    .baggage
+                    .entry(k.to_string())
+                    .or_insert_with(|| v.as_str().into_owned());
+            }
+        }
+        request.baggage.extend(operations.baggage);
+
+        let mut started: Option<SpanContext> = None;
+        for spec in operations.spans {
+            for attr in &spec.attributes {
+                request.attributes.observe(attr);
+            }
+            match request.active.get_mut(&spec.name) {
+                Some(span) => {
+                    for attr in spec.attributes {
+                        span.set_attribute(attr);
+                    }
+                }
+                None => started = Some(request.start(&tracer, spec, settings)),
+            }
+        }
+
+        // propagate the context of the last span started by the message
+        let mut actions: Vec<Action> = vec![];
+        if let Some(span_context) = started {
+            let cx = Context::new()
+                .with_remote_span_context(span_context)
+                .with_baggage(request.baggage_items());
+            actions = global::get_text_map_propagator(|propagator| set_vars(propagator, &cx));
+        }
+
+        let mut finished = vec![];
+        for name in operations.finish {
+            request.finish(&name, &mut finished);
+        }
+        if !request.active.is_empty() {
+            ((actions, finished, None), Retain::Keep)
+        } else if request.contexts.is_empty() {
+            // no span was ever started, the request is not recorded
+            ((actions, finished, None), Retain::Remove)
         } else {
-            println!("---------------------------");
-            println!("UNABLE to Terminate span !?!");
-            println!("---------------------------");
+            let completed = (request.attributes.clone(), request.elapsed(now));
+            ((actions, finished, Some(completed)), Retain::Remove)
+        }
+    });
+
+    // the spans are ended once the store is unlocked
+    match updated {
+        Ok(((actions, finished, completed), evicted)) => {
+            for mut span in finished {
+                span.end();
+            }
+            if let Some((attributes, duration)) = completed {
+                red.completed(&attributes, duration);
+            }
+            if let Some(request) = evicted {
+                abort(request, red, now, "evicted");
+            }
+            Ok(actions)
+        }
+        Err(err) => {
+            warn!("request {} not traced, {}", key, err);
+            Ok(vec![error_var(err)])
         }
     }
+}
+
+/// SET-VAR action of the `ERROR_VAR` variable, telling why a message is not
+/// traced.
+fn error_var(reason: impl fmt::Display) -> Action {
+    Action::SetVar {
+        scope: ActionVarScope::REQUEST,
+        name: ERROR_VAR.to_string(),
+        value: TypedData::STRING(reason.to_string()),
+    }
+}
+
+/// End the spans of the requests expired at `now`, returns their number.
+pub fn sweep(db: &OtelContext, red: &RequestMetrics, now: Instant) -> usize {
+    let expired = db.expire(now);
+    let count = expired.len();
+    for request in expired {
+        abort(request, red, now, "timeout");
+    }
+    count
+}
+
+/// End the spans of every request in the store, returns their number.
+pub fn drain(db: &OtelContext, red: &RequestMetrics, now: Instant) -> usize {
+    let drained = db.drain();
+    let count = drained.len();
+    for request in drained {
+        abort(request, red, now, "shutdown");
+    }
+    count
+}
 
-    Ok(Some(actions))
+/// End the spans in progress of a request removed from the store with an
+/// error status, and the last message received for it.
+fn abort(request: Expired<OtelSpanContext>, red: &RequestMetrics, now: Instant, reason: &str) {
+    warn!(
+        "request {} {} after {}",
+        request.key, reason, request.last_event
+    );
+    let duration = request.value.elapsed(now);
+    red.aborted(&request.value.attributes, duration);
+    for (_, mut span) in request.value.active {
+        span.set_attribute(KeyValue::new(
+            LAST_EVENT_ATTRIBUTE,
+            request.last_event.clone(),
+        ));
+        span.set_status(StatusCode::Error, reason.to_string());
+        span.end();
+    }
 }
 
-fn key_of(header: &FrameHeader, details: &KVList) -> String {
-    let str_id = match details.iter().find(|(k, _)| k == "id").unwrap() {
-        (_, TypedData::STRING(s)) => s,
-        _ => "::",
+impl OtelSpanContext {
+    /// Start the span described by `spec`.
+    ///
+    /// OpenTelemetry has no follows-from relationship: as in its OpenTracing
+    /// compatibility layer, the parent is the first `child-of` span, or else
+    /// the first `follows-from` one, and every referenced span is linked.
+    fn start(
+        &mut self,
+        tracer: &BoxedTracer,
+        spec: SpanSpec,
+        settings: &OtelSettings,
+    ) -> SpanContext {
+        let references = spec
+            .child_of
+            .iter()
+            .map(|name| ("child_of", name))
+            .chain(spec.follows_from.iter().map(|name| ("follows_from", name)));
+
+        let mut parent = None;
+        let mut links = vec![];
+        for (ref_type, name) in references {
+            match self.contexts.get(name) {
+                Some(span_context) => {
+                    if parent.is_none() {
+                        parent = Some(span_context.clone());
+                    }
+                    links.push(Link::new(
+                        span_context.clone(),
+                        vec![KeyValue::new("opentracing.ref_type", ref_type)],
+                    ));
+                }
+                None => warn!("span {} references unknown span {}", spec.name, name),
+            }
+        }
+        let parent_cx = match parent.or_else(|| self.remote.clone()) {
+            Some(span_context) => Context::new().with_remote_span_context(span_context),
+            None => Context::new(),
+        };
+
+        let mut attributes = spec.attributes;
+        if settings.baggage_attributes {
+            attributes.extend(self.baggage_items());
+        }
+
+        let span = tracer
+            .span_builder(spec.name.clone())
+            .with_attributes(attributes)
+            .with_links(links)
+            .start_with_context(tracer, &parent_cx);
+        let span_context = span.span_context().clone();
+        self.contexts
+            .insert(spec.name.clone(), span_context.clone());
+        self.active.insert(spec.name, span);
+        span_context
+    }
+
+    /// Time elapsed between the first message of the request and `now`.
+    fn elapsed(&self, now: Instant) -> Duration {
+        self.received.map_or(Duration::ZERO, |received| {
+            now.saturating_duration_since(received)
+        })
+    }
+
+    fn baggage_items(&self) -> Vec<KeyValue> {
+        self.baggage
+            .iter()
+            .map(|(k, v)| KeyValue::new(k.to_owned(), v.to_owned()))
+            .collect()
+    }
+
+    /// Remove the span `name`, or every span with `FINISH_ALL`, from the
+    /// active ones, they are ended by the caller.
+    fn finish(&mut self, name: &str, finished: &mut Vec<BoxedSpan>) {
+        if name == FINISH_ALL {
+            finished.extend(self.active.drain().map(|(_, span)| span));
+            return;
+        }
+        match self.active.remove(name) {
+            Some(span) => finished.push(span),
+            None => warn!("unable to finish unknown span {}", name),
+        }
+    }
+}
+
+/// Context of the incoming trace, and its baggage, extracted from `carrier`
+/// by the propagator.
+fn extract(carrier: &HashMap<String, String>) -> Option<Context> {
+    if carrier.is_empty() {
+        return None;
+    }
+    Some(global::get_text_map_propagator(|p| {
+        p.extract_with_context(&Context::new(), carrier)
+    }))
+}
+
+/// Interpret the arguments of a message, see the module documentation.
+/// `fields` are the lowercase propagation fields of the propagator.
+fn operations_of(details: &KVList, fields: &[String], settings: &OtelSettings) -> Operations {
+    let id_arg = settings.id_arg.as_deref();
+    let mut operations = Operations::default();
+    let mut current: Option<SpanSpec> = None;
+
+    let mut args = details.iter().peekable();
+    while let Some((k, v)) = args.next() {
+        // unnamed arguments following `tag` and `baggage` are their value
+        let mut values = vec![];
+        if k == TAG_ARG || k == BAGGAGE_ARG {
+            while let Some((_, value)) = args.next_if(|(k, _)| k.is_empty()) {
+                values.push(value);
+            }
+        }
+
+        match k.as_str() {
+            _ if Some(k.as_str()) == id_arg => {}
+            SPAN_ARG => {
+                operations.spans.extend(current.take());
+                current = Some(SpanSpec {
+                    name: v.to_string(),
+                    ..SpanSpec::default()
+                });
+            }
+            FINISH_ARG => operations.finish.push(v.to_string()),
+            BAGGAGE_ARG => match concat_values(&values) {
+                Some(value) => operations.baggage.push((v.to_string(), value)),
+                None => warn!("no value for baggage {}", v),
+            },
+            "" => warn!("unnamed argument {} ignored", v),
+            HEADERS_ARG => match Headers::try_from(v) {
+                Ok(headers) => {
+                    for field in fields {
+                        if let Some(value) = headers.get_all(field).next() {
+                            operations
+                                .carrier
+                                .insert(field.to_owned(), value.to_owned());
+                        }
+                    }
+                    if let Some(span) = current.as_mut() {
+                        let recorded = &settings.recorded_headers;
+                        span.attributes
+                            .extend(header_attributes(&headers, recorded));
+                    }
+                }
+                Err(err) => warn!("unable to decode headers {}", err),
+            },
+            _ if fields.contains(&k.to_lowercase()) => {
+                operations.carrier.insert(k.to_lowercase(), v.to_string());
+            }
+            _ => match current.as_mut() {
+                Some(span) => match k.as_str() {
+                    CHILD_OF_ARG => span.child_of.push(v.to_string()),
+                    FOLLOWS_FROM_ARG => span.follows_from.push(v.to_string()),
+                    TAG_ARG => match tag_attribute(v, &values) {
+                        Some(attr) => span.attributes.push(attr),
+                        None => warn!("no value for tag {}", v),
+                    },
+                    _ => span.attributes.push(v.as_value(Key::new(k.to_owned()))),
+                },
+                None => warn!("argument {} does not apply to any span", k),
+            },
+        }
+    }
+    operations.spans.extend(current);
+    operations
+}
+
+fn concat_values(values: &[&TypedData]) -> Option<String> {
+    if values.is_empty() {
+        None
+    } else {
+        Some(values.iter().map(|v| v.to_string()).collect())
+    }
+}
+
+/// Attribute `key` with the value of a `tag` argument: a single value keeps
+/// its type, several values are concatenated.
+fn tag_attribute(key: &TypedData, values: &[&TypedData]) -> Option<KeyValue> {
+    let key = Key::new(key.to_string());
+    match values {
+        [] => None,
+        [value] => Some(value.as_value(key)),
+        values => concat_values(values).map(|value| key.string(value)),
+    }
+}
+
+fn header_attributes(headers: &Headers, recorded: &[String]) -> Vec<KeyValue> {
+    recorded
+        .iter()
+        .filter_map(|name| {
+            let values: Vec<&str> = headers.get_all(name).collect();
+            if values.is_empty() {
+                None
+            } else {
+                let key = Key::new(format!("http.request.header.{}", name));
+                Some(key.string(values.join(",")))
+            }
+        })
+        .collect()
+}
+
+/// Key of the request a message applies to, see `OtelSettings::id_arg`:
+/// `id:<id>`, or `stream:<stream-id>`, prefixed by `<engine-id>/` if the
+/// requests are scoped by engine.
+fn key_of(
+    negotiated: &Negotiated,
+    header: &FrameHeader,
+    details: &KVList,
+    settings: &OtelSettings,
+) -> Result<String, KeyError> {
+    let id = match &settings.id_arg {
+        Some(id_arg) => match details.iter().find(|(k, _)| k == id_arg) {
+            Some((_, id)) => id_of(id)
+                .map(Some)
+                .ok_or_else(|| KeyError::EmptyId(id_arg.to_owned())),
+            None => Err(KeyError::MissingId(id_arg.to_owned())),
+        },
+        None => Ok(None),
+    };
+    let key = match (id, settings.id_fallback) {
+        (Ok(Some(id)), _) => format!("id:{}", id),
+        (Ok(None), _) | (Err(_), IdFallback::Stream) => format!("stream:{}", header.stream_id),
+        (Err(err), IdFallback::Error) => return Err(err),
+    };
+    match &negotiated.engine_id {
+        Some(engine_id) if settings.engine_scoped => Ok(format!("{}/{}", engine_id, key)),
+        _ => Ok(key),
+    }
+}
+
+/// Id of any type but null, binary ids are given in hex.
+fn id_of(id: &TypedData) -> Option<String> {
+    let id = match id {
+        TypedData::NULL => return None,
+        TypedData::BINARY(v) => v.iter().map(|b| format!("{:02x}", b)).collect(),
+        id => id.to_string(),
     };
-    format!("{}::{}", header.stream_id.to_string(), str_id)
+    if id.is_empty() {
+        None
+    } else {
+        Some(id)
+    }
 }
 
 impl TypedData {
+    #[allow(clippy::unnecessary_cast)]
     pub fn as_value(&self, key: Key) -> KeyValue {
         match self {
             TypedData::NULL => key.string("<null>"),
@@ -121,7 +734,44 @@ impl TypedData {
             TypedData::IPV4(addr) => key.string(addr.to_string().to_owned()),
             TypedData::IPV6(addr) => key.string(addr.to_string().to_owned()),
             TypedData::STRING(s) => key.string(s.to_owned()),
-            TypedData::BINARY(_) => key.string("<bin>"),
+            TypedData::BINARY(v) => key.string(binary_attribute(v)),
+        }
+    }
+}
+
+/// Binary values larger than this are truncated when used as attribute.
+const MAX_BINARY_ATTRIBUTE_SIZE: usize = 256;
+
+/// Hex representation of a binary value, truncated to at most
+/// `MAX_BINARY_ATTRIBUTE_SIZE` bytes; the full size is then appended.
+fn binary_attribute(bin: &[u8]) -> String {
+    let mut hex = String::with_capacity(2 * bin.len().min(MAX_BINARY_ATTRIBUTE_SIZE) + 24);
+    for b in bin.iter().take(MAX_BINARY_ATTRIBUTE_SIZE) {
+        hex.push_str(&format!("{:02x}", b));
+    }
+    if bin.len() > MAX_BINARY_ATTRIBUTE_SIZE {
+        hex.push_str(&format!("... ({} bytes)", bin.len()));
+    }
+    hex
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::MissingId(arg) => write!(f, "missing {} argument", arg),
+            KeyError::EmptyId(arg) => write!(f, "empty {} argument", arg),
+        }
+    }
+}
+
+impl FromStr for IdFallback {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(IdFallback::Error),
+            "stream" => Ok(IdFallback::Stream),
+            _ => Err(format!("invalid id fallback: {}", s)),
         }
     }
 }
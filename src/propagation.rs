@@ -0,0 +1,209 @@
+//! Propagation of the trace context to the servers behind HAProxy.
+//!
+//! The agent cannot set the request headers itself: the fields injected by
+//! the propagators are sent back as SET-VAR actions, in the `REQUEST` scope,
+//! HAProxy then copies them into the headers of the request, e.g.
+//! `http-request set-header traceparent %[var(req.spoe.traceparent)]`.
+//!
+//! HAProxy variable names only allow alphanumeric characters, `.` and `_`:
+//! the field names are lowercased and their `-` replaced by `_`, so the
+//! `uber-trace-id` field of the Jaeger propagator is set in the
+//! `uber_trace_id` variable.
+
+use crate::frame::{Action, ActionVarScope, TypedData};
+
+use opentelemetry::propagation::text_map_propagator::FieldIter;
+use opentelemetry::propagation::{Extractor, Injector, TextMapPropagator};
+use opentelemetry::sdk::propagation::{
+    BaggagePropagator, TextMapCompositePropagator, TraceContextPropagator,
+};
+use opentelemetry::trace::{SpanContext, TraceContextExt, TraceFlags, TraceState};
+use opentelemetry::Context;
+pub use opentelemetry_zipkin::B3Encoding;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+/// Propagators the agent supports, named as in `OTEL_PROPAGATORS`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Propagator {
+    /// W3C `traceparent` and `tracestate`.
+    TraceContext,
+    /// W3C `baggage`.
+    Baggage,
+    /// Jaeger `uber-trace-id`.
+    Jaeger,
+    /// Zipkin `b3` single header.
+    B3,
+    /// Zipkin `X-B3-*` headers.
+    B3Multi,
+}
+
+/// Propagators used when none is configured.
+pub const DEFAULT_PROPAGATORS: &[Propagator] = &[Propagator::TraceContext, Propagator::Baggage];
+
+/// Parse a comma separated list of propagators, e.g. `tracecontext,baggage`.
+pub fn parse_propagators(list: &str) -> Result<Vec<Propagator>, String> {
+    list.split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(Propagator::from_str)
+        .collect()
+}
+
+/// Propagator injecting and extracting every field of `propagators`.
+pub fn text_map_propagator(propagators: &[Propagator]) -> TextMapCompositePropagator {
+    let propagators = propagators
+        .iter()
+        .map(|propagator| -> Box<dyn TextMapPropagator + Send + Sync> {
+            match propagator {
+                Propagator::TraceContext => Box::new(TraceContextPropagator::new()),
+                Propagator::Baggage => Box::new(BaggagePropagator::new()),
+                Propagator::Jaeger => Box::new(JaegerPropagator::new()),
+                Propagator::B3 => Box::new(B3Propagator::new(B3Encoding::SingleHeader)),
+                Propagator::B3Multi => Box::new(B3Propagator::new(B3Encoding::MultipleHeader)),
+            }
+        })
+        .collect();
+    TextMapCompositePropagator::new(propagators)
+}
+
+/// SET-VAR actions carrying the fields `propagator` injects for `cx`.
+pub fn set_vars(propagator: &dyn TextMapPropagator, cx: &Context) -> Vec<Action> {
+    let mut carrier: HashMap<String, String> = HashMap::new();
+    propagator.inject_context(cx, &mut carrier);
+
+    let mut fields: Vec<(String, String)> = carrier.into_iter().collect();
+    fields.sort();
+    fields
+        .into_iter()
+        .map(|(field, value)| Action::SetVar {
+            scope: ActionVarScope::REQUEST,
+            name: var_name(&field),
+            value: TypedData::STRING(value),
+        })
+        .collect()
+}
+
+/// Name of the HAProxy variable holding the propagation field `field`.
+pub fn var_name(field: &str) -> String {
+    field.to_lowercase().replace('-', "_")
+}
+
+/// The Jaeger propagator resets the span context when `uber-trace-id` is
+/// missing, which would discard the one extracted by the previous
+/// propagators: it is only applied when it finds a valid one.
+#[derive(Debug)]
+struct JaegerPropagator(opentelemetry_jaeger::Propagator);
+
+impl JaegerPropagator {
+    fn new() -> JaegerPropagator {
+        JaegerPropagator(opentelemetry_jaeger::Propagator::new())
+    }
+}
+
+impl TextMapPropagator for JaegerPropagator {
+    fn inject_context(&self, cx: &Context, injector: &mut dyn Injector) {
+        self.0.inject_context(cx, injector)
+    }
+
+    fn extract_with_context(&self, cx: &Context, extractor: &dyn Extractor) -> Context {
+        let extracted = self.0.extract_with_context(cx, extractor);
+        if extracted.span().span_context().is_valid() {
+            extracted
+        } else {
+            cx.clone()
+        }
+    }
+
+    fn fields(&self) -> FieldIter<'_> {
+        self.0.fields()
+    }
+}
+
+/// Zipkin B3 propagator, see https://github.com/openzipkin/b3-propagation,
+/// as implemented by `opentelemetry_zipkin`.
+///
+/// A missing sampling state defers the decision to the receiver: the
+/// extracted context is then sampled, as is a debug one. Nothing is injected
+/// without a span context, where `opentelemetry_zipkin` would still send a
+/// sampling state.
+#[derive(Debug)]
+pub struct B3Propagator(opentelemetry_zipkin::Propagator);
+
+impl B3Propagator {
+    pub fn new(encoding: B3Encoding) -> B3Propagator {
+        B3Propagator(opentelemetry_zipkin::Propagator::with_encoding(encoding))
+    }
+}
+
+impl TextMapPropagator for B3Propagator {
+    fn inject_context(&self, cx: &Context, injector: &mut dyn Injector) {
+        if cx.span().span_context().is_valid() {
+            let mut injector = FieldsOnly {
+                fields: self.0.fields().collect(),
+                injector,
+            };
+            self.0.inject_context(cx, &mut injector)
+        }
+    }
+
+    fn extract_with_context(&self, cx: &Context, extractor: &dyn Extractor) -> Context {
+        let extracted = self.0.extract_with_context(&Context::new(), extractor);
+        let span = extracted.span();
+        let span_context = span.span_context();
+        if !span_context.is_valid() {
+            return cx.clone();
+        }
+        let flags = span_context.trace_flags();
+        let sampled = flags.is_sampled() || flags & B3_DEFERRED_OR_DEBUG != TraceFlags::default();
+        cx.with_remote_span_context(SpanContext::new(
+            span_context.trace_id(),
+            span_context.span_id(),
+            if sampled {
+                TraceFlags::SAMPLED
+            } else {
+                TraceFlags::default()
+            },
+            true,
+            TraceState::default(),
+        ))
+    }
+
+    fn fields(&self) -> FieldIter<'_> {
+        self.0.fields()
+    }
+}
+
+/// Trace flags `opentelemetry_zipkin` sets on a context without sampling
+/// state, or with the debug flag, which implies sampled.
+const B3_DEFERRED_OR_DEBUG: TraceFlags = TraceFlags::new(0x02 | 0x04);
+
+/// Sets the `fields` of the encoding only: `opentelemetry_zipkin` sets the
+/// `X-B3-*` headers along with the single `b3` one.
+struct FieldsOnly<'a, 'b> {
+    fields: Vec<&'a str>,
+    injector: &'b mut dyn Injector,
+}
+
+impl Injector for FieldsOnly<'_, '_> {
+    fn set(&mut self, key: &str, value: String) {
+        if self.fields.contains(&key) {
+            self.injector.set(key, value)
+        }
+    }
+}
+
+impl FromStr for Propagator {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tracecontext" => Ok(Propagator::TraceContext),
+            "baggage" => Ok(Propagator::Baggage),
+            "jaeger" => Ok(Propagator::Jaeger),
+            "b3" => Ok(Propagator::B3),
+            "b3multi" => Ok(Propagator::B3Multi),
+            _ => Err(format!("unsupported propagator: {}", s)),
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//! Rate, errors and duration (RED) metrics of the requests traced.
+//!
+//! The tags of the spans of a request tell its method, status, frontend and
+//! backend: once its last span ends, the request is counted in
+//! `haproxy.requests`, in `haproxy.request.errors` if it failed, and its
+//! duration, from its first message to its last one, is recorded in
+//! `haproxy.request.duration`. A request expired or evicted from the span
+//! store is counted as an error, with the `aborted` status class.
+//!
+//! Every distinct set of attributes is a time series of its own: each
+//! attribute keeps at most `attribute_limit` values, the following ones are
+//! replaced by `other`.
+
+use opentelemetry::metrics::{Counter, Meter, Unit, ValueRecorder};
+use opentelemetry::{global, Key, KeyValue, Value};
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Name of the meter of the agent.
+pub const METER_NAME: &str = "haproxy-spoa";
+
+/// Values kept for each attribute, by default.
+pub const DEFAULT_ATTRIBUTE_LIMIT: usize = 100;
+
+/// Tags of the spans the attributes of the request are read from.
+const METHOD_TAG: &str = "http.method";
+const STATUS_CODE_TAG: &str = "http.status_code";
+const FRONTEND_TAG: &str = "haproxy.frontend";
+const BACKEND_TAG: &str = "haproxy.backend";
+const ERROR_TAG: &str = "error";
+
+const STATUS_CLASS_ATTRIBUTE: &str = "http.status_class";
+
+/// Value replacing those beyond the limit of an attribute.
+const OTHER: &str = "other";
+
+/// Attributes of a request, read from the tags of its spans.
+#[derive(Clone, Debug, Default)]
+pub struct RequestAttributes {
+    method: Option<String>,
+    status_code: Option<i64>,
+    frontend: Option<String>,
+    backend: Option<String>,
+    error: bool,
+}
+
+impl RequestAttributes {
+    /// Keep the value of `tag`, if it is one of the request attributes.
+    pub fn observe(&mut self, tag: &KeyValue) {
+        match tag.key.as_str() {
+            METHOD_TAG => self.method = Some(tag.value.as_str().into_owned()),
+            STATUS_CODE_TAG => self.status_code = status_code(&tag.value),
+            FRONTEND_TAG => self.frontend = Some(tag.value.as_str().into_owned()),
+            BACKEND_TAG => self.backend = Some(tag.value.as_str().into_owned()),
+            ERROR_TAG => self.error = tag.value == Value::Bool(true),
+            _ => {}
+        }
+    }
+
+    fn status_class(&self) -> String {
+        match self.status_code {
+            Some(code) if (100..600).contains(&code) => format!("{}xx", code / 100),
+            _ => "unknown".to_string(),
+        }
+    }
+
+    fn is_error(&self) -> bool {
+        self.error || matches!(self.status_code, Some(code) if code >= 500)
+    }
+}
+
+/// The status code is given as an integer or a string, depending on the
+/// sample fetch.
+fn status_code(value: &Value) -> Option<i64> {
+    match value {
+        Value::I64(code) => Some(*code),
+        value => value.as_str().parse().ok(),
+    }
+}
+
+/// Instruments of the RED metrics.
+pub struct RequestMetrics {
+    requests: Counter<u64>,
+    errors: Counter<u64>,
+    duration: ValueRecorder<f64>,
+    attribute_limit: usize,
+    values: Mutex<HashMap<&'static str, HashSet<String>>>,
+}
+
+impl Default for RequestMetrics {
+    fn default() -> Self {
+        RequestMetrics::new(&global::meter(METER_NAME), DEFAULT_ATTRIBUTE_LIMIT)
+    }
+}
+
+impl RequestMetrics {
+    /// Instruments of `meter`, the instruments of the global meter are only
+    /// recorded if its provider is installed beforehand.
+    pub fn new(meter: &Meter, attribute_limit: usize) -> RequestMetrics {
+        RequestMetrics {
+            requests: meter
+                .u64_counter("haproxy.requests")
+                .with_description("Requests traced")
+                .init(),
+            errors: meter
+                .u64_counter("haproxy.request.errors")
+                .with_description("Requests failed, with a 5xx status or an error tag")
+                .init(),
+            duration: meter
+                .f64_value_recorder("haproxy.request.duration")
+                .with_description("Time between the first and the last message of a request")
+                .with_unit(Unit::new("s"))
+                .init(),
+            attribute_limit,
+            values: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record a request whose last span ended.
+    pub fn completed(&self, request: &RequestAttributes, duration: Duration) {
+        let attributes = self.attributes(request, request.status_class());
+        self.requests.add(1, &attributes);
+        if request.is_error() {
+            self.errors.add(1, &attributes);
+        }
+        self.duration.record(duration.as_secs_f64(), &attributes);
+    }
+
+    /// Record a request removed from the span store before its last span
+    /// ended.
+    pub fn aborted(&self, request: &RequestAttributes, duration: Duration) {
+        let attributes = self.attributes(request, "aborted".to_string());
+        self.requests.add(1, &attributes);
+        self.errors.add(1, &attributes);
+        self.duration.record(duration.as_secs_f64(), &attributes);
+    }
+
+    fn attributes(&self, request: &RequestAttributes, status_class: String) -> Vec<KeyValue> {
+        let attributes = [
+            (METHOD_TAG, request.method.clone()),
+            (STATUS_CLASS_ATTRIBUTE, Some(status_class)),
+            (FRONTEND_TAG, request.frontend.clone()),
+            (BACKEND_TAG, request.backend.clone()),
+        ];
+        let mut values = self.values.lock().unwrap();
+        attributes
+            .iter()
+            .filter_map(|(key, value)| {
+                let value = value.as_ref()?;
+                let known = values.entry(key).or_default();
+                let value = if known.contains(value) {
+                    value.to_owned()
+                } else if known.len() < self.attribute_limit {
+                    known.insert(value.to_owned());
+                    value.to_owned()
+                } else {
+                    OTHER.to_string()
+                };
+                Some(Key::new(*key).string(value))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,164 @@
+//! Routing of the messages of NOTIFY frames.
+//!
+//! A NOTIFY frame may carry several messages, each one triggered by a
+//! different SPOE event. The `Router` dispatches every message to the
+//! `MessageHandler`s registered for its name, either exactly or by prefix
+//! (`opentracing:*`), and merges the resulting actions into a single ACK
+//! frame.
+
+use crate::frame::{Action, Error, FrameHeader, KVList, ListOfMessages};
+use crate::handler::SpoaHandler;
+use crate::negotiation::Negotiated;
+
+use async_trait::async_trait;
+use std::str::FromStr;
+use tracing::warn;
+
+/// Processes the messages routed to it by a `Router`.
+#[async_trait]
+pub trait MessageHandler: Send + Sync + 'static {
+    /// Process the message `name` with its arguments `args`, sent in the
+    /// NOTIFY frame identified by `header`.
+    async fn handle(
+        &self,
+        negotiated: &Negotiated,
+        header: &FrameHeader,
+        name: &str,
+        args: &KVList,
+    ) -> Result<Vec<Action>, Error>;
+
+    /// Called once the server shut down, after every connection is closed.
+    async fn shutdown(&self) {}
+}
+
+/// What to do with a message no handler is registered for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnknownMessages {
+    /// Skip the message silently.
+    Ignore,
+    /// Skip the message, and log its name.
+    Log,
+    /// Fail the whole NOTIFY frame, its ACK only carries the error, see
+    /// `SpoaHandler::notify`.
+    Reject,
+}
+
+/// Message names a handler is registered for.
+#[derive(Debug, PartialEq, Eq)]
+enum Pattern {
+    Exact(String),
+    Prefix(String),
+}
+
+impl Pattern {
+    /// A trailing `*` matches any suffix, other patterns must match the
+    /// whole name.
+    fn parse(pattern: &str) -> Pattern {
+        match pattern.strip_suffix('*') {
+            Some(prefix) => Pattern::Prefix(prefix.to_string()),
+            None => Pattern::Exact(pattern.to_string()),
+        }
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        match self {
+            Pattern::Exact(exact) => name == exact,
+            Pattern::Prefix(prefix) => name.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+struct Route {
+    pattern: Pattern,
+    handler: Box<dyn MessageHandler>,
+}
+
+/// `SpoaHandler` dispatching every message of a NOTIFY frame to the
+/// `MessageHandler`s matching its name.
+///
+/// When several handlers match a message, they are all invoked in the order
+/// they were registered. The messages of a frame are processed one after the
+/// other, in frame order: a message ending the request with `finish=*` is
+/// only processed once those sent before it in the same event are.
+pub struct Router {
+    routes: Vec<Route>,
+    unknown_messages: UnknownMessages,
+}
+
+impl Default for Router {
+    fn default() -> Self {
+        Router {
+            routes: vec![],
+            unknown_messages: UnknownMessages::Log,
+        }
+    }
+}
+
+impl Router {
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    /// Register `handler` for the messages matching `pattern`: either a
+    /// message name, or a prefix followed by `*`.
+    pub fn route<H: MessageHandler>(mut self, pattern: &str, handler: H) -> Router {
+        self.routes.push(Route {
+            pattern: Pattern::parse(pattern),
+            handler: Box::new(handler),
+        });
+        self
+    }
+
+    /// Set the behaviour for the messages no handler is registered for.
+    pub fn unknown_messages(mut self, unknown_messages: UnknownMessages) -> Router {
+        self.unknown_messages = unknown_messages;
+        self
+    }
+}
+
+#[async_trait]
+impl SpoaHandler for Router {
+    async fn notify(
+        &self,
+        negotiated: &Negotiated,
+        header: &FrameHeader,
+        messages: &ListOfMessages,
+    ) -> Result<Vec<Action>, Error> {
+        let mut actions: Vec<Action> = vec![];
+        for (name, args) in messages {
+            let mut routed = false;
+            for route in self.routes.iter().filter(|r| r.pattern.matches(name)) {
+                routed = true;
+                let mut handled = route.handler.handle(negotiated, header, name, args).await?;
+                actions.append(&mut handled);
+            }
+            if !routed {
+                match self.unknown_messages {
+                    UnknownMessages::Ignore => {}
+                    UnknownMessages::Log => warn!("no handler for message {}", name),
+                    UnknownMessages::Reject => return Err(Error::UnknownMessage(name.to_owned())),
+                }
+            }
+        }
+        Ok(actions)
+    }
+
+    async fn shutdown(&self) {
+        for route in &self.routes {
+            route.handler.shutdown().await;
+        }
+    }
+}
+
+impl FromStr for UnknownMessages {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(UnknownMessages::Ignore),
+            "log" => Ok(UnknownMessages::Log),
+            "reject" => Ok(UnknownMessages::Reject),
+            _ => Err(format!("invalid unknown messages behaviour: {}", s)),
+        }
+    }
+}
@@ -0,0 +1,508 @@
+//! SPOA server: accepts the connections from HAProxy and runs the SPOP
+//! protocol on each of them, the messages being processed by a
+//! `SpoaHandler`.
+
+use crate::connection::{Connection, ConnectionSettings};
+use crate::frame::{
+    Action, ActionVarScope, Error, Frame, FrameHeader, FrameType, ListOfMessages, StatusCode,
+    TypedData,
+};
+use crate::handler::SpoaHandler;
+use crate::listener::Listener;
+use crate::metrics::metrics;
+use crate::negotiation::{is_healthcheck, negotiate, Negotiated};
+use crate::shutdown::Shutdown;
+
+use futures::future;
+use std::fmt::Display;
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::{JoinError, JoinSet};
+use tokio::time::{self, Instant};
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
+
+/// Variable set by default when the handler fails to process a NOTIFY
+/// frame, see `Server::error_var`.
+pub const DEFAULT_ERROR_VAR: &str = "spoa_error";
+
+/// Pause of a listener after it failed to accept a connection.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Serves the connections of HAProxy, delegating the processing of the
+/// messages to the handler `H`.
+pub struct Server<H> {
+    settings: ConnectionSettings,
+    handler: Arc<H>,
+    error_var: Arc<str>,
+}
+
+impl<H: SpoaHandler> Server<H> {
+    /// Create a new `Server` applying `settings` to every connection.
+    pub fn new(settings: ConnectionSettings, handler: H) -> Server<H> {
+        Server {
+            settings,
+            handler: Arc::new(handler),
+            error_var: DEFAULT_ERROR_VAR.into(),
+        }
+    }
+
+    /// Name of the variable set in the ACK frame, holding the error, when
+    /// the handler fails to process a NOTIFY frame.
+    pub fn error_var(mut self, name: &str) -> Server<H> {
+        self.error_var = name.into();
+        self
+    }
+
+    /// Accept connections on every listener until `shutdown` completes.
+    ///
+    /// Every open connection then sends the ACK frames of the NOTIFY frames
+    /// in progress, waiting for them up to its `drain_timeout`, and is closed
+    /// with an AGENT-DISCONNECT frame. Once they are all closed, the handler
+    /// is shut down and the function returns.
+    ///
+    /// A listener failing to accept a connection keeps accepting the other
+    /// ones, unless the listener itself is broken: the connections are then
+    /// closed the same way, and the error returned.
+    pub async fn run(self, listeners: Vec<Listener>, shutdown: impl Future) -> Result<(), Error> {
+        // Every connection subscribes to `notify_shutdown`, and holds a clone
+        // of `shutdown_complete_tx` until it is closed. Once all the senders
+        // are dropped, every connection has sent its AGENT-DISCONNECT frame.
+        let (notify_shutdown, _) = broadcast::channel::<()>(1);
+        let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel::<()>(1);
+
+        let accept_all = future::try_join_all(
+            listeners
+                .iter()
+                .map(|listener| self.accept(listener, &notify_shutdown, &shutdown_complete_tx)),
+        );
+        let result = tokio::select! {
+            res = accept_all => res.map(|_| ()),
+            _ = shutdown => Ok(()),
+        };
+        drop(listeners);
+
+        drop(notify_shutdown);
+        drop(shutdown_complete_tx);
+        let _ = shutdown_complete_rx.recv().await;
+
+        self.handler.shutdown().await;
+        result
+    }
+
+    async fn accept(
+        &self,
+        listener: &Listener,
+        notify_shutdown: &broadcast::Sender<()>,
+        shutdown_complete_tx: &mpsc::Sender<()>,
+    ) -> Result<(), Error> {
+        loop {
+            let accepted = match listener {
+                Listener::Tcp(listener) => listener.accept().await.map(|(socket, addr)| {
+                    self.spawn(socket, addr, notify_shutdown, shutdown_complete_tx);
+                }),
+                // the peers of a Unix domain socket are unnamed, the
+                // connections are told apart by the socket they came from
+                Listener::Unix(unix, _) => match unix.accept().await {
+                    Ok((socket, _)) => {
+                        let addr = listener.local_addr()?;
+                        self.spawn(socket, addr, notify_shutdown, shutdown_complete_tx);
+                        Ok(())
+                    }
+                    Err(err) => Err(err),
+                },
+            };
+            match accepted {
+                Ok(()) => {}
+                // the listener keeps accepting the other connections, once
+                // the descriptors or the memory it lacked are released
+                Err(err) if is_temporary(&err) => {
+                    warn!("failed to accept a connection: {}", err);
+                    time::sleep(ACCEPT_BACKOFF).await;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    fn spawn<S>(
+        &self,
+        socket: S,
+        peer: impl Display,
+        notify_shutdown: &broadcast::Sender<()>,
+        shutdown_complete_tx: &mpsc::Sender<()>,
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let settings = self.settings.clone();
+        let handler = self.handler.clone();
+        let error_var = self.error_var.clone();
+        let shutdown = Shutdown::new(notify_shutdown.subscribe());
+        let shutdown_complete = shutdown_complete_tx.clone();
+        // the events of the connection are logged within its span
+        let span = info_span!("connection", peer = %peer, engine_id = field::Empty);
+        tokio::spawn(
+            async move {
+                // Process each socket concurrently.
+                process(
+                    socket,
+                    settings,
+                    handler,
+                    error_var,
+                    shutdown,
+                    shutdown_complete,
+                )
+                .await
+            }
+            .instrument(span),
+        );
+    }
+}
+
+async fn process<H: SpoaHandler, S: AsyncRead + AsyncWrite + Unpin>(
+    socket: S,
+    settings: ConnectionSettings,
+    handler: Arc<H>,
+    error_var: Arc<str>,
+    mut shutdown: Shutdown,
+    // Dropped once the connection is closed.
+    _shutdown_complete: mpsc::Sender<()>,
+) {
+    // The `Connection` lets us read/write redis **frames** instead of
+    // byte streams. The `Connection` type is defined by mini-redis.
+    let mut connection = Connection::with_settings(socket, settings);
+    let mut tasks = JoinSet::new();
+
+    serve(
+        &mut connection,
+        &handler,
+        &error_var,
+        &mut tasks,
+        &mut shutdown,
+    )
+    .await;
+
+    // The NOTIFY frames still in progress once the connection is closed are
+    // given the drain timeout to complete, without sending their ACK, and
+    // aborted past it: none of them outlives the connection.
+    let drain_timeout = connection.settings().drain_timeout;
+    let _ = time::timeout(drain_timeout, async {
+        while tasks.join_next().await.is_some() {}
+    })
+    .await;
+    abort(&mut tasks).await;
+
+    // the connection is counted once its HELLO handshake succeeded, unless
+    // it is a healthcheck
+    if let Some(negotiated) = connection.negotiated() {
+        if !negotiated.healthcheck {
+            metrics().connections.dec();
+            handler.closed(negotiated).await;
+        }
+    }
+}
+
+/// Process the frames of the connection until it is closed.
+async fn serve<H: SpoaHandler, S: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut Connection<S>,
+    handler: &Arc<H>,
+    error_var: &Arc<str>,
+    tasks: &mut JoinSet<Frame>,
+    shutdown: &mut Shutdown,
+) {
+    let max_in_flight = connection.settings().max_in_flight.max(1);
+
+    // In asynchronous mode, each NOTIFY frame is processed in its own task
+    // of `tasks`; the resulting ACK frames are sent back in the order they
+    // complete.
+    loop {
+        tokio::select! {
+            Some(joined) = tasks.join_next() => {
+                if let Some(response) = acknowledgement(joined) {
+                    if !write(connection, &response).await {
+                        return;
+                    }
+                }
+            }
+            frame = connection.read_frame(), if tasks.len() < max_in_flight => {
+                let frame = match frame {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => {
+                        info!("connection closed by peer");
+                        return;
+                    }
+                    Err(err) => {
+                        error!("{}", err);
+                        metrics().error(&err);
+                        disconnect(connection, err.status_code(), &err.to_string()).await;
+                        return;
+                    }
+                };
+                // healthchecks are answered without any logs or metrics
+                let healthcheck = matches!(
+                    &frame,
+                    Frame::HAProxyHello { content, .. } if is_healthcheck(content)
+                );
+                if !healthcheck {
+                    debug!("GOT: {:?}", frame);
+                    metrics().frame_received(&frame);
+                }
+
+                match frame {
+                    Frame::Notify { header, messages } => {
+                        let negotiated = match connection.negotiated() {
+                            Some(negotiated) => negotiated.clone(),
+                            None => {
+                                let message = "NOTIFY frame received before HELLO";
+                                warn!("disconnecting: {}", message);
+                                disconnect(connection, StatusCode::INVALID_FRAME, message).await;
+                                return;
+                            }
+                        };
+                        if negotiated.has_capability("async") {
+                            let handler = handler.clone();
+                            let error_var = error_var.clone();
+                            tasks.spawn(async move {
+                                handle_notify(
+                                    &*handler, &error_var, &negotiated, &header, &messages,
+                                )
+                                .await
+                            }.instrument(Span::current()));
+                        } else {
+                            let response = handle_notify(
+                                &**handler, error_var, &negotiated, &header, &messages,
+                            )
+                            .await;
+                            if !write(connection, &response).await {
+                                return;
+                            }
+                        }
+                    }
+                    Frame::HAProxyDisconnect { .. } => {
+                        if let Some((status_code, message)) = frame.disconnect_status() {
+                            info!("disconnected by peer: {} {}", status_code, message);
+                            if let Some(negotiated) = connection.negotiated() {
+                                handler.disconnect(negotiated, status_code, &message).await;
+                            }
+                        }
+                        let status_code = StatusCode::NORMAL;
+                        disconnect(connection, status_code, status_code.message()).await;
+                        return;
+                    }
+                    // the parameters of the connection are negotiated once
+                    Frame::HAProxyHello { .. } if connection.negotiated().is_some() => {
+                        let message = "HELLO frame received after the handshake";
+                        warn!("disconnecting: {}", message);
+                        disconnect(connection, StatusCode::INVALID_FRAME, message).await;
+                        return;
+                    }
+                    frame => match handle_frame(&frame, connection) {
+                        Ok(response) if healthcheck => {
+                            // the connection is closed once the AGENT-HELLO
+                            // frame is sent
+                            let _ = connection.write_frame(&response).await;
+                            return;
+                        }
+                        Ok(response) => {
+                            if let (Frame::AgentHello { .. }, Some(negotiated)) =
+                                (&response, connection.negotiated())
+                            {
+                                if let Some(engine_id) = &negotiated.engine_id {
+                                    Span::current().record("engine_id", engine_id.as_str());
+                                }
+                                info!("new connection");
+                                metrics().connections.inc();
+                                metrics().connections_total.inc();
+                            }
+                            if !write(connection, &response).await {
+                                return;
+                            }
+                            if let (Frame::AgentHello { .. }, Some(negotiated)) =
+                                (&response, connection.negotiated())
+                            {
+                                handler.hello(negotiated).await;
+                            }
+                        }
+                        Err(err) => {
+                            warn!("disconnecting: {}", err);
+                            metrics().error(&err);
+                            disconnect(connection, err.status_code(), &err.to_string()).await;
+                            return;
+                        }
+                    },
+                }
+            }
+            _ = shutdown.recv() => {
+                if drain(connection, tasks).await {
+                    info!("disconnecting: agent is shutting down");
+                    disconnect(connection, StatusCode::NORMAL, "agent is shutting down").await;
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// Whether accepting a connection failed because of this connection alone,
+/// or of a lack of resources that may be released, rather than of the
+/// listener itself.
+fn is_temporary(err: &io::Error) -> bool {
+    match err.kind() {
+        io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionRefused
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::TimedOut
+        | io::ErrorKind::OutOfMemory => true,
+        _ => matches!(
+            err.raw_os_error(),
+            Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM | libc::EPROTO)
+        ),
+    }
+}
+
+/// Send the ACK frames of the NOTIFY frames in progress, as they complete,
+/// until the drain timeout expires; those still in progress then are
+/// aborted. Returns `false` if the connection is broken.
+async fn drain<S: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut Connection<S>,
+    tasks: &mut JoinSet<Frame>,
+) -> bool {
+    let deadline = Instant::now() + connection.settings().drain_timeout;
+    while !tasks.is_empty() {
+        match time::timeout_at(deadline, tasks.join_next()).await {
+            Ok(Some(joined)) => {
+                if let Some(response) = acknowledgement(joined) {
+                    if !write(connection, &response).await {
+                        return false;
+                    }
+                }
+            }
+            Ok(None) => break,
+            Err(_) => {
+                warn!(
+                    "{} NOTIFY frames not acknowledged before the drain timeout",
+                    tasks.len()
+                );
+                abort(tasks).await;
+                break;
+            }
+        }
+    }
+    true
+}
+
+/// The ACK frame of a completed NOTIFY task, `None` if it panicked or was
+/// aborted.
+fn acknowledgement(joined: Result<Frame, JoinError>) -> Option<Frame> {
+    match joined {
+        Ok(response) => Some(response),
+        Err(err) => {
+            if err.is_panic() {
+                error!("NOTIFY frame processing panicked: {}", err);
+            }
+            None
+        }
+    }
+}
+
+/// Abort the NOTIFY tasks still in progress and wait for them to be
+/// dropped.
+async fn abort(tasks: &mut JoinSet<Frame>) {
+    tasks.abort_all();
+    while tasks.join_next().await.is_some() {}
+}
+
+fn handle_frame<S: AsyncRead + AsyncWrite + Unpin>(
+    frame: &Frame,
+    connection: &mut Connection<S>,
+) -> Result<Frame, Error> {
+    match frame {
+        Frame::HAProxyHello { header, content } => {
+            let negotiated =
+                negotiate(connection.settings(), content).map_err(Error::Negotiation)?;
+            let response = negotiated.agent_hello(header);
+            connection.set_negotiated(negotiated);
+            Ok(response)
+        }
+        _ => Err(Error::NotSupported),
+    }
+}
+
+/// Hand the messages over to the handler, and build the ACK frame carrying
+/// the resulting actions, or the `error_var` variable if the handler failed.
+async fn handle_notify<H: SpoaHandler>(
+    handler: &H,
+    error_var: &str,
+    negotiated: &Negotiated,
+    header: &FrameHeader,
+    messages: &ListOfMessages,
+) -> Frame {
+    for (name, _) in messages {
+        metrics().message_received(name);
+    }
+    let timer = metrics().notify_duration.start_timer();
+    let result = handler.notify(negotiated, header, messages).await;
+    timer.observe_duration();
+
+    let actions = match result {
+        Ok(actions) => actions,
+        Err(err) => {
+            error!("{:?}", err);
+            metrics().error(&err);
+            vec![Action::SetVar {
+                scope: ActionVarScope::REQUEST,
+                name: error_var.to_string(),
+                value: TypedData::STRING(err.to_string()),
+            }]
+        }
+    };
+    metrics().actions_sent(&actions);
+    Frame::Ack {
+        header: header.reply_header(&FrameType::ACK),
+        actions,
+    }
+}
+
+/// Write `frame` to the connection, returns `false` if the connection is
+/// broken and must be closed.
+///
+/// A frame exceeding the max-frame-size is not written, the connection is
+/// closed with an AGENT-DISCONNECT frame instead.
+async fn write<S: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut Connection<S>,
+    frame: &Frame,
+) -> bool {
+    debug!("REP: {:?}", frame);
+    match connection.write_frame(frame).await {
+        Ok(_) => {
+            metrics().frame_sent(frame);
+            true
+        }
+        Err(err) => {
+            error!("unable to write frame {}", err);
+            metrics().error(&err);
+            if let Error::FrameTooBig { .. } = err {
+                let frame = Frame::agent_disconnect(err.status_code(), &err.to_string());
+                if connection.write_frame(&frame).await.is_ok() {
+                    metrics().frame_sent(&frame);
+                }
+            }
+            false
+        }
+    }
+}
+
+/// Send an AGENT-DISCONNECT frame, the connection is expected to be closed
+/// right after.
+async fn disconnect<S: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut Connection<S>,
+    status_code: StatusCode,
+    message: &str,
+) {
+    let frame = Frame::agent_disconnect(status_code, message);
+    write(connection, &frame).await;
+}
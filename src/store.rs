@@ -0,0 +1,286 @@
+//! Spans in progress of the requests, kept until their last span ends.
+//!
+//! A request whose last span never ends, aborted by the client or lost in a
+//! reload of HAProxy, would be kept forever: every request has a deadline,
+//! pushed back by each of its messages, past which `SpanStore::expire`
+//! removes it. The store is bounded too: once full, a new request either
+//! evicts the request closest to its deadline, or is not stored at all.
+//!
+//! Every connection updates the store on every NOTIFY frame: the requests
+//! are spread over shards by their key, each one behind its own lock, so
+//! that the connections updating different requests seldom wait for each
+//! other. The capacity is split between the shards, their capacities
+//! adding up to it exactly, and the eviction picks the oldest request of its
+//! shard.
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::hash::BuildHasher;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// What to do with a new request when the store is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Eviction {
+    /// Evict the request closest to its deadline.
+    Oldest,
+    /// Keep the stored requests, the new one is not stored.
+    Reject,
+}
+
+#[derive(Clone, Debug)]
+pub struct StoreSettings {
+    /// Time a request is kept after its last message.
+    pub ttl: Duration,
+
+    /// Maximum number of requests kept, at least 1.
+    pub capacity: usize,
+
+    pub eviction: Eviction,
+
+    /// Interval between two sweeps of the expired requests.
+    pub sweep_interval: Duration,
+
+    /// Number of locks the requests are spread over, at most `capacity`.
+    pub shards: usize,
+}
+
+impl Default for StoreSettings {
+    fn default() -> Self {
+        StoreSettings {
+            ttl: Duration::from_secs(300),
+            capacity: 100_000,
+            eviction: Eviction::Oldest,
+            sweep_interval: Duration::from_secs(1),
+            shards: 32,
+        }
+    }
+}
+
+/// A request removed from the store before its last span ended.
+#[derive(Debug)]
+pub struct Expired<T> {
+    pub key: String,
+    pub value: T,
+
+    /// Last message received for the request.
+    pub last_event: String,
+}
+
+/// Whether `SpanStore::update` keeps the request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Retain {
+    Keep,
+    Remove,
+}
+
+/// The store is full, and its eviction policy is `Reject`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct StoreFull;
+
+struct Entry<T> {
+    value: T,
+    deadline: Deadline,
+    last_event: String,
+}
+
+// The sequence number tells apart the requests expiring at the same instant.
+type Deadline = (Instant, u64);
+
+struct Entries<T> {
+    entries: HashMap<String, Entry<T>>,
+    deadlines: BTreeMap<Deadline, String>,
+    sequence: u64,
+}
+
+impl<T> Default for Entries<T> {
+    fn default() -> Self {
+        Entries {
+            entries: HashMap::new(),
+            deadlines: BTreeMap::new(),
+            sequence: 0,
+        }
+    }
+}
+
+impl<T> Entries<T> {
+    fn insert(&mut self, key: &str, mut entry: Entry<T>, deadline: Instant) {
+        self.sequence += 1;
+        entry.deadline = (deadline, self.sequence);
+        self.deadlines.insert(entry.deadline, key.to_string());
+        self.entries.insert(key.to_string(), entry);
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry<T>> {
+        let entry = self.entries.remove(key)?;
+        self.deadlines.remove(&entry.deadline);
+        Some(entry)
+    }
+
+    /// Remove the request closest to its deadline, if it is before `now`.
+    fn pop_first(&mut self, now: Option<Instant>) -> Option<Expired<T>> {
+        let (&deadline, _) = self.deadlines.iter().next()?;
+        if let Some(now) = now {
+            if deadline.0 > now {
+                return None;
+            }
+        }
+        let key = self.deadlines.remove(&deadline)?;
+        let entry = self.entries.remove(&key)?;
+        Some(Expired {
+            key,
+            value: entry.value,
+            last_event: entry.last_event,
+        })
+    }
+}
+
+/// Requests identified by a key, each one holding a `T`.
+pub struct SpanStore<T> {
+    settings: StoreSettings,
+    shards: Vec<Mutex<Entries<T>>>,
+    hasher: RandomState,
+}
+
+impl<T> Default for SpanStore<T> {
+    fn default() -> Self {
+        SpanStore::new(StoreSettings::default())
+    }
+}
+
+impl<T> SpanStore<T> {
+    /// # Panics
+    ///
+    /// Panics if `settings.capacity` is 0: the store could not hold any
+    /// request.
+    pub fn new(settings: StoreSettings) -> SpanStore<T> {
+        assert!(
+            settings.capacity > 0,
+            "span store capacity must be at least 1"
+        );
+        // every shard holds at least one request
+        let shards = settings.shards.min(settings.capacity).max(1);
+        SpanStore {
+            shards: (0..shards).map(|_| Mutex::default()).collect(),
+            hasher: RandomState::new(),
+            settings,
+        }
+    }
+
+    pub fn settings(&self) -> &StoreSettings {
+        &self.settings
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().entries.len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remove the requests whose deadline is before `now`.
+    pub fn expire(&self, now: Instant) -> Vec<Expired<T>> {
+        let mut expired = vec![];
+        for shard in &self.shards {
+            let mut entries = shard.lock().unwrap();
+            while let Some(request) = entries.pop_first(Some(now)) {
+                expired.push(request);
+            }
+        }
+        expired
+    }
+
+    /// Remove every request, e.g. when the agent stops.
+    pub fn drain(&self) -> Vec<Expired<T>> {
+        let mut drained = vec![];
+        for shard in &self.shards {
+            let mut entries = shard.lock().unwrap();
+            while let Some(request) = entries.pop_first(None) {
+                drained.push(request);
+            }
+        }
+        drained
+    }
+
+    /// Shard of the request `key`, and its capacity: the first shards hold
+    /// one more request when the capacity is not a multiple of their number.
+    fn shard(&self, key: &str) -> (&Mutex<Entries<T>>, usize) {
+        let count = self.shards.len();
+        let index = self.hasher.hash_one(key) as usize % count;
+        let capacity =
+            self.settings.capacity / count + usize::from(index < self.settings.capacity % count);
+        (&self.shards[index], capacity)
+    }
+}
+
+impl<T: Default> SpanStore<T> {
+    /// Apply `f` to the request `key`, stored if it is new, on receipt of
+    /// the message `event`, and push back its deadline unless `f` removes
+    /// it.
+    ///
+    /// Storing a new request may evict another one, returned along with the
+    /// result of `f`.
+    pub fn update<R>(
+        &self,
+        key: &str,
+        event: &str,
+        f: impl FnOnce(&mut T) -> (R, Retain),
+    ) -> Result<(R, Option<Expired<T>>), StoreFull> {
+        let now = Instant::now();
+        let (shard, capacity) = self.shard(key);
+        let mut entries = shard.lock().unwrap();
+
+        let mut evicted = None;
+        let mut entry = match entries.remove(key) {
+            Some(entry) => entry,
+            None => {
+                if entries.entries.len() >= capacity {
+                    match self.settings.eviction {
+                        // the request is only stored once a slot is freed
+                        Eviction::Oldest => match entries.pop_first(None) {
+                            Some(request) => evicted = Some(request),
+                            None => return Err(StoreFull),
+                        },
+                        Eviction::Reject => return Err(StoreFull),
+                    }
+                }
+                Entry {
+                    value: T::default(),
+                    deadline: (now, 0),
+                    last_event: String::new(),
+                }
+            }
+        };
+
+        entry.last_event = event.to_string();
+        let (result, retain) = f(&mut entry.value);
+        if retain == Retain::Keep {
+            entries.insert(key, entry, now + self.settings.ttl);
+        }
+        Ok((result, evicted))
+    }
+}
+
+impl fmt::Display for StoreFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "span store is full")
+    }
+}
+
+impl FromStr for Eviction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "oldest" => Ok(Eviction::Oldest),
+            "reject" => Ok(Eviction::Reject),
+            _ => Err(format!("invalid eviction policy: {}", s)),
+        }
+    }
+}
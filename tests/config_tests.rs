@@ -0,0 +1,268 @@
+use clap::Parser;
+use haproxy_spoa_rust::config::{Args, Config};
+use haproxy_spoa_rust::exporter::{Compression, Exporter};
+use haproxy_spoa_rust::listener::ListenAddr;
+use haproxy_spoa_rust::logging::LogFormat;
+use haproxy_spoa_rust::otel::IdFallback;
+use haproxy_spoa_rust::propagation::Propagator;
+use haproxy_spoa_rust::store::Eviction;
+use std::collections::HashMap;
+use std::time::Duration;
+
+fn vars(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+    let vars: HashMap<String, String> = vars
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    move |name| vars.get(name).cloned()
+}
+
+#[test]
+fn should_default_every_setting() {
+    let settings = Config::default().settings().unwrap();
+    assert_eq!(settings.service_name, "spoa");
+    assert_eq!(settings.listen, vec!["0.0.0.0:7000".parse().unwrap()]);
+    assert_eq!(settings.unix_socket.mode, None);
+    assert_eq!(settings.messages, "opentracing:*");
+    assert_eq!(
+        settings.propagators,
+        vec![Propagator::TraceContext, Propagator::Baggage]
+    );
+    assert_eq!(settings.exporter.exporter, Exporter::JaegerAgent);
+    assert_eq!(settings.otel.id_arg.as_deref(), Some("id"));
+    assert!(settings.metrics_listen.is_none());
+}
+
+#[test]
+fn should_read_the_example_configuration() {
+    let config = Config::from_toml(include_str!("../devenv/conf/spoa.toml")).unwrap();
+    let settings = config.settings().unwrap();
+    let defaults = Config::default().settings().unwrap();
+    assert_eq!(settings.listen, defaults.listen);
+    assert_eq!(
+        settings.connection.drain_timeout,
+        defaults.connection.drain_timeout
+    );
+    assert_eq!(
+        settings.otel.recorded_headers,
+        defaults.otel.recorded_headers
+    );
+}
+
+#[test]
+fn should_read_toml_and_yaml_alike() {
+    let toml = r#"
+        service-name = "edge"
+        listen = ["127.0.0.1:7001", "unix@/run/spoa.sock"]
+
+        [unix-socket]
+        mode = "660"
+        gid = 99
+
+        [spop]
+        async = false
+        drain-timeout-ms = 100
+
+        [tracing]
+        propagators = ["b3"]
+        request-id-arg = ""
+        recorded-headers = ["X-Tenant"]
+
+        [tracing.span-store]
+        eviction = "reject"
+
+        [exporter]
+        type = "otlp-http"
+        compression = "gzip"
+
+        [exporter.headers]
+        api-key = "secret"
+
+        [log]
+        format = "json"
+
+        [metrics]
+        listen = "127.0.0.1:9090"
+    "#;
+    let yaml = r#"
+        service-name: edge
+        listen: [127.0.0.1:7001, unix@/run/spoa.sock]
+        unix-socket:
+          mode: "660"
+          gid: 99
+        spop:
+          async: false
+          drain-timeout-ms: 100
+        tracing:
+          propagators: [b3]
+          request-id-arg: ""
+          recorded-headers: [X-Tenant]
+          span-store:
+            eviction: reject
+        exporter:
+          type: otlp-http
+          compression: gzip
+          headers:
+            api-key: secret
+        log:
+          format: json
+        metrics:
+          listen: 127.0.0.1:9090
+    "#;
+    for config in [Config::from_toml(toml), Config::from_yaml(yaml)] {
+        let settings = config.unwrap().settings().unwrap();
+        assert_eq!(settings.service_name, "edge");
+        assert_eq!(
+            settings.listen,
+            vec![
+                ListenAddr::Tcp("127.0.0.1:7001".parse().unwrap()),
+                ListenAddr::Unix("/run/spoa.sock".into())
+            ]
+        );
+        assert_eq!(settings.unix_socket.mode, Some(0o660));
+        assert_eq!(settings.unix_socket.uid, None);
+        assert_eq!(settings.unix_socket.gid, Some(99));
+        assert!(!settings.connection.asynchronous);
+        assert_eq!(
+            settings.connection.drain_timeout,
+            Duration::from_millis(100)
+        );
+        assert_eq!(settings.propagators, vec![Propagator::B3]);
+        assert_eq!(settings.otel.id_arg, None);
+        assert_eq!(settings.otel.recorded_headers, vec!["x-tenant"]);
+        assert_eq!(settings.otel.store.eviction, Eviction::Reject);
+        assert_eq!(settings.exporter.exporter, Exporter::OtlpHttp);
+        assert_eq!(settings.exporter.compression, Compression::Gzip);
+        assert_eq!(
+            settings.exporter.headers,
+            vec![("api-key".to_string(), "secret".to_string())]
+        );
+        assert_eq!(settings.log.format, LogFormat::Json);
+        assert_eq!(
+            settings.metrics_listen,
+            Some("127.0.0.1:9090".parse().unwrap())
+        );
+    }
+}
+
+#[test]
+fn should_reject_invalid_settings() {
+    assert!(Config::from_toml("[spop]\nmax-frame = 1024").is_err());
+    assert!(Config::from_toml("[spop]\nmax-frame-size = \"big\"").is_err());
+
+    let invalid = |toml: &str| Config::from_toml(toml).unwrap().settings().unwrap_err();
+    assert_eq!(
+        invalid("[tracing.span-store]\neviction = \"random\""),
+        "invalid tracing.span-store.eviction: invalid eviction policy: random"
+    );
+    assert!(invalid("listen = \"localhost\"").starts_with("invalid listen localhost"));
+    assert_eq!(
+        invalid("[unix-socket]\nmode = \"rw\""),
+        "invalid unix-socket.mode rw: invalid digit found in string"
+    );
+    assert_eq!(
+        invalid("[exporter]\ntype = \"stdout\"\ncompression = \"gzip\""),
+        "compression is not supported by stdout"
+    );
+    assert_eq!(
+        invalid("[exporter]\ntimeout-ms = 1000"),
+        "timeout is not supported by jaeger"
+    );
+    assert!(invalid("[log]\nlevel = \"=\"").starts_with("invalid log filter"));
+    assert_eq!(
+        invalid("[spop]\nmax-frame-size = 128"),
+        "invalid spop.max-frame-size 128: below the minimum of 256"
+    );
+    assert_eq!(
+        invalid("[spop]\nmax-in-flight = 0"),
+        "invalid spop.max-in-flight 0: must be at least 1"
+    );
+    assert_eq!(
+        invalid("[tracing.span-store]\ncapacity = 0"),
+        "invalid tracing.span-store.capacity 0: must be at least 1"
+    );
+    assert_eq!(
+        invalid("[exporter.batch]\nmax-queue-size = 0"),
+        "invalid exporter.batch.max-queue-size 0: must be at least 1"
+    );
+    assert_eq!(
+        invalid("[exporter.batch]\nschedule-delay-ms = 0"),
+        "invalid exporter.batch.schedule-delay-ms 0: must be at least 1"
+    );
+    assert_eq!(
+        invalid("[metrics]\nexporter = \"stdout\"\ninterval-ms = 0"),
+        "invalid metrics.interval-ms 0: must be at least 1"
+    );
+}
+
+#[test]
+fn should_override_the_file_by_the_environment() {
+    let mut config = Config::from_toml(
+        r#"
+        listen = "127.0.0.1:7001"
+        [tracing]
+        request-id-fallback = "error"
+        [exporter]
+        type = "otlp"
+        "#,
+    )
+    .unwrap();
+    config
+        .apply_env(vars(&[
+            ("PORT", "7002"),
+            ("REQUEST_ID_FALLBACK", "stream"),
+            ("OTEL_PROPAGATORS", "jaeger,b3multi"),
+            ("SPAN_TTL_MS", "1000"),
+            ("METRICS_PORT", "9091"),
+        ]))
+        .unwrap();
+    let settings = config.settings().unwrap();
+    assert_eq!(settings.listen, vec!["0.0.0.0:7002".parse().unwrap()]);
+    assert_eq!(settings.otel.id_fallback, IdFallback::Stream);
+    assert_eq!(
+        settings.propagators,
+        vec![Propagator::Jaeger, Propagator::B3Multi]
+    );
+    assert_eq!(settings.otel.store.ttl, Duration::from_secs(1));
+    assert_eq!(settings.exporter.exporter, Exporter::OtlpGrpc);
+    assert_eq!(
+        settings.metrics_listen,
+        Some("0.0.0.0:9091".parse().unwrap())
+    );
+
+    let mut config = Config::default();
+    assert_eq!(
+        config.apply_env(vars(&[("MAX_IN_FLIGHT", "many")])),
+        Err("invalid MAX_IN_FLIGHT many: invalid digit found in string".to_string())
+    );
+}
+
+#[test]
+fn should_override_the_environment_by_the_arguments() {
+    let args = Args::try_parse_from([
+        "spoa",
+        "--listen",
+        "127.0.0.1:7003",
+        "--listen",
+        "unix@/run/spoa.sock",
+        "--log-level",
+        "debug",
+        "--check-config",
+    ])
+    .unwrap();
+    assert!(args.check_config);
+    let config = args
+        .config(vars(&[("PORT", "7002"), ("LOG_LEVEL", "warn")]))
+        .unwrap();
+    let settings = config.settings().unwrap();
+    assert_eq!(
+        settings.listen,
+        vec![
+            "127.0.0.1:7003".parse().unwrap(),
+            "unix@/run/spoa.sock".parse().unwrap()
+        ]
+    );
+    assert_eq!(settings.log.filter, "debug");
+
+    assert!(Args::try_parse_from(["spoa", "--port", "7000"]).is_err());
+}
@@ -0,0 +1,187 @@
+use flate2::read::GzDecoder;
+use haproxy_spoa_rust::exporter::{
+    parse_headers, tracer_provider, BatchSettings, Compression, Exporter, ExporterSettings,
+    MetricsExporter,
+};
+use opentelemetry::trace::{Span, Tracer, TracerProvider};
+use std::io::Read;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+#[test]
+fn should_parse_exporter_settings() {
+    assert_eq!("otlp".parse(), Ok(Exporter::OtlpGrpc));
+    assert_eq!("otlp-http".parse(), Ok(Exporter::OtlpHttp));
+    assert_eq!("jaeger".parse(), Ok(Exporter::JaegerAgent));
+    assert_eq!("jaeger-collector".parse(), Ok(Exporter::JaegerCollector));
+    assert_eq!("stdout".parse(), Ok(Exporter::Stdout));
+    assert!("zipkin".parse::<Exporter>().is_err());
+
+    assert_eq!("gzip".parse(), Ok(Compression::Gzip));
+    assert!("zstd".parse::<Compression>().is_err());
+
+    assert_eq!("none".parse(), Ok(MetricsExporter::None));
+    assert_eq!("otlp".parse(), Ok(MetricsExporter::Otlp));
+    assert!("prometheus".parse::<MetricsExporter>().is_err());
+
+    assert_eq!(
+        parse_headers("api-key=secret, tenant=a=b"),
+        Ok(vec![
+            ("api-key".to_string(), "secret".to_string()),
+            ("tenant".to_string(), "a=b".to_string()),
+        ])
+    );
+    assert!(parse_headers("api-key").is_err());
+}
+
+#[tokio::test]
+async fn should_reject_unsupported_options() {
+    let settings = ExporterSettings {
+        exporter: Exporter::OtlpGrpc,
+        compression: Compression::Gzip,
+        ..ExporterSettings::default()
+    };
+    assert!(tracer_provider("spoa".to_string(), &settings).is_err());
+
+    let settings = ExporterSettings {
+        exporter: Exporter::JaegerAgent,
+        headers: vec![("api-key".to_string(), "secret".to_string())],
+        ..ExporterSettings::default()
+    };
+    assert!(tracer_provider("spoa".to_string(), &settings).is_err());
+
+    let settings = ExporterSettings {
+        exporter: Exporter::JaegerAgent,
+        timeout: Some(Duration::from_secs(1)),
+        ..ExporterSettings::default()
+    };
+    assert_eq!(
+        settings.validate(),
+        Err("timeout is not supported by jaeger".to_string())
+    );
+
+    let settings = ExporterSettings {
+        exporter: Exporter::OtlpGrpc,
+        headers: vec![("api key".to_string(), "secret".to_string())],
+        ..ExporterSettings::default()
+    };
+    assert!(tracer_provider("spoa".to_string(), &settings).is_err());
+}
+
+#[tokio::test]
+async fn should_reject_batch_settings_the_processor_panics_on() {
+    for batch in [
+        BatchSettings {
+            max_queue_size: 0,
+            ..BatchSettings::default()
+        },
+        BatchSettings {
+            max_export_batch_size: 0,
+            ..BatchSettings::default()
+        },
+        BatchSettings {
+            scheduled_delay: Duration::ZERO,
+            ..BatchSettings::default()
+        },
+    ] {
+        let settings = ExporterSettings {
+            exporter: Exporter::Stdout,
+            batch,
+            ..ExporterSettings::default()
+        };
+        assert!(tracer_provider("spoa".to_string(), &settings).is_err());
+    }
+}
+
+/// Headers, lowercased, and body of the next HTTP request, answered with
+/// `200 OK`.
+async fn receive(listener: &TcpListener) -> (String, Vec<u8>) {
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let mut received = vec![];
+    let mut buf = [0u8; 4096];
+    let (head, length) = loop {
+        let n = stream.read(&mut buf).await.unwrap();
+        received.extend_from_slice(&buf[..n]);
+        let text = String::from_utf8_lossy(&received).to_lowercase();
+        if let Some(end) = text.find("\r\n\r\n") {
+            let head = text[..end].to_string();
+            let length: usize = head
+                .lines()
+                .find_map(|l| l.strip_prefix("content-length: "))
+                .unwrap()
+                .parse()
+                .unwrap();
+            received.drain(..end + 4);
+            break (head, length);
+        }
+    };
+    while received.len() < length {
+        let n = stream.read(&mut buf).await.unwrap();
+        received.extend_from_slice(&buf[..n]);
+    }
+    stream
+        .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+        .await
+        .unwrap();
+    (head, received)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn should_export_compressed_spans_over_otlp_http() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let settings = ExporterSettings {
+        exporter: Exporter::OtlpHttp,
+        endpoint: Some(format!(
+            "http://{}/v1/traces",
+            listener.local_addr().unwrap()
+        )),
+        headers: vec![("api-key".to_string(), "secret".to_string())],
+        compression: Compression::Gzip,
+        ..ExporterSettings::default()
+    };
+    let (provider, _) = tracer_provider("spoa-test".to_string(), &settings).unwrap();
+    provider.tracer("test").start("exported").end();
+
+    let flushed = tokio::task::spawn_blocking(move || provider.force_flush());
+    let (head, body) = receive(&listener).await;
+    flushed.await.unwrap();
+
+    assert!(head.starts_with("post /v1/traces "));
+    assert!(head.contains("content-type: application/x-protobuf"));
+    assert!(head.contains("content-encoding: gzip"));
+    assert!(head.contains("api-key: secret"));
+
+    let mut decoded = vec![];
+    GzDecoder::new(&body[..]).read_to_end(&mut decoded).unwrap();
+    let decoded = String::from_utf8_lossy(&decoded);
+    assert!(decoded.contains("exported"));
+    assert!(decoded.contains("spoa-test"));
+}
+
+#[tokio::test]
+async fn should_count_spans_dropped_when_the_queue_is_full() {
+    let settings = ExporterSettings {
+        exporter: Exporter::Stdout,
+        batch: BatchSettings {
+            max_queue_size: 1,
+            scheduled_delay: Duration::from_secs(60),
+            ..BatchSettings::default()
+        },
+        ..ExporterSettings::default()
+    };
+    let (provider, dropped) = tracer_provider("spoa-test".to_string(), &settings).unwrap();
+
+    // the processor does not run before the test yields: the first span
+    // fills its queue
+    let tracer = provider.tracer("test");
+    for _ in 0..5 {
+        tracer.start("dropped").end();
+    }
+    assert_eq!(dropped.get(), 4);
+
+    // shutting down blocks until the processor exported the queued span
+    tokio::task::spawn_blocking(move || drop(provider))
+        .await
+        .unwrap();
+}
@@ -0,0 +1,143 @@
+use bytes::Bytes;
+use haproxy_spoa_rust::fragment::Reassembler;
+use haproxy_spoa_rust::frame::{
+    Error, FragmentError, Frame, FrameFlags, FrameHeader, FrameType, StatusCode,
+};
+
+// payload of the NOTIFY frame used in frame_tests: a single
+// "opentracing:frontend_tcp_request" message with 3 args
+const NOTIFY_PAYLOAD: &str = "20, 6f, 70, 65, 6e, 74, 72, 61, 63, 69, 6e, 67, 3a, 66, 72, 6f, 6e, 74, 65, 6e, 64, 5f, 74, 63, 70, 5f, 72, 65, 71, 75, 65, 73, 74, 3, 2, 69, 64, 8, 29, 36, 31, 62, 35, 37, 65, 66, 30, 2d, 32, 34, 62, 62, 2d, 34, 32, 63, 37, 2d, 38, 39, 33, 35, 2d, 61, 65, 64, 64, 32, 37, 36, 61, 66, 34, 61, 35, 3a, 30, 30, 30, 38, 4, 73, 70, 61, 6e, 8, 14, 46, 72, 6f, 6e, 74, 65, 6e, 64, 20, 54, 43, 50, 20, 72, 65, 71, 75, 65, 73, 74, 8, 63, 68, 69, 6c, 64, 2d, 6f, 66, 8, e, 43, 6c, 69, 65, 6e, 74, 20, 73, 65, 73, 73, 69, 6f, 6e";
+
+fn from_hex_string(raw: &str) -> Vec<u8> {
+    raw.split(", ")
+        .map(|sub| u8::from_str_radix(sub, 16).ok().unwrap())
+        .collect()
+}
+
+fn header(r#type: FrameType, is_fin: bool, is_abort: bool) -> FrameHeader {
+    FrameHeader {
+        r#type,
+        flags: FrameFlags::new(is_fin, is_abort),
+        stream_id: 2,
+        frame_id: 7,
+    }
+}
+
+#[test]
+fn should_reassemble_fragmented_notify_frame() {
+    let payload = from_hex_string(NOTIFY_PAYLOAD);
+    let mut reassembler = Reassembler::new(1024);
+
+    // split in the middle of strings, not on KV boundaries
+    let chunks: Vec<&[u8]> = vec![&payload[..10], &payload[10..57], &payload[57..]];
+
+    let first = reassembler
+        .push(
+            header(FrameType::NOTIFY, false, false),
+            Bytes::copy_from_slice(chunks[0]),
+        )
+        .unwrap();
+    assert!(first.is_none());
+    let second = reassembler
+        .push(
+            header(FrameType::UNSET, false, false),
+            Bytes::copy_from_slice(chunks[1]),
+        )
+        .unwrap();
+    assert!(second.is_none());
+    let last = reassembler
+        .push(
+            header(FrameType::UNSET, true, false),
+            Bytes::copy_from_slice(chunks[2]),
+        )
+        .unwrap();
+
+    match last {
+        Some(Frame::Notify { header, messages }) => {
+            assert_eq!(header.stream_id, 2);
+            assert_eq!(header.frame_id, 7);
+            assert!(header.flags.is_fin());
+            assert_eq!(messages[0].0, "opentracing:frontend_tcp_request");
+        }
+        _ => panic!("Invalid frame reassembled: {:?}", last),
+    }
+}
+
+#[test]
+fn should_discard_aborted_fragmented_frame() {
+    let payload = from_hex_string(NOTIFY_PAYLOAD);
+    let mut reassembler = Reassembler::new(1024);
+
+    reassembler
+        .push(
+            header(FrameType::NOTIFY, false, false),
+            Bytes::copy_from_slice(&payload[..10]),
+        )
+        .unwrap();
+    let aborted = reassembler
+        .push(header(FrameType::UNSET, true, true), Bytes::new())
+        .unwrap();
+    assert!(aborted.is_none());
+
+    // the same ids can be reused afterwards
+    let restarted = reassembler.push(
+        header(FrameType::NOTIFY, false, false),
+        Bytes::copy_from_slice(&payload[..10]),
+    );
+    assert!(restarted.is_ok());
+}
+
+#[test]
+fn should_reject_fragments_exceeding_max_size() {
+    let payload = from_hex_string(NOTIFY_PAYLOAD);
+    let mut reassembler = Reassembler::new(64);
+
+    reassembler
+        .push(
+            header(FrameType::NOTIFY, false, false),
+            Bytes::copy_from_slice(&payload[..60]),
+        )
+        .unwrap();
+    let result = reassembler.push(
+        header(FrameType::UNSET, false, false),
+        Bytes::copy_from_slice(&payload[60..]),
+    );
+    assert!(matches!(
+        result,
+        Err(Error::InvalidFragment(FragmentError::TooBig(64)))
+    ));
+}
+
+#[test]
+fn should_reject_continuation_of_unknown_frame() {
+    let mut reassembler = Reassembler::new(64);
+    let result = reassembler.push(header(FrameType::UNSET, true, false), Bytes::new());
+    assert!(matches!(
+        result,
+        Err(Error::InvalidFragment(FragmentError::NotStarted(2, 7)))
+    ));
+}
+
+#[test]
+fn should_reject_reassembled_payload_ending_after_the_message_name() {
+    let payload = from_hex_string(NOTIFY_PAYLOAD);
+    let mut reassembler = Reassembler::new(1024);
+
+    reassembler
+        .push(
+            header(FrameType::NOTIFY, false, false),
+            Bytes::copy_from_slice(&payload[..10]),
+        )
+        .unwrap();
+    // the number of arguments of the message is missing
+    let result = reassembler.push(
+        header(FrameType::UNSET, true, false),
+        Bytes::copy_from_slice(&payload[10..33]),
+    );
+    match result {
+        Err(err @ Error::InvalidFrame(_)) => {
+            assert_eq!(err.status_code(), StatusCode::INVALID_FRAME)
+        }
+        _ => panic!("Invalid frame reassembled: {:?}", result),
+    }
+}
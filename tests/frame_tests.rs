@@ -1,8 +1,14 @@
-use bytes::BytesMut;
-use haproxy_spoa_rust::frame::{Error, Frame, KVList, TypedData};
+use bytes::{Bytes, BytesMut};
+use haproxy_spoa_rust::frame::{
+    parse_varint, write_varint, Action, ActionVarScope, Error, Frame, FrameError, FrameFlags,
+    FrameHeader, FrameHeaderError, FrameType, Headers, HeadersError, KVList, StatusCode,
+    TypedData, VarintError,
+};
+use std::convert::TryFrom;
 use std::io::Cursor;
 use std::fmt::Write;
 
+#[allow(clippy::len_zero)]
 fn to_hex_string(raw: &[u8]) -> String {
     let mut s = String::new();
     for x in raw {
@@ -20,6 +26,7 @@ fn from_hex_string(raw: &str) -> Vec<u8> {
         .collect()
 }
 
+#[allow(clippy::expect_fun_call)]
 fn assert_content_contains_string(content: &KVList, key: &str, value: &str) {
     match content.iter().find(|(k, _)| k == key).expect(format!("Key not found: '{}' in {:?}", key, content).as_str()) {
         (_, TypedData::STRING(s)) => assert_eq!(s, &value.to_string()),
@@ -27,6 +34,7 @@ fn assert_content_contains_string(content: &KVList, key: &str, value: &str) {
     };
 }
 
+#[allow(clippy::expect_fun_call)]
 fn assert_content_contains_uint32(content: &KVList, key: &str, value: u32) {
     match content.iter().find(|(k, _)| k == key).expect(format!("Key not found: '{}' in {:?}", key, content).as_str()) {
         (_, TypedData::UINT32(v)) => assert_eq!(v, &value),
@@ -35,11 +43,12 @@ fn assert_content_contains_uint32(content: &KVList, key: &str, value: u32) {
 }
 
 fn parse_frame(raw: &str) -> Result<Frame, Error> {
-    let raw_bytes = from_hex_string(raw);
-    let mut buff = Cursor::new(&raw_bytes[..]);
+    let raw_bytes = Bytes::from(from_hex_string(raw));
+    let mut buff = Cursor::new(&raw_bytes);
     Frame::parse(&mut buff)
 }
 
+#[allow(clippy::unnecessary_mut_passed)]
 fn write_frame(frame: &Frame) -> String {
     let mut full = BytesMut::new();
     Frame::write_to(frame, &mut full).unwrap();
@@ -48,7 +57,7 @@ fn write_frame(frame: &Frame) -> String {
 }
 
 
-#[allow(non_snake_case)]
+#[allow(non_snake_case, clippy::bool_assert_comparison)]
 #[test]
 fn should_parse_HAProxyHello_frame() {
     let result = parse_frame("0, 0, 0, 81, 1, 0, 0, 0, 1, 0, 0, 12, 73, 75, 70, 70, 6f, 72, 74, 65, 64, 2d, 76, 65, 72, 73, 69, 6f, 6e, 73, 8, 3, 32, 2e, 30, e, 6d, 61, 78, 2d, 66, 72, 61, 6d, 65, 2d, 73, 69, 7a, 65, 3, fc, f0, 6, c, 63, 61, 70, 61, 62, 69, 6c, 69, 74, 69, 65, 73, 8, 10, 70, 69, 70, 65, 6c, 69, 6e, 69, 6e, 67, 2c, 61, 73, 79, 6e, 63, 9, 65, 6e, 67, 69, 6e, 65, 2d, 69, 64, 8, 24, 61, 33, 31, 61, 64, 30, 65, 64, 2d, 62, 62, 36, 39, 2d, 34, 36, 63, 35, 2d, 39, 66, 35, 63, 2d, 62, 32, 30, 33, 62, 62, 35, 39, 61, 38, 37, 61");
@@ -72,7 +81,7 @@ fn should_parse_HAProxyHello_frame() {
     }
 }
 
-#[allow(non_snake_case)]
+#[allow(non_snake_case, clippy::bool_assert_comparison)]
 #[test]
 fn should_parse_AgentHello_frame() {
     let result = parse_frame("0, 0, 0, 46, 65, 0, 0, 0, 1, 0, 0, 7, 76, 65, 72, 73, 69, 6f, 6e, 8, 3, 32, 2e, 30, e, 6d, 61, 78, 2d, 66, 72, 61, 6d, 65, 2d, 73, 69, 7a, 65, 3, fc, f0, 6, c, 63, 61, 70, 61, 62, 69, 6c, 69, 74, 69, 65, 73, 8, 10, 70, 69, 70, 65, 6c, 69, 6e, 69, 6e, 67, 2c, 61, 73, 79, 6e, 63");
@@ -91,7 +100,11 @@ fn should_parse_AgentHello_frame() {
     }
 }
 
-#[allow(non_snake_case)]
+#[allow(
+    non_snake_case,
+    clippy::bool_assert_comparison,
+    clippy::needless_borrow
+)]
 #[test]
 fn should_parse_Notify_frame() {
     let result = parse_frame("0, 0, 0, 8b, 3, 0, 0, 0, 1, 2, 2, 20, 6f, 70, 65, 6e, 74, 72, 61, 63, 69, 6e, 67, 3a, 66, 72, 6f, 6e, 74, 65, 6e, 64, 5f, 74, 63, 70, 5f, 72, 65, 71, 75, 65, 73, 74, 3, 2, 69, 64, 8, 29, 36, 31, 62, 35, 37, 65, 66, 30, 2d, 32, 34, 62, 62, 2d, 34, 32, 63, 37, 2d, 38, 39, 33, 35, 2d, 61, 65, 64, 64, 32, 37, 36, 61, 66, 34, 61, 35, 3a, 30, 30, 30, 38, 4, 73, 70, 61, 6e, 8, 14, 46, 72, 6f, 6e, 74, 65, 6e, 64, 20, 54, 43, 50, 20, 72, 65, 71, 75, 65, 73, 74, 8, 63, 68, 69, 6c, 64, 2d, 6f, 66, 8, e, 43, 6c, 69, 65, 6e, 74, 20, 73, 65, 73, 73, 69, 6f, 6e");
@@ -102,7 +115,7 @@ fn should_parse_Notify_frame() {
             assert_eq!(header.stream_id, 2);
             assert_eq!(header.flags.is_fin(), true);
             assert_eq!(header.flags.is_abort(), false);
-            let msg = messages.get("opentracing:frontend_tcp_request").expect("<opentracing:frontend_tcp_request> message not found");
+            let (_, msg) = messages.iter().find(|(name, _)| name == "opentracing:frontend_tcp_request").expect("<opentracing:frontend_tcp_request> message not found");
             assert_content_contains_string(&msg, "id", "61b57ef0-24bb-42c7-8935-aedd276af4a5:0008");
             assert_content_contains_string(&msg, "span", "Frontend TCP request");
             assert_content_contains_string(&msg, "child-of", "Client session");
@@ -128,3 +141,211 @@ fn decode_encode_should_lead_to_the_same_result__Ack_frame() {
     let encoded = write_frame(&frame);
     assert_eq!(raw, encoded);
 }
+
+#[allow(non_snake_case)]
+#[test]
+fn should_parse_Notify_frame_without_fin_as_a_fragment() {
+    let raw = "0, 0, 0, e, 3, 0, 0, 0, 0, 2, 2, 20, 6f, 70, 65, 6e, 74, 72";
+    let result = parse_frame(raw);
+    match result {
+        Ok(Frame::Fragment { ref header, ref payload }) => {
+            assert_eq!(header.frame_id, 2);
+            assert_eq!(header.stream_id, 2);
+            assert!(!header.flags.is_fin());
+            assert_eq!(payload.len(), 7);
+        }
+        _ => panic!("Invalid frame parsed: {:?}", result),
+    }
+    assert_eq!(raw, write_frame(&result.unwrap()));
+}
+
+#[allow(non_snake_case)]
+#[test]
+fn should_encode_AgentDisconnect_frame_with_status_code_and_message() {
+    let frame = Frame::agent_disconnect(StatusCode::UNSUPPORTED_VERSION, "unsupported version");
+    let encoded = write_frame(&frame);
+    assert_eq!(encoded, "0, 0, 0, 32, 66, 0, 0, 0, 1, 0, 0, b, 73, 74, 61, 74, 75, 73, 2d, 63, 6f, 64, 65, 3, 8, 7, 6d, 65, 73, 73, 61, 67, 65, 8, 13, 75, 6e, 73, 75, 70, 70, 6f, 72, 74, 65, 64, 20, 76, 65, 72, 73, 69, 6f, 6e");
+
+    let decoded = parse_frame(&encoded).unwrap();
+    assert_eq!(
+        decoded.disconnect_status(),
+        Some((8, "unsupported version".to_string()))
+    );
+}
+
+#[allow(non_snake_case)]
+#[test]
+fn decode_encode_should_lead_to_the_same_result__Ack_frame_with_binary_SetVar() {
+    let frame = Frame::Ack {
+        header: FrameHeader {
+            r#type: FrameType::ACK,
+            flags: FrameFlags::new(true, false),
+            stream_id: 2,
+            frame_id: 1,
+        },
+        actions: vec![Action::SetVar {
+            scope: ActionVarScope::TRANSACTION,
+            name: "payload".to_string(),
+            value: TypedData::BINARY(Bytes::from_static(&[0x00, 0xff, 0x10, 0x0a])),
+        }],
+    };
+    let encoded = write_frame(&frame);
+    assert_eq!(encoded, "0, 0, 0, 18, 67, 0, 0, 0, 1, 2, 1, 1, 3, 2, 7, 70, 61, 79, 6c, 6f, 61, 64, 9, 4, 0, ff, 10, a");
+
+    match parse_frame(&encoded) {
+        Ok(Frame::Ack { actions, .. }) => match &actions[0] {
+            Action::SetVar { value, .. } => {
+                assert_eq!(value, &TypedData::BINARY(Bytes::from_static(&[0x00, 0xff, 0x10, 0x0a])))
+            }
+            action => panic!("Invalid action parsed: {:?}", action),
+        },
+        result => panic!("Invalid frame parsed: {:?}", result),
+    }
+}
+
+#[allow(non_snake_case)]
+#[test]
+fn should_parse_binary_in_Notify_frame_without_copying() {
+    // "msg" with a single "arg" of 2 bytes of binary
+    let raw = Bytes::from(from_hex_string(
+        "0, 0, 0, 14, 3, 0, 0, 0, 1, 1, 1, 3, 6d, 73, 67, 1, 3, 61, 72, 67, 9, 2, 1, 2",
+    ));
+    match Frame::parse(&mut Cursor::new(&raw)) {
+        Ok(Frame::Notify { messages, .. }) => match &messages[0].1[0] {
+            (_, TypedData::BINARY(value)) => {
+                assert_eq!(&value[..], &[1, 2]);
+                // the value points into the frame
+                assert_eq!(value.as_ptr(), raw[raw.len() - 2..].as_ptr());
+            }
+            arg => panic!("Invalid argument parsed: {:?}", arg),
+        },
+        result => panic!("Invalid frame parsed: {:?}", result),
+    }
+}
+
+#[allow(non_snake_case)]
+#[test]
+fn should_reject_truncated_binary_in_Notify_frame() {
+    // "msg" with a single "arg" claiming 8 bytes of binary, only 2 provided
+    let result = parse_frame("0, 0, 0, 14, 3, 0, 0, 0, 1, 1, 1, 3, 6d, 73, 67, 1, 3, 61, 72, 67, 9, 8, 1, 2");
+    assert!(matches!(result, Err(Error::InvalidFrame(FrameError::InvalidFramePayload(_)))));
+}
+
+#[allow(non_snake_case)]
+#[test]
+fn should_reject_truncated_frame_header() {
+    // shorter than the length prefix
+    let result = parse_frame("0, 0, 3");
+    assert!(matches!(result, Err(Error::InvalidFrame(FrameError::InsufficientBytes))));
+    // NOTIFY frame type followed by only 2 bytes of flags
+    let result = parse_frame("0, 0, 0, 3, 3, 0, 0");
+    match result {
+        Err(ref err @ Error::InvalidFrame(FrameError::InvalidFrameHeader(_))) => assert_eq!(err.status_code(), StatusCode::INVALID_FRAME),
+        _ => panic!("Invalid frame parsed: {:?}", result),
+    }
+}
+
+#[allow(non_snake_case)]
+#[test]
+fn should_reject_overflowing_varint_in_frame_header() {
+    // stream-id of 11 bytes
+    let result = parse_frame("0, 0, 0, 11, 3, 0, 0, 0, 1, f0, ff, ff, ff, ff, ff, ff, ff, ff, ff, ff, 0");
+    match result {
+        Err(ref err @ Error::InvalidFrame(FrameError::InvalidFrameHeader(FrameHeaderError::InvalidStreamId(VarintError::Overflow)))) => assert_eq!(err.status_code(), StatusCode::INVALID_FRAME),
+        _ => panic!("Invalid frame parsed: {:?}", result),
+    }
+    // 10 bytes, but beyond 64 bits
+    let result = parse_frame("0, 0, 0, 10, 3, 0, 0, 0, 1, f0, ff, ff, ff, ff, ff, ff, ff, ff, 7f, 0");
+    assert!(matches!(result, Err(Error::InvalidFrame(FrameError::InvalidFrameHeader(FrameHeaderError::InvalidStreamId(VarintError::Overflow))))));
+}
+
+#[test]
+fn should_encode_and_decode_the_largest_varint() {
+    for value in [0, 239, 240, 2287, 2288, u32::MAX as u64, u64::MAX] {
+        let mut raw = BytesMut::new();
+        write_varint(&mut raw, value).unwrap();
+        assert!(raw.len() <= 10);
+        assert_eq!(parse_varint(&mut Cursor::new(&raw[..])).unwrap(), value);
+    }
+}
+
+#[allow(non_snake_case)]
+#[test]
+fn should_reject_truncated_message_in_Notify_frame() {
+    // "msg" without its number of arguments
+    let result = parse_frame("0, 0, 0, b, 3, 0, 0, 0, 1, 1, 1, 3, 6d, 73, 67");
+    match result {
+        Err(ref err @ Error::InvalidFrame(FrameError::InvalidFramePayload(_))) => assert_eq!(err.status_code(), StatusCode::INVALID_FRAME),
+        _ => panic!("Invalid frame parsed: {:?}", result),
+    }
+    // "msg" with a single "arg" without value
+    let result = parse_frame("0, 0, 0, 10, 3, 0, 0, 0, 1, 1, 1, 3, 6d, 73, 67, 1, 3, 61, 72, 67");
+    assert!(matches!(result, Err(Error::InvalidFrame(FrameError::InvalidFramePayload(_)))));
+}
+
+#[allow(non_snake_case)]
+#[test]
+fn should_reject_truncated_action_in_Ack_frame() {
+    // SET-VAR action without its number of arguments
+    let result = parse_frame("0, 0, 0, 8, 67, 0, 0, 0, 1, 2, 1, 1");
+    assert!(matches!(result, Err(Error::InvalidFrame(FrameError::InvalidFramePayload(_)))));
+}
+
+fn hdrs_bin(headers: &[(&str, &str)]) -> TypedData {
+    let mut raw = vec![];
+    for (name, value) in headers {
+        raw.push(name.len() as u8);
+        raw.extend_from_slice(name.as_bytes());
+        raw.push(value.len() as u8);
+        raw.extend_from_slice(value.as_bytes());
+    }
+    // end marker: empty name and empty value
+    raw.extend_from_slice(&[0, 0]);
+    TypedData::BINARY(Bytes::from(raw))
+}
+
+#[test]
+fn should_decode_binary_headers_in_order() {
+    let value = hdrs_bin(&[
+        ("host", "localhost:7001"),
+        ("accept", "*/*"),
+        ("traceparent", "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"),
+        ("Accept", "text/html"),
+    ]);
+    let headers = Headers::try_from(&value).unwrap();
+
+    assert_eq!(headers.get_all("host").collect::<Vec<_>>(), vec!["localhost:7001"]);
+    assert_eq!(
+        headers.get_all("ACCEPT").collect::<Vec<_>>(),
+        vec!["*/*", "text/html"]
+    );
+    assert_eq!(
+        headers.get_all("traceparent").next(),
+        Some("00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01")
+    );
+    assert_eq!(headers.get_all("tracestate").next(), None);
+}
+
+#[test]
+fn should_decode_empty_binary_headers() {
+    let headers = Headers::try_from(&hdrs_bin(&[])).unwrap();
+    assert_eq!(headers, Headers::default());
+}
+
+#[test]
+fn should_reject_binary_headers_without_end_marker() {
+    let value = TypedData::BINARY(Bytes::from_static(b"\x04host\x09localhost"));
+    assert!(matches!(
+        Headers::try_from(&value),
+        Err(HeadersError::InsufficientBytes)
+    ));
+    assert!(matches!(
+        Headers::try_from(&TypedData::STRING("host".to_string())),
+        Err(HeadersError::NotBinary)
+    ));
+    let value = TypedData::BINARY(Bytes::from_static(b"\xf0\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\x00"));
+    assert!(matches!(
+        Headers::try_from(&value),
+        Err(HeadersError::InvalidSize(VarintError::Overflow))
+    ));
+}
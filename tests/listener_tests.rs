@@ -0,0 +1,63 @@
+use haproxy_spoa_rust::listener::{ListenAddr, Listener, UnixSocketSettings};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::PathBuf;
+
+fn socket_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("spoa-{}-{}.sock", name, std::process::id()))
+}
+
+#[test]
+fn should_parse_tcp_and_unix_addresses() {
+    assert_eq!(
+        "127.0.0.1:7000".parse(),
+        Ok(ListenAddr::Tcp("127.0.0.1:7000".parse().unwrap()))
+    );
+    assert_eq!(
+        "unix@/run/spoa.sock".parse(),
+        Ok(ListenAddr::Unix(PathBuf::from("/run/spoa.sock")))
+    );
+    assert_eq!(
+        "unix@/run/spoa.sock"
+            .parse::<ListenAddr>()
+            .unwrap()
+            .to_string(),
+        "unix@/run/spoa.sock"
+    );
+    assert!("unix@".parse::<ListenAddr>().is_err());
+    assert!("localhost:7000".parse::<ListenAddr>().is_err());
+}
+
+#[tokio::test]
+async fn should_apply_the_mode_of_unix_sockets() {
+    let path = socket_path("mode");
+    let settings = UnixSocketSettings {
+        mode: Some(0o600),
+        ..Default::default()
+    };
+    let listener = Listener::bind(&ListenAddr::Unix(path.clone()), &settings)
+        .await
+        .unwrap();
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    drop(listener);
+    assert!(!path.exists());
+}
+
+#[tokio::test]
+async fn should_replace_a_stale_unix_socket_only() {
+    let path = socket_path("stale");
+    let addr = ListenAddr::Unix(path.clone());
+    let settings = UnixSocketSettings::default();
+
+    // left over by an agent which did not remove it
+    drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+    let listener = Listener::bind(&addr, &settings).await.unwrap();
+
+    let err = Listener::bind(&addr, &settings).await.unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::AddrInUse);
+
+    drop(listener);
+    assert!(!path.exists());
+}
@@ -0,0 +1,37 @@
+use haproxy_spoa_rust::logging::{init, LogFormat, LogSettings};
+use tracing::level_filters::LevelFilter;
+
+#[test]
+fn should_parse_log_format() {
+    assert_eq!("text".parse(), Ok(LogFormat::Text));
+    assert_eq!("json".parse(), Ok(LogFormat::Json));
+    assert!("logfmt".parse::<LogFormat>().is_err());
+}
+
+#[test]
+fn should_change_the_level_at_runtime() {
+    assert!(init(&LogSettings {
+        filter: "info,,=".to_string(),
+        ..LogSettings::default()
+    })
+    .is_err());
+
+    let handle = init(&LogSettings {
+        filter: "warn".to_string(),
+        format: LogFormat::Json,
+    })
+    .unwrap();
+    assert_eq!(handle.level(), LevelFilter::WARN);
+    assert!(!tracing::enabled!(tracing::Level::INFO));
+
+    assert_eq!(handle.raise(), Ok(LevelFilter::INFO));
+    assert!(tracing::enabled!(tracing::Level::INFO));
+    assert_eq!(handle.raise(), Ok(LevelFilter::DEBUG));
+    assert_eq!(handle.raise(), Ok(LevelFilter::TRACE));
+    assert_eq!(handle.raise(), Ok(LevelFilter::TRACE));
+
+    handle.set_level(LevelFilter::ERROR).unwrap();
+    assert_eq!(handle.lower(), Ok(LevelFilter::OFF));
+    assert_eq!(handle.lower(), Ok(LevelFilter::OFF));
+    assert!(!tracing::enabled!(tracing::Level::ERROR));
+}
@@ -0,0 +1,187 @@
+use async_trait::async_trait;
+use haproxy_spoa_rust::connection::{Connection, ConnectionSettings};
+use haproxy_spoa_rust::frame::{
+    Action, ActionVarScope, Error, Frame, FrameFlags, FrameHeader, FrameType, KVList,
+    ListOfMessages, TypedData,
+};
+use haproxy_spoa_rust::handler::SpoaHandler;
+use haproxy_spoa_rust::metrics::{serve, Metrics, MESSAGE_LABEL_LIMIT};
+use haproxy_spoa_rust::negotiation::Negotiated;
+use haproxy_spoa_rust::server::Server;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+/// Answers every message with a SET-VAR action, and rejects the `fail`
+/// message.
+struct Echo;
+
+#[async_trait]
+impl SpoaHandler for Echo {
+    async fn notify(
+        &self,
+        _negotiated: &Negotiated,
+        _header: &FrameHeader,
+        messages: &ListOfMessages,
+    ) -> Result<Vec<Action>, Error> {
+        if messages.iter().any(|(name, _)| name == "fail") {
+            return Err(Error::UnknownMessage("fail".to_string()));
+        }
+        Ok(messages
+            .iter()
+            .map(|(name, _)| Action::SetVar {
+                scope: ActionVarScope::TRANSACTION,
+                name: name.to_owned(),
+                value: TypedData::BOOL(true),
+            })
+            .collect())
+    }
+}
+
+fn header(r#type: FrameType, frame_id: u64) -> FrameHeader {
+    FrameHeader {
+        r#type,
+        flags: FrameFlags::new(true, false),
+        stream_id: 1,
+        frame_id,
+    }
+}
+
+fn hello() -> Frame {
+    let content: KVList = vec![
+        (
+            "supported-versions".to_string(),
+            TypedData::STRING("2.0".to_string()),
+        ),
+        ("max-frame-size".to_string(), TypedData::UINT32(16380)),
+        (
+            "capabilities".to_string(),
+            TypedData::STRING("pipelining".to_string()),
+        ),
+    ];
+    Frame::HAProxyHello {
+        header: header(FrameType::HAPROXY_HELLO, 0),
+        content,
+    }
+}
+
+fn notify(frame_id: u64, message: &str) -> Frame {
+    Frame::Notify {
+        header: header(FrameType::NOTIFY, frame_id),
+        messages: vec![(message.to_string(), vec![])],
+    }
+}
+
+/// Body of the response to `GET path`.
+async fn get(addr: std::net::SocketAddr, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let request = format!(
+        "GET {} HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n",
+        path
+    );
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn should_serve_metrics_of_the_connections() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    let running = tokio::spawn(
+        Server::new(ConnectionSettings::default(), Echo).run(vec![listener.into()], shutdown_rx),
+    );
+
+    let metrics_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let metrics_addr = metrics_listener.local_addr().unwrap();
+    tokio::spawn(serve(metrics_listener, std::future::pending()));
+
+    // a healthcheck is answered, and closed, without being counted
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let mut healthcheck = hello();
+    if let Frame::HAProxyHello { content, .. } = &mut healthcheck {
+        content.push(("healthcheck".to_string(), TypedData::BOOL(true)));
+    }
+    client.write_frame(&healthcheck).await.unwrap();
+    client.read_frame().await.unwrap().unwrap();
+    assert!(client.read_frame().await.unwrap().is_none());
+    let response = get(metrics_addr, "/metrics").await;
+    assert!(response.contains("spoa_connections 0"), "{}", response);
+    assert!(
+        response.contains("spoa_connections_total 0"),
+        "{}",
+        response
+    );
+    assert!(!response.contains("spoa_frames_total"), "{}", response);
+
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+    client.write_frame(&hello()).await.unwrap();
+    client.read_frame().await.unwrap().unwrap();
+    client
+        .write_frame(&notify(1, "check-client"))
+        .await
+        .unwrap();
+    client.read_frame().await.unwrap().unwrap();
+    client.write_frame(&notify(2, "fail")).await.unwrap();
+    // acknowledged with the error
+    client.read_frame().await.unwrap().unwrap();
+    client
+        .write_frame(&notify(3, "check-client"))
+        .await
+        .unwrap();
+    client.read_frame().await.unwrap().unwrap();
+
+    // the frames are counted once written, the connection is closed first
+    drop(shutdown_tx);
+    running.await.unwrap().unwrap();
+
+    let response = get(metrics_addr, "/metrics").await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"));
+    for line in &[
+        "spoa_connections 0",
+        "spoa_connections_total 1",
+        "spoa_frames_total{direction=\"received\",type=\"HAPROXY_HELLO\"} 1",
+        "spoa_frames_total{direction=\"received\",type=\"NOTIFY\"} 3",
+        "spoa_frames_total{direction=\"sent\",type=\"AGENT_HELLO\"} 1",
+        "spoa_frames_total{direction=\"sent\",type=\"ACK\"} 3",
+        "spoa_frames_total{direction=\"sent\",type=\"AGENT_DISCONNECT\"} 1",
+        "spoa_messages_total{message=\"check-client\"} 2",
+        "spoa_messages_total{message=\"fail\"} 1",
+        "spoa_actions_total{type=\"SET_VAR\"} 3",
+        "spoa_errors_total{kind=\"unknown_message\"} 1",
+        "spoa_notify_duration_seconds_count 3",
+    ] {
+        assert!(
+            response.contains(line),
+            "{} not found in {}",
+            line,
+            response
+        );
+    }
+
+    let response = get(metrics_addr, "/").await;
+    assert!(response.starts_with("HTTP/1.1 404 Not Found"));
+}
+
+#[test]
+fn should_count_the_messages_beyond_the_limit_as_other() {
+    let metrics = Metrics::new();
+    for i in 0..MESSAGE_LABEL_LIMIT + 20 {
+        metrics.message_received(&format!("message-{}", i));
+    }
+    metrics.message_received("message-0");
+
+    let response = metrics.encode();
+    assert!(
+        response.contains("spoa_messages_total{message=\"message-0\"} 2"),
+        "{}",
+        response
+    );
+    assert!(
+        response.contains("spoa_messages_total{message=\"other\"} 20"),
+        "{}",
+        response
+    );
+}
@@ -0,0 +1,114 @@
+use haproxy_spoa_rust::connection::ConnectionSettings;
+use haproxy_spoa_rust::frame::{Error, KVList, StatusCode, TypedData};
+use haproxy_spoa_rust::negotiation::{negotiate, NegotiationError};
+
+fn hello(versions: &str, max_frame_size: u32, capabilities: &str) -> KVList {
+    vec![
+        (
+            "supported-versions".to_string(),
+            TypedData::STRING(versions.to_string()),
+        ),
+        (
+            "max-frame-size".to_string(),
+            TypedData::UINT32(max_frame_size),
+        ),
+        (
+            "capabilities".to_string(),
+            TypedData::STRING(capabilities.to_string()),
+        ),
+        (
+            "engine-id".to_string(),
+            TypedData::STRING("a31ad0ed-bb69-46c5-9f5c-b203bb59a87a".to_string()),
+        ),
+    ]
+}
+
+#[test]
+fn should_negotiate_parameters_supported_by_both_peers() {
+    let settings = ConnectionSettings {
+        max_frame_size: 16380,
+        fragmentation: false,
+        ..ConnectionSettings::default()
+    };
+    let negotiated = negotiate(
+        &settings,
+        &hello("1.0, 2.0", 8192, "pipelining,async,fragmentation"),
+    )
+    .unwrap();
+
+    assert_eq!(negotiated.version, "2.0");
+    assert_eq!(negotiated.max_frame_size, 8192);
+    assert_eq!(negotiated.capabilities, vec!["pipelining", "async"]);
+    assert_eq!(
+        negotiated.engine_id.as_deref(),
+        Some("a31ad0ed-bb69-46c5-9f5c-b203bb59a87a")
+    );
+}
+
+#[test]
+fn should_use_local_max_frame_size_when_smaller() {
+    let settings = ConnectionSettings {
+        max_frame_size: 4096,
+        ..ConnectionSettings::default()
+    };
+    let negotiated = negotiate(&settings, &hello("2.0", 16380, "")).unwrap();
+
+    assert_eq!(negotiated.max_frame_size, 4096);
+    assert!(negotiated.capabilities.is_empty());
+}
+
+#[test]
+fn should_reject_unsupported_version() {
+    let result = negotiate(
+        &ConnectionSettings::default(),
+        &hello("1.0,3.1", 16380, "pipelining"),
+    );
+    match result {
+        Err(err @ NegotiationError::UnsupportedVersion(_)) => {
+            assert_eq!(err.status_code(), StatusCode::UNSUPPORTED_VERSION)
+        }
+        _ => panic!("Unexpected negotiation: {:?}", result),
+    }
+}
+
+#[test]
+fn should_reject_too_small_max_frame_size() {
+    let result = negotiate(
+        &ConnectionSettings::default(),
+        &hello("2.0", 128, "pipelining"),
+    );
+    match result {
+        Err(err @ NegotiationError::InvalidMaxFrameSize(128)) => {
+            assert_eq!(err.status_code(), StatusCode::INVALID_MAX_FRAME_SIZE)
+        }
+        _ => panic!("Unexpected negotiation: {:?}", result),
+    }
+}
+
+#[test]
+fn should_reject_hello_without_capabilities() {
+    let mut content = hello("2.0", 16380, "");
+    content.retain(|(k, _)| k != "capabilities");
+    let result = negotiate(&ConnectionSettings::default(), &content);
+    assert!(matches!(
+        result,
+        Err(NegotiationError::CapabilitiesNotFound)
+    ));
+}
+
+#[test]
+fn should_report_negotiation_error_status_code() {
+    let err = Error::Negotiation(NegotiationError::VersionNotFound);
+    assert_eq!(err.status_code(), StatusCode::VERSION_NOT_FOUND);
+    assert_eq!(u32::from(err.status_code()), 5);
+}
+
+#[test]
+fn should_detect_healthcheck_hello() {
+    let settings = ConnectionSettings::default();
+    let mut content = hello("2.0", 16380, "pipelining");
+    assert!(!negotiate(&settings, &content).unwrap().healthcheck);
+
+    content.push(("healthcheck".to_string(), TypedData::BOOL(true)));
+    assert!(negotiate(&settings, &content).unwrap().healthcheck);
+}
@@ -0,0 +1,817 @@
+use bytes::Bytes;
+use haproxy_spoa_rust::frame::{
+    Action, FrameFlags, FrameHeader, FrameType, KVList, ListOfMessages, TypedData,
+};
+use haproxy_spoa_rust::handler::SpoaHandler;
+use haproxy_spoa_rust::negotiation::Negotiated;
+use haproxy_spoa_rust::otel::{
+    drain, handle_message, sweep, IdFallback, OtelContext, OtelHandler, OtelSettings,
+};
+use haproxy_spoa_rust::propagation::{text_map_propagator, Propagator};
+use haproxy_spoa_rust::red::RequestMetrics;
+use haproxy_spoa_rust::router::Router;
+use haproxy_spoa_rust::store::{Eviction, SpanStore};
+use opentelemetry::sdk::export::trace::SpanData;
+use opentelemetry::sdk::trace::{SpanProcessor, TracerProvider};
+use opentelemetry::trace::{SpanId, StatusCode, TraceId, TraceResult};
+use opentelemetry::{global, sdk, Context, Key, KeyValue, Value};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+#[test]
+fn should_use_hex_representation_of_binary_as_attribute() {
+    let attr = TypedData::BINARY(Bytes::from_static(&[0x00, 0xff, 0x10])).as_value(Key::new("bin"));
+    assert_eq!(attr.value, Value::from("00ff10"));
+}
+
+#[test]
+fn should_truncate_large_binary_attribute() {
+    let attr = TypedData::BINARY(Bytes::from(vec![0xab_u8; 1000])).as_value(Key::new("bin"));
+    let expected = format!("{}... (1000 bytes)", "ab".repeat(256));
+    assert_eq!(attr.value, Value::from(expected));
+}
+
+/// Keeps the spans ended by every test, they are told apart by their name.
+#[derive(Debug)]
+struct Recorder;
+
+fn recorded() -> &'static Mutex<Vec<SpanData>> {
+    static RECORDED: OnceLock<Mutex<Vec<SpanData>>> = OnceLock::new();
+    RECORDED.get_or_init(|| {
+        let provider = TracerProvider::builder()
+            .with_span_processor(Recorder)
+            .build();
+        global::set_tracer_provider(provider);
+        global::set_text_map_propagator(text_map_propagator(&[
+            Propagator::TraceContext,
+            Propagator::Baggage,
+        ]));
+        Mutex::new(vec![])
+    })
+}
+
+impl SpanProcessor for Recorder {
+    fn on_start(&self, _span: &mut sdk::trace::Span, _cx: &Context) {}
+
+    fn on_end(&self, span: SpanData) {
+        recorded().lock().unwrap().push(span);
+    }
+
+    fn force_flush(&self) -> TraceResult<()> {
+        Ok(())
+    }
+
+    fn shutdown(&mut self) -> TraceResult<()> {
+        Ok(())
+    }
+}
+
+fn span(name: &str) -> SpanData {
+    recorded()
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|span| span.name == name)
+        .cloned()
+        .unwrap_or_else(|| panic!("span {} not ended", name))
+}
+
+fn notify(ctx: &OtelContext, id: &str, args: &[(&str, TypedData)]) -> Vec<Action> {
+    notify_with(ctx, &OtelSettings::default(), id, args)
+}
+
+fn notify_with(
+    ctx: &OtelContext,
+    settings: &OtelSettings,
+    id: &str,
+    args: &[(&str, TypedData)],
+) -> Vec<Action> {
+    let header = FrameHeader {
+        r#type: FrameType::NOTIFY,
+        flags: FrameFlags::new(true, false),
+        stream_id: 1,
+        frame_id: 1,
+    };
+    let mut details: KVList = vec![("id".to_string(), TypedData::STRING(id.to_string()))];
+    for (k, v) in args {
+        details.push((k.to_string(), v.clone()));
+    }
+    handle_message(
+        ctx,
+        settings,
+        red(),
+        &negotiated(None),
+        &header,
+        "opentracing:test",
+        &details,
+    )
+    .unwrap()
+}
+
+/// RED metrics of every test, recorded by the global meter, which records
+/// nothing.
+fn red() -> &'static RequestMetrics {
+    static RED: OnceLock<RequestMetrics> = OnceLock::new();
+    RED.get_or_init(RequestMetrics::default)
+}
+
+fn negotiated(engine_id: Option<&str>) -> Negotiated {
+    Negotiated {
+        version: "2.0".to_string(),
+        max_frame_size: 16380,
+        capabilities: vec![],
+        engine_id: engine_id.map(String::from),
+        healthcheck: false,
+    }
+}
+
+/// Send `args` as is in the stream `stream_id`, with a new frame id, on a
+/// connection of the engine `engine_id`.
+fn notify_args(
+    ctx: &OtelContext,
+    settings: &OtelSettings,
+    engine_id: Option<&str>,
+    stream_id: u64,
+    frame_id: u64,
+    args: &[(&str, TypedData)],
+) -> Vec<Action> {
+    let header = FrameHeader {
+        r#type: FrameType::NOTIFY,
+        flags: FrameFlags::new(true, false),
+        stream_id,
+        frame_id,
+    };
+    let details: KVList = args
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.clone()))
+        .collect();
+    handle_message(
+        ctx,
+        settings,
+        red(),
+        &negotiated(engine_id),
+        &header,
+        "opentracing:test",
+        &details,
+    )
+    .unwrap()
+}
+
+fn string(s: &str) -> TypedData {
+    TypedData::STRING(s.to_string())
+}
+
+#[test]
+fn should_parent_child_of_spans_and_finish_them_all() {
+    recorded();
+    let ctx = OtelContext::default();
+
+    let actions = notify(
+        &ctx,
+        "req-1",
+        &[
+            ("span", string("t1 session")),
+            ("span", string("t1 client")),
+            ("child-of", string("t1 session")),
+        ],
+    );
+    // the context of the last span started is propagated
+    assert_eq!(actions.len(), 2);
+    notify(&ctx, "req-1", &[("finish", string("*"))]);
+
+    let session = span("t1 session");
+    let client = span("t1 client");
+    assert_eq!(session.parent_span_id, SpanId::INVALID);
+    assert_eq!(client.parent_span_id, session.span_context.span_id());
+    assert_eq!(
+        client.span_context.trace_id(),
+        session.span_context.trace_id()
+    );
+    assert!(ctx.is_empty());
+}
+
+#[test]
+fn should_link_follows_from_spans() {
+    recorded();
+    let ctx = OtelContext::default();
+
+    notify(&ctx, "req-2", &[("span", string("t2 tcp"))]);
+    notify(
+        &ctx,
+        "req-2",
+        &[
+            ("span", string("t2 http")),
+            ("follows-from", string("t2 tcp")),
+            ("finish", string("t2 tcp")),
+        ],
+    );
+    notify(&ctx, "req-2", &[("finish", string("t2 http"))]);
+
+    let tcp = span("t2 tcp");
+    let http = span("t2 http");
+    assert_eq!(http.parent_span_id, tcp.span_context.span_id());
+    let link = http.links.iter().next().unwrap();
+    assert_eq!(link.span_context(), &tcp.span_context);
+    assert_eq!(
+        link.attributes(),
+        &vec![KeyValue::new("opentracing.ref_type", "follows_from")]
+    );
+}
+
+#[test]
+fn should_tag_new_and_started_spans() {
+    recorded();
+    let ctx = OtelContext::default();
+
+    notify(
+        &ctx,
+        "req-3",
+        &[
+            ("span", string("t3 response")),
+            ("tag", string("http.version")),
+            ("", string("HTTP/")),
+            ("", string("1.1")),
+            ("tag", string("http.status_code")),
+            ("", TypedData::UINT32(500)),
+        ],
+    );
+    notify(
+        &ctx,
+        "req-3",
+        &[
+            ("span", string("t3 response")),
+            ("tag", string("error")),
+            ("", TypedData::BOOL(true)),
+            ("finish", string("t3 response")),
+        ],
+    );
+
+    let response = span("t3 response");
+    assert_eq!(
+        response.attributes.get(&Key::new("http.version")),
+        Some(&Value::from("HTTP/1.1"))
+    );
+    assert_eq!(
+        response.attributes.get(&Key::new("http.status_code")),
+        Some(&Value::I64(500))
+    );
+    assert_eq!(
+        response.attributes.get(&Key::new("error")),
+        Some(&Value::Bool(true))
+    );
+}
+
+const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+fn assert_continues_traceparent(span: &SpanData) {
+    assert_eq!(
+        span.span_context.trace_id(),
+        TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap()
+    );
+    assert_eq!(
+        span.parent_span_id,
+        SpanId::from_hex("00f067aa0ba902b7").unwrap()
+    );
+}
+
+#[test]
+fn should_continue_trace_given_as_argument() {
+    recorded();
+    let ctx = OtelContext::default();
+
+    notify(
+        &ctx,
+        "req-4",
+        &[
+            ("traceparent", string(TRACEPARENT)),
+            ("span", string("t4 session")),
+            ("span", string("t4 client")),
+            ("child-of", string("t4 session")),
+            ("finish", string("*")),
+        ],
+    );
+
+    let session = span("t4 session");
+    assert_continues_traceparent(&session);
+    assert!(session.attributes.get(&Key::new("traceparent")).is_none());
+    assert_eq!(
+        span("t4 client").parent_span_id,
+        session.span_context.span_id()
+    );
+}
+
+#[test]
+fn should_continue_trace_given_in_request_headers() {
+    recorded();
+    let ctx = OtelContext::default();
+
+    let mut hdrs_bin = vec![];
+    for part in &["Host", "example.com", "traceparent", TRACEPARENT, "", ""] {
+        hdrs_bin.push(part.len() as u8);
+        hdrs_bin.extend_from_slice(part.as_bytes());
+    }
+    notify(
+        &ctx,
+        "req-5",
+        &[
+            ("span", string("t5 request")),
+            ("headers", TypedData::BINARY(Bytes::from(hdrs_bin))),
+            ("finish", string("t5 request")),
+        ],
+    );
+
+    assert_continues_traceparent(&span("t5 request"));
+}
+
+fn var(actions: &[Action], var_name: &str) -> Option<String> {
+    actions.iter().find_map(|action| match action {
+        Action::SetVar {
+            name,
+            value: TypedData::STRING(value),
+            ..
+        } if name == var_name => Some(value.to_owned()),
+        _ => None,
+    })
+}
+
+#[test]
+fn should_propagate_baggage_arguments() {
+    recorded();
+    let ctx = OtelContext::default();
+
+    let actions = notify(
+        &ctx,
+        "req-6",
+        &[
+            ("baggage", string("haproxy_id")),
+            ("", string("fe1:")),
+            ("", TypedData::UINT32(42)),
+            ("span", string("t6 request")),
+        ],
+    );
+    assert_eq!(
+        var(&actions, "baggage"),
+        Some("haproxy_id=fe1:42".to_string())
+    );
+
+    // the baggage of the request is propagated by every later span
+    let actions = notify(
+        &ctx,
+        "req-6",
+        &[
+            ("span", string("t6 response")),
+            ("child-of", string("t6 request")),
+            ("finish", string("*")),
+        ],
+    );
+    assert_eq!(
+        var(&actions, "baggage"),
+        Some("haproxy_id=fe1:42".to_string())
+    );
+    assert!(span("t6 request")
+        .attributes
+        .get(&Key::new("haproxy_id"))
+        .is_none());
+}
+
+#[test]
+fn should_merge_incoming_baggage_and_record_it_as_attributes() {
+    recorded();
+    let ctx = OtelContext::default();
+    let settings = OtelSettings {
+        baggage_attributes: true,
+        ..OtelSettings::default()
+    };
+
+    let mut hdrs_bin = vec![];
+    for part in &["baggage", "userId=alice,region=eu", "", ""] {
+        hdrs_bin.push(part.len() as u8);
+        hdrs_bin.extend_from_slice(part.as_bytes());
+    }
+    let actions = notify_with(
+        &ctx,
+        &settings,
+        "req-7",
+        &[
+            ("headers", TypedData::BINARY(Bytes::from(hdrs_bin))),
+            ("baggage", string("region")),
+            ("", string("us")),
+            ("span", string("t7 request")),
+            ("finish", string("t7 request")),
+        ],
+    );
+    // the order of the items is not specified
+    let mut items: Vec<String> = var(&actions, "baggage")
+        .unwrap()
+        .split(',')
+        .map(String::from)
+        .collect();
+    items.sort();
+    assert_eq!(items, vec!["region=us", "userId=alice"]);
+
+    let request = span("t7 request");
+    assert_eq!(
+        request.attributes.get(&Key::new("userId")),
+        Some(&Value::from("alice"))
+    );
+    assert_eq!(
+        request.attributes.get(&Key::new("region")),
+        Some(&Value::from("us"))
+    );
+}
+
+#[test]
+fn should_end_spans_of_expired_requests_with_an_error() {
+    recorded();
+    let ctx = OtelContext::default();
+
+    notify(&ctx, "req-8", &[("span", string("t8 session"))]);
+    notify(&ctx, "req-8", &[("span", string("t8 request"))]);
+    assert_eq!(sweep(&ctx, red(), Instant::now()), 0);
+
+    let ttl = ctx.settings().ttl;
+    assert_eq!(
+        sweep(&ctx, red(), Instant::now() + ttl + Duration::from_secs(1)),
+        1
+    );
+    assert!(ctx.is_empty());
+
+    for name in &["t8 session", "t8 request"] {
+        let span = span(name);
+        assert_eq!(span.status_code, StatusCode::Error);
+        assert_eq!(span.status_message, "timeout");
+        assert_eq!(
+            span.attributes.get(&Key::new("haproxy.last_event")),
+            Some(&Value::from("opentracing:test"))
+        );
+    }
+}
+
+#[test]
+fn should_end_spans_in_progress_on_shutdown() {
+    recorded();
+    let ctx = OtelContext::default();
+
+    notify(&ctx, "req-14", &[("span", string("t14 request"))]);
+    notify(&ctx, "req-15", &[("span", string("t15 request"))]);
+    assert_eq!(drain(&ctx, red(), Instant::now()), 2);
+    assert!(ctx.is_empty());
+
+    for name in &["t14 request", "t15 request"] {
+        let span = span(name);
+        assert_eq!(span.status_code, StatusCode::Error);
+        assert_eq!(span.status_message, "shutdown");
+    }
+}
+
+#[test]
+fn should_not_trace_messages_without_id() {
+    recorded();
+    let ctx = OtelContext::default();
+    let settings = OtelSettings::default();
+
+    let actions = notify_args(
+        &ctx,
+        &settings,
+        None,
+        9,
+        1,
+        &[("span", string("t9 missing"))],
+    );
+    assert_eq!(
+        var(&actions, "otel_error"),
+        Some("missing id argument".to_string())
+    );
+    let actions = notify_args(
+        &ctx,
+        &settings,
+        None,
+        9,
+        2,
+        &[("id", TypedData::NULL), ("span", string("t9 null"))],
+    );
+    assert_eq!(
+        var(&actions, "otel_error"),
+        Some("empty id argument".to_string())
+    );
+    assert!(ctx.is_empty());
+}
+
+#[test]
+fn should_not_trace_new_requests_once_the_store_is_full() {
+    recorded();
+    let mut settings = OtelSettings::default();
+    settings.store.capacity = 1;
+    settings.store.shards = 1;
+    settings.store.eviction = Eviction::Reject;
+    let ctx = OtelContext::new(SpanStore::new(settings.store.clone()));
+
+    notify_with(&ctx, &settings, "req-18a", &[("span", string("t18 kept"))]);
+    let actions = notify_with(
+        &ctx,
+        &settings,
+        "req-18b",
+        &[("span", string("t18 rejected"))],
+    );
+    assert_eq!(
+        var(&actions, "otel_error"),
+        Some("span store is full".to_string())
+    );
+    assert_eq!(ctx.len(), 1);
+}
+
+#[test]
+fn should_identify_requests_by_ids_of_any_type() {
+    recorded();
+    let ctx = OtelContext::default();
+    let settings = OtelSettings {
+        id_arg: Some("txn".to_string()),
+        ..OtelSettings::default()
+    };
+
+    let actions = notify_args(
+        &ctx,
+        &settings,
+        None,
+        10,
+        1,
+        &[
+            ("txn", TypedData::UINT64(42)),
+            ("span", string("t10 request")),
+        ],
+    );
+    assert!(var(&actions, "otel_error").is_none());
+    notify_args(
+        &ctx,
+        &settings,
+        None,
+        10,
+        2,
+        &[
+            ("txn", TypedData::UINT64(42)),
+            ("finish", string("t10 request")),
+        ],
+    );
+    assert!(ctx.is_empty());
+    // the id argument is not an attribute
+    assert!(span("t10 request")
+        .attributes
+        .get(&Key::new("txn"))
+        .is_none());
+}
+
+#[test]
+fn should_fall_back_to_the_stream_without_id() {
+    recorded();
+    let ctx = OtelContext::default();
+    let settings = OtelSettings {
+        id_fallback: IdFallback::Stream,
+        ..OtelSettings::default()
+    };
+
+    notify_args(
+        &ctx,
+        &settings,
+        None,
+        11,
+        1,
+        &[("span", string("t11 request"))],
+    );
+    assert_eq!(ctx.len(), 1);
+    notify_args(
+        &ctx,
+        &settings,
+        None,
+        11,
+        2,
+        &[("finish", string("t11 request"))],
+    );
+    assert!(ctx.is_empty());
+    span("t11 request");
+
+    assert_eq!("stream".parse(), Ok(IdFallback::Stream));
+    assert!("frame".parse::<IdFallback>().is_err());
+}
+
+#[test]
+fn should_correlate_messages_of_different_streams_by_id() {
+    recorded();
+    let ctx = OtelContext::default();
+    let settings = OtelSettings::default();
+
+    // HAProxy sent the messages over different connections
+    notify_args(
+        &ctx,
+        &settings,
+        Some("engine-a"),
+        12,
+        1,
+        &[("id", string("req-12")), ("span", string("t12 request"))],
+    );
+    notify_args(
+        &ctx,
+        &settings,
+        Some("engine-a"),
+        7,
+        1,
+        &[("id", string("req-12")), ("finish", string("t12 request"))],
+    );
+    assert!(ctx.is_empty());
+    span("t12 request");
+}
+
+#[test]
+fn should_scope_ids_by_engine() {
+    recorded();
+    let ctx = OtelContext::default();
+    let settings = OtelSettings {
+        engine_scoped: true,
+        ..OtelSettings::default()
+    };
+
+    for engine_id in &["engine-a", "engine-b"] {
+        notify_args(
+            &ctx,
+            &settings,
+            Some(engine_id),
+            13,
+            1,
+            &[("id", string("req-13")), ("span", string("t13 request"))],
+        );
+    }
+    assert_eq!(ctx.len(), 2);
+    for engine_id in &["engine-a", "engine-b"] {
+        notify_args(
+            &ctx,
+            &settings,
+            Some(engine_id),
+            13,
+            2,
+            &[("id", string("req-13")), ("finish", string("t13 request"))],
+        );
+    }
+    assert!(ctx.is_empty());
+}
+
+/// Message `name` of the devenv SPOE configuration, for the request `id`.
+fn devenv_message(name: &str, id: &str, args: &[(&str, TypedData)]) -> (String, KVList) {
+    let mut details: KVList = vec![("id".to_string(), string(id))];
+    for (k, v) in args {
+        details.push((k.to_string(), v.clone()));
+    }
+    (format!("opentracing:{}", name), details)
+}
+
+#[tokio::test]
+async fn should_finish_the_trace_of_the_devenv_http_response_event() {
+    recorded();
+    let handler = OtelHandler::new();
+    let router = Router::new().route("opentracing:*", handler.clone());
+    let id = "devenv-1";
+    // every event before on-http-response, in the order HAProxy sends them
+    let events: Vec<ListOfMessages> = vec![
+        vec![devenv_message(
+            "client_session_start",
+            id,
+            &[
+                ("span", string("HAProxy session")),
+                ("baggage", string("haproxy_id")),
+                ("", string(id)),
+                ("span", string("Client session")),
+                ("child-of", string("HAProxy session")),
+            ],
+        )],
+        vec![devenv_message(
+            "frontend_tcp_request",
+            id,
+            &[
+                ("span", string("Frontend TCP request")),
+                ("child-of", string("Client session")),
+            ],
+        )],
+        vec![devenv_message(
+            "frontend_http_request",
+            id,
+            &[
+                ("span", string("Frontend HTTP request")),
+                ("follows-from", string("Frontend TCP request")),
+                ("tag", string("http.method")),
+                ("", string("GET")),
+                ("finish", string("Frontend TCP request")),
+            ],
+        )],
+        vec![devenv_message(
+            "backend_tcp_request",
+            id,
+            &[
+                ("span", string("Backend TCP request")),
+                ("follows-from", string("Frontend HTTP request")),
+                ("finish", string("Frontend HTTP request")),
+            ],
+        )],
+        vec![devenv_message(
+            "backend_http_request",
+            id,
+            &[
+                ("span", string("Backend HTTP request")),
+                ("follows-from", string("Backend TCP request")),
+                ("finish", string("Backend TCP request")),
+            ],
+        )],
+        vec![devenv_message(
+            "server_session_start",
+            id,
+            &[
+                ("span", string("Server session")),
+                ("child-of", string("HAProxy session")),
+                ("finish", string("Backend HTTP request")),
+            ],
+        )],
+        vec![devenv_message(
+            "tcp_response",
+            id,
+            &[
+                ("span", string("TCP response")),
+                ("child-of", string("Server session")),
+            ],
+        )],
+        // on-http-response carries the 4 messages of the event in a frame
+        vec![
+            devenv_message(
+                "http_response",
+                id,
+                &[
+                    ("span", string("HTTP response")),
+                    ("follows-from", string("TCP response")),
+                    ("tag", string("http.status_code")),
+                    ("", TypedData::INT32(503)),
+                    ("finish", string("TCP response")),
+                ],
+            ),
+            devenv_message(
+                "http_response-error",
+                id,
+                &[
+                    ("span", string("HTTP response")),
+                    ("tag", string("error")),
+                    ("", TypedData::BOOL(true)),
+                ],
+            ),
+            devenv_message(
+                "server_session_end",
+                id,
+                &[
+                    ("finish", string("HTTP response")),
+                    ("finish", string("Server session")),
+                ],
+            ),
+            devenv_message("client_session_end", id, &[("finish", string("*"))]),
+        ],
+    ];
+    for (frame_id, messages) in events.iter().enumerate() {
+        let header = FrameHeader {
+            r#type: FrameType::NOTIFY,
+            flags: FrameFlags::new(true, false),
+            stream_id: 1,
+            frame_id: frame_id as u64,
+        };
+        router
+            .notify(&negotiated(None), &header, messages)
+            .await
+            .unwrap();
+    }
+
+    let session = span("HAProxy session");
+    let trace_id = session.span_context.trace_id();
+    let names = [
+        "Client session",
+        "Frontend TCP request",
+        "Frontend HTTP request",
+        "Backend TCP request",
+        "Backend HTTP request",
+        "Server session",
+        "TCP response",
+        "HTTP response",
+    ];
+    for name in names {
+        assert_eq!(span(name).span_context.trace_id(), trace_id, "{}", name);
+    }
+    let response = span("HTTP response");
+    assert_eq!(
+        response.attributes.get(&Key::new("http.status_code")),
+        Some(&Value::I64(503))
+    );
+    assert_eq!(
+        response.attributes.get(&Key::new("error")),
+        Some(&Value::Bool(true))
+    );
+    // a single trace, none of its spans ended twice or left behind
+    let ended = recorded()
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|span| span.span_context.trace_id() == trace_id)
+        .count();
+    assert_eq!(ended, names.len() + 1);
+    assert!(handler.context().is_empty());
+}
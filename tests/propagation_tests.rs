@@ -0,0 +1,240 @@
+use haproxy_spoa_rust::frame::{Action, TypedData};
+use haproxy_spoa_rust::propagation::{
+    parse_propagators, set_vars, text_map_propagator, var_name, B3Encoding, B3Propagator,
+    Propagator,
+};
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::trace::{SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState};
+use opentelemetry::Context;
+use std::collections::HashMap;
+
+fn context(flags: TraceFlags) -> Context {
+    Context::new().with_remote_span_context(SpanContext::new(
+        TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap(),
+        SpanId::from_hex("00f067aa0ba902b7").unwrap(),
+        flags,
+        true,
+        TraceState::default(),
+    ))
+}
+
+fn vars(actions: Vec<Action>) -> Vec<(String, String)> {
+    actions
+        .into_iter()
+        .map(|action| match action {
+            Action::SetVar {
+                name,
+                value: TypedData::STRING(value),
+                ..
+            } => (name, value),
+            other => panic!("unexpected action {:?}", other),
+        })
+        .collect()
+}
+
+fn extract(propagator: &dyn TextMapPropagator, headers: &[(&str, &str)]) -> SpanContext {
+    let carrier: HashMap<String, String> = headers
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    propagator.extract(&carrier).span().span_context().clone()
+}
+
+#[test]
+fn should_parse_propagators() {
+    assert_eq!(
+        parse_propagators("tracecontext, baggage,jaeger,b3,b3multi"),
+        Ok(vec![
+            Propagator::TraceContext,
+            Propagator::Baggage,
+            Propagator::Jaeger,
+            Propagator::B3,
+            Propagator::B3Multi,
+        ])
+    );
+    assert!(parse_propagators("tracecontext,xray").is_err());
+}
+
+#[test]
+fn should_name_variables_after_propagation_fields() {
+    assert_eq!(var_name("traceparent"), "traceparent");
+    assert_eq!(var_name("uber-trace-id"), "uber_trace_id");
+    assert_eq!(var_name("X-B3-TraceId"), "x_b3_traceid");
+}
+
+#[test]
+fn should_set_vars_of_every_propagator() {
+    let propagator = text_map_propagator(&[Propagator::TraceContext, Propagator::Jaeger]);
+    assert_eq!(
+        vars(set_vars(&propagator, &context(TraceFlags::SAMPLED))),
+        vec![
+            (
+                "traceparent".to_string(),
+                "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01".to_string()
+            ),
+            ("tracestate".to_string(), "".to_string()),
+            (
+                "uber_trace_id".to_string(),
+                "4bf92f3577b34da6a3ce929d0e0e4736:00f067aa0ba902b7:0:1".to_string()
+            ),
+        ]
+    );
+}
+
+#[test]
+fn should_set_no_var_without_span_context() {
+    let propagator = text_map_propagator(&[Propagator::TraceContext, Propagator::B3]);
+    assert!(set_vars(&propagator, &Context::new()).is_empty());
+}
+
+#[test]
+fn should_inject_b3_headers() {
+    let single = B3Propagator::new(B3Encoding::SingleHeader);
+    assert_eq!(
+        vars(set_vars(&single, &context(TraceFlags::SAMPLED))),
+        vec![(
+            "b3".to_string(),
+            "4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-1".to_string()
+        )]
+    );
+
+    let multi = B3Propagator::new(B3Encoding::MultipleHeader);
+    assert_eq!(
+        vars(set_vars(&multi, &context(TraceFlags::default()))),
+        vec![
+            ("x_b3_sampled".to_string(), "0".to_string()),
+            ("x_b3_spanid".to_string(), "00f067aa0ba902b7".to_string()),
+            (
+                "x_b3_traceid".to_string(),
+                "4bf92f3577b34da6a3ce929d0e0e4736".to_string()
+            ),
+        ]
+    );
+}
+
+#[test]
+fn should_extract_b3_headers() {
+    let single = B3Propagator::new(B3Encoding::SingleHeader);
+    let span_context = extract(
+        &single,
+        &[(
+            "b3",
+            "80f198ee56343ba864fe8b2a57d3eff7-e457b5a2e4d86bd1-1-05e3ac9a4f6e3b90",
+        )],
+    );
+    assert_eq!(
+        span_context.trace_id(),
+        TraceId::from_hex("80f198ee56343ba864fe8b2a57d3eff7").unwrap()
+    );
+    assert_eq!(
+        span_context.span_id(),
+        SpanId::from_hex("e457b5a2e4d86bd1").unwrap()
+    );
+    assert!(span_context.is_sampled());
+    assert!(span_context.is_remote());
+
+    // 64 bits trace id, not sampled
+    let multi = B3Propagator::new(B3Encoding::MultipleHeader);
+    let span_context = extract(
+        &multi,
+        &[
+            ("x-b3-traceid", "64fe8b2a57d3eff7"),
+            ("x-b3-spanid", "e457b5a2e4d86bd1"),
+            ("x-b3-sampled", "0"),
+        ],
+    );
+    assert_eq!(
+        span_context.trace_id(),
+        TraceId::from_hex("64fe8b2a57d3eff7").unwrap()
+    );
+    assert!(!span_context.is_sampled());
+
+    assert!(!extract(&single, &[("b3", "1")]).is_valid());
+    assert!(!extract(&single, &[("b3", "not-hex-1")]).is_valid());
+}
+
+/// Examples of https://github.com/openzipkin/b3-propagation.
+#[test]
+fn should_follow_the_b3_specification() {
+    let single = B3Propagator::new(B3Encoding::SingleHeader);
+    let trace_id = "80f198ee56343ba864fe8b2a57d3eff7";
+    let span_id = "e457b5a2e4d86bd1";
+    let b3 = |value: &str| extract(&single, &[("b3", value)]);
+    // sampled, with or without parent span id, accepted, debug or deferred
+    for value in [
+        format!("{}-{}-1-05e3ac9a4f6e3b90", trace_id, span_id),
+        format!("{}-{}-1", trace_id, span_id),
+        format!("{}-{}-d", trace_id, span_id),
+        format!("{}-{}", trace_id, span_id),
+    ] {
+        let span_context = b3(&value);
+        assert_eq!(
+            span_context.trace_id(),
+            TraceId::from_hex(trace_id).unwrap()
+        );
+        assert_eq!(span_context.span_id(), SpanId::from_hex(span_id).unwrap());
+        assert!(span_context.is_sampled(), "{}", value);
+    }
+    assert!(!b3(&format!("{}-{}-0", trace_id, span_id)).is_sampled());
+    // a sampling state alone, or ids that are not lower-hex, carry no trace
+    assert!(!b3("0").is_valid());
+    assert!(!b3(&format!("{}-{}-1", trace_id.to_uppercase(), span_id)).is_valid());
+    assert!(!b3(&format!("{}-{}-2", trace_id, span_id)).is_valid());
+
+    let multi = B3Propagator::new(B3Encoding::MultipleHeader);
+    let ids = [("x-b3-traceid", trace_id), ("x-b3-spanid", span_id)];
+    let headers = |extra: &[(&'static str, &'static str)]| {
+        let mut headers = ids.to_vec();
+        headers.extend_from_slice(extra);
+        extract(&multi, &headers)
+    };
+    assert!(headers(&[("x-b3-sampled", "1")]).is_sampled());
+    assert!(!headers(&[("x-b3-sampled", "0")]).is_sampled());
+    // debug implies sampled, a missing sampling state defers the decision
+    assert!(headers(&[("x-b3-flags", "1")]).is_sampled());
+    assert!(headers(&[]).is_sampled());
+    assert!(!extract(&multi, &[("x-b3-sampled", "1")]).is_valid());
+
+    // a single header, or a header each, the unused ones never injected
+    let fields: Vec<String> = single.fields().map(String::from).collect();
+    assert_eq!(fields, vec!["b3"]);
+    let fields: Vec<String> = multi.fields().map(String::from).collect();
+    assert_eq!(
+        fields,
+        vec!["x-b3-traceid", "x-b3-spanid", "x-b3-sampled", "x-b3-flags"]
+    );
+    assert_eq!(
+        vars(set_vars(&single, &context(TraceFlags::default()))),
+        vec![(
+            "b3".to_string(),
+            "4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-0".to_string()
+        )]
+    );
+    assert_eq!(
+        vars(set_vars(&multi, &context(TraceFlags::SAMPLED))),
+        vec![
+            ("x_b3_sampled".to_string(), "1".to_string()),
+            ("x_b3_spanid".to_string(), "00f067aa0ba902b7".to_string()),
+            (
+                "x_b3_traceid".to_string(),
+                "4bf92f3577b34da6a3ce929d0e0e4736".to_string()
+            ),
+        ]
+    );
+}
+
+#[test]
+fn should_keep_context_extracted_before_jaeger() {
+    let propagator = text_map_propagator(&[Propagator::TraceContext, Propagator::Jaeger]);
+    let span_context = extract(
+        &propagator,
+        &[(
+            "traceparent",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        )],
+    );
+    assert_eq!(
+        span_context.trace_id(),
+        TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap()
+    );
+}
@@ -0,0 +1,229 @@
+use haproxy_spoa_rust::frame::{FrameFlags, FrameHeader, FrameType, KVList, TypedData};
+use haproxy_spoa_rust::handler::SpoaHandler;
+use haproxy_spoa_rust::negotiation::Negotiated;
+use haproxy_spoa_rust::otel::{OtelHandler, OtelSettings};
+use haproxy_spoa_rust::red::{RequestAttributes, RequestMetrics};
+use haproxy_spoa_rust::router::Router;
+use opentelemetry::global;
+use opentelemetry::metrics::MeterProvider;
+use opentelemetry::sdk::export::metrics::{CheckpointSet, ExportKindSelector};
+use opentelemetry::sdk::export::metrics::{Count, Sum};
+use opentelemetry::sdk::metrics::aggregators::{MinMaxSumCountAggregator, SumAggregator};
+use opentelemetry::sdk::metrics::controllers::{self, PullController};
+use opentelemetry::sdk::metrics::selectors::simple::Selector;
+use opentelemetry::KeyValue;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+fn controller() -> PullController {
+    controllers::pull(
+        Box::new(Selector::Inexpensive),
+        Box::new(ExportKindSelector::Cumulative),
+    )
+    .with_cache_period(Duration::ZERO)
+    .build()
+}
+
+/// Count recorded by each instrument, by its attributes.
+fn collect(controller: &mut PullController) -> BTreeMap<(String, String), u64> {
+    controller.collect().unwrap();
+    let mut counts = BTreeMap::new();
+    controller
+        .try_for_each(&ExportKindSelector::Cumulative, &mut |record| {
+            let aggregator = record.aggregator().unwrap().as_any();
+            let count = if let Some(sum) = aggregator.downcast_ref::<SumAggregator>() {
+                sum.sum()?.to_u64(record.descriptor().number_kind())
+            } else {
+                let values = aggregator.downcast_ref::<MinMaxSumCountAggregator>();
+                values.unwrap().count()?
+            };
+            let attributes = record
+                .attributes()
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect::<Vec<_>>()
+                .join(",");
+            let name = record.descriptor().name().to_string();
+            counts.insert((name, attributes), count);
+            Ok(())
+        })
+        .unwrap();
+    counts
+}
+
+fn request(tags: &[KeyValue]) -> RequestAttributes {
+    let mut request = RequestAttributes::default();
+    for tag in tags {
+        request.observe(tag);
+    }
+    request
+}
+
+fn count(counts: &BTreeMap<(String, String), u64>, name: &str, attributes: &str) -> u64 {
+    counts
+        .get(&(name.to_string(), attributes.to_string()))
+        .copied()
+        .unwrap_or(0)
+}
+
+#[test]
+fn should_count_requests_and_errors_by_status_class() {
+    let mut controller = controller();
+    let red = RequestMetrics::new(&controller.provider().meter("test", None), 100);
+
+    let ok = request(&[
+        KeyValue::new("http.method", "GET"),
+        KeyValue::new("http.status_code", 200_i64),
+        KeyValue::new("haproxy.frontend", "fe"),
+        KeyValue::new("http.url", "/ignored"),
+    ]);
+    let failed = request(&[
+        KeyValue::new("http.method", "GET"),
+        KeyValue::new("http.status_code", "503"),
+        KeyValue::new("haproxy.frontend", "fe"),
+    ]);
+    red.completed(&ok, Duration::from_millis(10));
+    red.completed(&ok, Duration::from_millis(20));
+    red.completed(&failed, Duration::from_millis(30));
+    red.aborted(&RequestAttributes::default(), Duration::from_secs(1));
+
+    let counts = collect(&mut controller);
+    let ok = "haproxy.frontend=fe,http.method=GET,http.status_class=2xx";
+    let failed = "haproxy.frontend=fe,http.method=GET,http.status_class=5xx";
+    let aborted = "http.status_class=aborted";
+    assert_eq!(count(&counts, "haproxy.requests", ok), 2);
+    assert_eq!(count(&counts, "haproxy.request.duration", ok), 2);
+    assert_eq!(count(&counts, "haproxy.request.errors", ok), 0);
+    assert_eq!(count(&counts, "haproxy.requests", failed), 1);
+    assert_eq!(count(&counts, "haproxy.request.errors", failed), 1);
+    assert_eq!(count(&counts, "haproxy.requests", aborted), 1);
+    assert_eq!(count(&counts, "haproxy.request.errors", aborted), 1);
+}
+
+#[test]
+fn should_count_an_error_tag_as_an_error() {
+    let mut controller = controller();
+    let red = RequestMetrics::new(&controller.provider().meter("test", None), 100);
+
+    red.completed(
+        &request(&[
+            KeyValue::new("http.status_code", 404_i64),
+            KeyValue::new("error", true),
+        ]),
+        Duration::ZERO,
+    );
+
+    let counts = collect(&mut controller);
+    assert_eq!(
+        count(&counts, "haproxy.request.errors", "http.status_class=4xx"),
+        1
+    );
+}
+
+#[test]
+fn should_limit_the_values_of_each_attribute() {
+    let mut controller = controller();
+    let red = RequestMetrics::new(&controller.provider().meter("test", None), 2);
+
+    for backend in &["be1", "be2", "be3", "be4", "be1"] {
+        let request = request(&[KeyValue::new("haproxy.backend", *backend)]);
+        red.completed(&request, Duration::ZERO);
+    }
+
+    let counts = collect(&mut controller);
+    let requests = |backend: &str| {
+        let attributes = format!("haproxy.backend={},http.status_class=unknown", backend);
+        count(&counts, "haproxy.requests", &attributes)
+    };
+    assert_eq!(requests("be1"), 2);
+    assert_eq!(requests("be2"), 1);
+    assert_eq!(requests("be3"), 0);
+    assert_eq!(requests("other"), 2);
+}
+
+fn message(name: &str, args: &[(&str, TypedData)]) -> (String, KVList) {
+    let mut details: KVList = vec![("id".to_string(), TypedData::STRING("r1".to_string()))];
+    for (k, v) in args {
+        details.push((k.to_string(), v.clone()));
+    }
+    (format!("opentracing:{}", name), details)
+}
+
+fn string(s: &str) -> TypedData {
+    TypedData::STRING(s.to_string())
+}
+
+#[tokio::test]
+async fn should_complete_a_request_ended_by_a_notify_frame_of_several_messages() {
+    let mut controller = controller();
+    // the handler records the RED metrics with the global meter
+    global::set_meter_provider(controller.provider());
+    let router = Router::new().route(
+        "opentracing:*",
+        OtelHandler::with_settings(OtelSettings::default()),
+    );
+    let negotiated = Negotiated {
+        version: "2.0".to_string(),
+        max_frame_size: 16380,
+        capabilities: vec![],
+        engine_id: None,
+        healthcheck: false,
+    };
+    let header = |frame_id| FrameHeader {
+        r#type: FrameType::NOTIFY,
+        flags: FrameFlags::new(true, false),
+        stream_id: 1,
+        frame_id,
+    };
+
+    let request = vec![message(
+        "frontend_http_request",
+        &[
+            ("span", string("Client session")),
+            ("tag", string("http.method")),
+            ("", string("GET")),
+        ],
+    )];
+    router
+        .notify(&negotiated, &header(1), &request)
+        .await
+        .unwrap();
+    // the messages of the on-http-response event of the devenv
+    let response = vec![
+        message(
+            "http_response",
+            &[
+                ("span", string("HTTP response")),
+                ("child-of", string("Client session")),
+                ("tag", string("http.status_code")),
+                ("", TypedData::INT32(503)),
+            ],
+        ),
+        message(
+            "http_response-error",
+            &[
+                ("span", string("HTTP response")),
+                ("tag", string("error")),
+                ("", TypedData::BOOL(true)),
+            ],
+        ),
+        message("server_session_end", &[("finish", string("HTTP response"))]),
+        message("client_session_end", &[("finish", string("*"))]),
+    ];
+    router
+        .notify(&negotiated, &header(2), &response)
+        .await
+        .unwrap();
+    // requests still in progress are aborted
+    router.shutdown().await;
+
+    let counts = collect(&mut controller);
+    let failed = "http.method=GET,http.status_class=5xx";
+    assert_eq!(count(&counts, "haproxy.requests", failed), 1);
+    assert_eq!(count(&counts, "haproxy.request.errors", failed), 1);
+    let aborted = counts
+        .keys()
+        .filter(|(_, attributes)| attributes.contains("aborted"))
+        .count();
+    assert_eq!(aborted, 0);
+}
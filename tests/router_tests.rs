@@ -0,0 +1,173 @@
+use async_trait::async_trait;
+use haproxy_spoa_rust::frame::{
+    Action, ActionVarScope, Error, FrameFlags, FrameHeader, FrameType, KVList, ListOfMessages,
+    TypedData,
+};
+use haproxy_spoa_rust::handler::SpoaHandler;
+use haproxy_spoa_rust::negotiation::Negotiated;
+use haproxy_spoa_rust::router::{MessageHandler, Router, UnknownMessages};
+
+/// Answers every message with a SET-VAR action `<prefix>.<message>`.
+struct Tag(&'static str);
+
+#[async_trait]
+impl MessageHandler for Tag {
+    async fn handle(
+        &self,
+        _negotiated: &Negotiated,
+        _header: &FrameHeader,
+        name: &str,
+        _args: &KVList,
+    ) -> Result<Vec<Action>, Error> {
+        Ok(vec![Action::SetVar {
+            scope: ActionVarScope::TRANSACTION,
+            name: format!("{}.{}", self.0, name),
+            value: TypedData::BOOL(true),
+        }])
+    }
+}
+
+fn negotiated() -> Negotiated {
+    Negotiated {
+        version: "2.0".to_string(),
+        max_frame_size: 16380,
+        capabilities: vec![],
+        engine_id: None,
+        healthcheck: false,
+    }
+}
+
+fn header() -> FrameHeader {
+    FrameHeader {
+        r#type: FrameType::NOTIFY,
+        flags: FrameFlags::new(true, false),
+        stream_id: 1,
+        frame_id: 1,
+    }
+}
+
+fn messages(names: &[&str]) -> ListOfMessages {
+    names
+        .iter()
+        .map(|name| (name.to_string(), KVList::new()))
+        .collect()
+}
+
+fn var_names(actions: &[Action]) -> Vec<String> {
+    let mut names: Vec<String> = actions
+        .iter()
+        .map(|action| match action {
+            Action::SetVar { name, .. } => name.to_owned(),
+            Action::UnsetVar { name, .. } => name.to_owned(),
+        })
+        .collect();
+    names.sort();
+    names
+}
+
+#[tokio::test]
+async fn should_invoke_every_matching_handler_of_every_message() {
+    let router = Router::new()
+        .route("opentracing:frontend_tcp_request", Tag("exact"))
+        .route("opentracing:*", Tag("prefix"))
+        .route("*", Tag("all"));
+
+    let actions = router
+        .notify(
+            &negotiated(),
+            &header(),
+            &messages(&["opentracing:frontend_tcp_request", "check-ip"]),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(
+        var_names(&actions),
+        vec![
+            "all.check-ip",
+            "all.opentracing:frontend_tcp_request",
+            "exact.opentracing:frontend_tcp_request",
+            "prefix.opentracing:frontend_tcp_request",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn should_merge_actions_in_registration_order() {
+    let router = Router::new()
+        .route("opentracing:*", Tag("first"))
+        .route("opentracing:http_response", Tag("second"));
+
+    let actions = router
+        .notify(
+            &negotiated(),
+            &header(),
+            &messages(&["opentracing:http_response"]),
+        )
+        .await
+        .unwrap();
+
+    match (&actions[0], &actions[1]) {
+        (Action::SetVar { name: first, .. }, Action::SetVar { name: second, .. }) => {
+            assert_eq!(first, "first.opentracing:http_response");
+            assert_eq!(second, "second.opentracing:http_response");
+        }
+        other => panic!("unexpected actions {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn should_route_messages_in_frame_order() {
+    let router = Router::new().route("*", Tag("all"));
+    let names = [
+        "opentracing:http_response",
+        "opentracing:http_response-error",
+        "opentracing:server_session_end",
+        "opentracing:client_session_end",
+    ];
+
+    let actions = router
+        .notify(&negotiated(), &header(), &messages(&names))
+        .await
+        .unwrap();
+
+    let routed: Vec<String> = actions
+        .iter()
+        .map(|action| match action {
+            Action::SetVar { name, .. } => name.to_owned(),
+            Action::UnsetVar { name, .. } => name.to_owned(),
+        })
+        .collect();
+    let expected: Vec<String> = names.iter().map(|name| format!("all.{}", name)).collect();
+    assert_eq!(routed, expected);
+}
+
+#[tokio::test]
+async fn should_skip_unknown_messages_unless_rejected() {
+    let frame = messages(&["opentracing:http_response", "check-ip"]);
+
+    let router = Router::new()
+        .route("opentracing:*", Tag("otel"))
+        .unknown_messages(UnknownMessages::Ignore);
+    let actions = router
+        .notify(&negotiated(), &header(), &frame)
+        .await
+        .unwrap();
+    assert_eq!(var_names(&actions), vec!["otel.opentracing:http_response"]);
+
+    let router = Router::new()
+        .route("opentracing:*", Tag("otel"))
+        .unknown_messages(UnknownMessages::Reject);
+    match router.notify(&negotiated(), &header(), &frame).await {
+        Err(Error::UnknownMessage(name)) => assert_eq!(name, "check-ip"),
+        other => panic!("expected UnknownMessage, got {:?}", other),
+    }
+}
+
+#[test]
+fn should_parse_unknown_messages_behaviour() {
+    assert_eq!("ignore".parse(), Ok(UnknownMessages::Ignore));
+    assert_eq!("log".parse(), Ok(UnknownMessages::Log));
+    assert_eq!("reject".parse(), Ok(UnknownMessages::Reject));
+    assert!("drop".parse::<UnknownMessages>().is_err());
+}
@@ -0,0 +1,579 @@
+use async_trait::async_trait;
+use haproxy_spoa_rust::connection::{Connection, ConnectionSettings};
+use haproxy_spoa_rust::frame::{
+    Action, ActionVarScope, Error, Frame, FrameFlags, FrameHeader, FrameType, KVList,
+    ListOfMessages, StatusCode, TypedData,
+};
+use haproxy_spoa_rust::handler::SpoaHandler;
+use haproxy_spoa_rust::listener::{ListenAddr, Listener, UnixSocketSettings};
+use haproxy_spoa_rust::negotiation::Negotiated;
+use haproxy_spoa_rust::server::{Server, DEFAULT_ERROR_VAR};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UnixStream};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// Records every hook invoked, and answers each message with a SET-VAR
+/// action named after it, after `delay` unless the message is `fast`. The
+/// `fail` message is rejected.
+#[derive(Clone, Default)]
+struct Recorder {
+    events: Arc<Mutex<Vec<String>>>,
+    delay: Duration,
+}
+
+impl Recorder {
+    fn record(&self, event: String) {
+        self.events.lock().unwrap().push(event);
+    }
+
+    fn events(&self) -> Vec<String> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl SpoaHandler for Recorder {
+    async fn hello(&self, negotiated: &Negotiated) {
+        self.record(format!("hello {}", negotiated.version));
+    }
+
+    async fn notify(
+        &self,
+        _negotiated: &Negotiated,
+        header: &FrameHeader,
+        messages: &ListOfMessages,
+    ) -> Result<Vec<Action>, Error> {
+        self.record(format!("notify {}", header.frame_id));
+        if !messages.iter().any(|(name, _)| name == "fast") {
+            tokio::time::sleep(self.delay).await;
+        }
+        if messages.iter().any(|(name, _)| name == "fail") {
+            return Err(Error::UnknownMessage("fail".to_string()));
+        }
+        Ok(messages
+            .iter()
+            .map(|(name, _)| Action::SetVar {
+                scope: ActionVarScope::TRANSACTION,
+                name: name.to_owned(),
+                value: TypedData::BOOL(true),
+            })
+            .collect())
+    }
+
+    async fn disconnect(&self, _negotiated: &Negotiated, status_code: u32, message: &str) {
+        self.record(format!("disconnect {} {}", status_code, message));
+    }
+
+    async fn closed(&self, _negotiated: &Negotiated) {
+        self.record("closed".to_string());
+    }
+
+    async fn shutdown(&self) {
+        self.record("shutdown".to_string());
+    }
+}
+
+async fn start(
+    settings: ConnectionSettings,
+    handler: Recorder,
+) -> (
+    SocketAddr,
+    oneshot::Sender<()>,
+    JoinHandle<Result<(), Error>>,
+) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    let server = Server::new(settings, handler);
+    let running = tokio::spawn(server.run(vec![listener.into()], shutdown_rx));
+    (addr, shutdown_tx, running)
+}
+
+fn header(r#type: FrameType, stream_id: u64, frame_id: u64) -> FrameHeader {
+    FrameHeader {
+        r#type,
+        flags: FrameFlags::new(true, false),
+        stream_id,
+        frame_id,
+    }
+}
+
+fn hello(capabilities: &str) -> Frame {
+    let content: KVList = vec![
+        (
+            "supported-versions".to_string(),
+            TypedData::STRING("2.0".to_string()),
+        ),
+        ("max-frame-size".to_string(), TypedData::UINT32(16380)),
+        (
+            "capabilities".to_string(),
+            TypedData::STRING(capabilities.to_string()),
+        ),
+    ];
+    Frame::HAProxyHello {
+        header: header(FrameType::HAPROXY_HELLO, 0, 0),
+        content,
+    }
+}
+
+fn notify(frame_id: u64, message: &str) -> Frame {
+    Frame::Notify {
+        header: header(FrameType::NOTIFY, 1, frame_id),
+        messages: vec![(
+            message.to_string(),
+            vec![("id".to_string(), TypedData::STRING("abc".to_string()))],
+        )],
+    }
+}
+
+async fn handshake(addr: SocketAddr, capabilities: &str) -> Connection {
+    hello_over(TcpStream::connect(addr).await.unwrap(), capabilities).await
+}
+
+async fn hello_over<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: S,
+    capabilities: &str,
+) -> Connection<S> {
+    let mut client = Connection::new(socket);
+    client.write_frame(&hello(capabilities)).await.unwrap();
+    match client.read_frame().await.unwrap() {
+        Some(Frame::AgentHello { .. }) => client,
+        other => panic!("expected AGENT-HELLO, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn should_delegate_notify_frames_to_the_handler() {
+    for capabilities in &["pipelining", "pipelining,async"] {
+        let recorder = Recorder::default();
+        let (addr, shutdown, running) =
+            start(ConnectionSettings::default(), recorder.clone()).await;
+
+        let mut client = handshake(addr, capabilities).await;
+        client
+            .write_frame(&notify(7, "check-client"))
+            .await
+            .unwrap();
+
+        match client.read_frame().await.unwrap() {
+            Some(Frame::Ack { header, actions }) => {
+                assert_eq!(header.stream_id, 1);
+                assert_eq!(header.frame_id, 7);
+                assert_eq!(actions.len(), 1);
+                match &actions[0] {
+                    Action::SetVar { name, .. } => assert_eq!(name, "check-client"),
+                    other => panic!("expected SET-VAR, got {:?}", other),
+                }
+            }
+            other => panic!("expected ACK, got {:?}", other),
+        }
+
+        drop(shutdown);
+        running.await.unwrap().unwrap();
+        assert_eq!(
+            recorder.events(),
+            vec!["hello 2.0", "notify 7", "closed", "shutdown"]
+        );
+    }
+}
+
+#[tokio::test]
+async fn should_acknowledge_notify_frames_the_handler_failed_with_the_error() {
+    for capabilities in &["pipelining", "pipelining,async"] {
+        let recorder = Recorder::default();
+        let (addr, shutdown, running) =
+            start(ConnectionSettings::default(), recorder.clone()).await;
+
+        let mut client = handshake(addr, capabilities).await;
+        client.write_frame(&notify(3, "fail")).await.unwrap();
+
+        match client.read_frame().await.unwrap() {
+            Some(Frame::Ack { header, actions }) => {
+                assert_eq!(header.frame_id, 3);
+                match &actions[..] {
+                    [Action::SetVar {
+                        scope: ActionVarScope::REQUEST,
+                        name,
+                        value: TypedData::STRING(error),
+                    }] => {
+                        assert_eq!(name, DEFAULT_ERROR_VAR);
+                        assert_eq!(error, "UnknownMessage fail");
+                    }
+                    other => panic!("expected the error variable, got {:?}", other),
+                }
+            }
+            other => panic!("expected ACK, got {:?}", other),
+        }
+
+        drop(shutdown);
+        running.await.unwrap().unwrap();
+    }
+}
+
+#[tokio::test]
+async fn should_acknowledge_notify_frames_the_handler_failed_with_the_configured_variable() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (shutdown, shutdown_rx) = oneshot::channel::<()>();
+    let server =
+        Server::new(ConnectionSettings::default(), Recorder::default()).error_var("agent_error");
+    let running = tokio::spawn(server.run(vec![listener.into()], shutdown_rx));
+
+    let mut client = handshake(addr, "pipelining").await;
+    client.write_frame(&notify(3, "fail")).await.unwrap();
+
+    match client.read_frame().await.unwrap() {
+        Some(Frame::Ack { actions, .. }) => match &actions[..] {
+            [Action::SetVar { name, .. }] => assert_eq!(name, "agent_error"),
+            other => panic!("expected the error variable, got {:?}", other),
+        },
+        other => panic!("expected ACK, got {:?}", other),
+    }
+
+    drop(shutdown);
+    running.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn should_answer_healthcheck_hello_and_close_the_connection() {
+    let recorder = Recorder::default();
+    let (addr, shutdown, running) = start(ConnectionSettings::default(), recorder.clone()).await;
+
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let mut healthcheck = hello("pipelining");
+    if let Frame::HAProxyHello { content, .. } = &mut healthcheck {
+        content.push(("healthcheck".to_string(), TypedData::BOOL(true)));
+    }
+    client.write_frame(&healthcheck).await.unwrap();
+
+    assert!(matches!(
+        client.read_frame().await.unwrap(),
+        Some(Frame::AgentHello { .. })
+    ));
+    // closed by the agent
+    assert!(client.read_frame().await.unwrap().is_none());
+
+    drop(shutdown);
+    running.await.unwrap().unwrap();
+    assert_eq!(recorder.events(), vec!["shutdown"]);
+}
+
+fn assert_frame_too_big(frame: Option<Frame>) {
+    match frame {
+        Some(frame @ Frame::AgentDisconnect { .. }) => {
+            let status_code: u32 = StatusCode::FRAME_TOO_BIG.into();
+            assert_eq!(frame.disconnect_status().unwrap().0, status_code);
+        }
+        other => panic!("expected AGENT-DISCONNECT, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn should_disconnect_as_soon_as_an_oversized_frame_length_is_received() {
+    let recorder = Recorder::default();
+    let (addr, shutdown, running) = start(ConnectionSettings::default(), recorder.clone()).await;
+
+    // only the length prefix of a 1 MB frame is sent
+    let mut socket = TcpStream::connect(addr).await.unwrap();
+    socket.write_all(&[0, 0x10, 0, 0]).await.unwrap();
+    let mut client = Connection::new(socket);
+    assert_frame_too_big(client.read_frame().await.unwrap());
+
+    drop(shutdown);
+    running.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn should_disconnect_instead_of_sending_an_ack_larger_than_the_max_frame_size() {
+    let recorder = Recorder::default();
+    let (addr, shutdown, running) = start(ConnectionSettings::default(), recorder.clone()).await;
+
+    let mut client = Connection::new(TcpStream::connect(addr).await.unwrap());
+    let mut small = hello("pipelining");
+    if let Frame::HAProxyHello { content, .. } = &mut small {
+        content[1].1 = TypedData::UINT32(256);
+    }
+    client.write_frame(&small).await.unwrap();
+    assert!(matches!(
+        client.read_frame().await.unwrap(),
+        Some(Frame::AgentHello { .. })
+    ));
+    // the NOTIFY frame fits in 256 bytes, but the ACK setting a variable
+    // named after each of its messages does not
+    let messages = vec![
+        ("a".repeat(80), KVList::new()),
+        ("b".repeat(80), KVList::new()),
+        ("c".repeat(80), KVList::new()),
+    ];
+    client
+        .write_frame(&Frame::Notify {
+            header: header(FrameType::NOTIFY, 1, 1),
+            messages,
+        })
+        .await
+        .unwrap();
+    assert_frame_too_big(client.read_frame().await.unwrap());
+
+    drop(shutdown);
+    running.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn should_not_write_frames_larger_than_the_negotiated_max_frame_size() {
+    let (socket, _peer) = tokio::io::duplex(64 * 1024);
+    let mut connection = Connection::new(socket);
+    connection.set_negotiated(Negotiated {
+        version: "2.0".to_string(),
+        max_frame_size: 256,
+        capabilities: vec![],
+        engine_id: None,
+        healthcheck: false,
+    });
+    let ack = Frame::Ack {
+        header: header(FrameType::ACK, 1, 1),
+        actions: vec![Action::SetVar {
+            scope: ActionVarScope::TRANSACTION,
+            name: "baggage".to_string(),
+            value: TypedData::STRING("x".repeat(300)),
+        }],
+    };
+    assert!(matches!(
+        connection.write_frame(&ack).await,
+        Err(Error::FrameTooBig { max: 256, .. })
+    ));
+}
+
+#[tokio::test]
+async fn should_report_haproxy_disconnect_to_the_handler() {
+    let recorder = Recorder::default();
+    let (addr, shutdown, running) = start(ConnectionSettings::default(), recorder.clone()).await;
+
+    let mut client = handshake(addr, "pipelining").await;
+    let content: KVList = vec![
+        ("status-code".to_string(), TypedData::UINT32(0)),
+        (
+            "message".to_string(),
+            TypedData::STRING("reload".to_string()),
+        ),
+    ];
+    client
+        .write_frame(&Frame::HAProxyDisconnect {
+            header: header(FrameType::HAPROXY_DISCONNECT, 0, 0),
+            content,
+        })
+        .await
+        .unwrap();
+
+    let frame = client.read_frame().await.unwrap().unwrap();
+    let status_code: u32 = StatusCode::NORMAL.into();
+    assert_eq!(frame.disconnect_status().unwrap().0, status_code);
+
+    drop(shutdown);
+    running.await.unwrap().unwrap();
+    assert_eq!(
+        recorder.events(),
+        vec!["hello 2.0", "disconnect 0 reload", "closed", "shutdown"]
+    );
+}
+
+#[tokio::test]
+async fn should_disconnect_on_a_second_hello() {
+    let recorder = Recorder::default();
+    let (addr, shutdown, running) = start(ConnectionSettings::default(), recorder.clone()).await;
+
+    let mut client = handshake(addr, "pipelining").await;
+    client.write_frame(&hello("pipelining")).await.unwrap();
+
+    let frame = client.read_frame().await.unwrap().unwrap();
+    let status_code: u32 = StatusCode::INVALID_FRAME.into();
+    assert_eq!(frame.disconnect_status().unwrap().0, status_code);
+
+    drop(shutdown);
+    running.await.unwrap().unwrap();
+    assert_eq!(recorder.events(), vec!["hello 2.0", "closed", "shutdown"]);
+}
+
+#[tokio::test]
+async fn should_disconnect_every_connection_on_shutdown() {
+    let recorder = Recorder::default();
+    let (addr, shutdown, running) = start(ConnectionSettings::default(), recorder.clone()).await;
+
+    let mut client = handshake(addr, "pipelining").await;
+    shutdown.send(()).unwrap();
+
+    match client.read_frame().await.unwrap() {
+        Some(frame @ Frame::AgentDisconnect { .. }) => {
+            let (status_code, message) = frame.disconnect_status().unwrap();
+            assert_eq!(status_code, 0);
+            assert_eq!(message, "agent is shutting down");
+        }
+        other => panic!("expected AGENT-DISCONNECT, got {:?}", other),
+    }
+    running.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn should_serve_tcp_and_unix_listeners_at_once() {
+    let path = std::env::temp_dir().join(format!("spoa-server-{}.sock", std::process::id()));
+    let unix = Listener::bind(
+        &ListenAddr::Unix(path.clone()),
+        &UnixSocketSettings::default(),
+    )
+    .await
+    .unwrap();
+    let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = tcp.local_addr().unwrap();
+    let recorder = Recorder::default();
+    let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+    let server = Server::new(ConnectionSettings::default(), recorder.clone());
+    let running = tokio::spawn(server.run(vec![tcp.into(), unix], shutdown_rx));
+
+    let mut tcp_client = handshake(addr, "pipelining").await;
+    let mut unix_client = hello_over(UnixStream::connect(&path).await.unwrap(), "pipelining").await;
+    tcp_client.write_frame(&notify(1, "tcp")).await.unwrap();
+    assert!(matches!(
+        tcp_client.read_frame().await,
+        Ok(Some(Frame::Ack { .. }))
+    ));
+    unix_client.write_frame(&notify(2, "unix")).await.unwrap();
+    assert!(matches!(
+        unix_client.read_frame().await,
+        Ok(Some(Frame::Ack { .. }))
+    ));
+
+    shutdown_tx.send(()).unwrap();
+    assert_agent_disconnect(tcp_client.read_frame().await.unwrap());
+    assert_agent_disconnect(unix_client.read_frame().await.unwrap());
+    running.await.unwrap().unwrap();
+    assert!(!path.exists());
+}
+
+async fn read_ack(client: &mut Connection) -> u64 {
+    match client.read_frame().await.unwrap() {
+        Some(Frame::Ack { header, .. }) => header.frame_id,
+        other => panic!("expected ACK, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn should_acknowledge_notify_frames_as_they_complete_up_to_max_in_flight() {
+    let recorder = Recorder {
+        delay: Duration::from_millis(200),
+        ..Recorder::default()
+    };
+    let settings = ConnectionSettings {
+        max_in_flight: 2,
+        ..ConnectionSettings::default()
+    };
+    let (addr, shutdown, running) = start(settings, recorder.clone()).await;
+
+    let mut client = handshake(addr, "pipelining,async").await;
+    client.write_frame(&notify(1, "slow")).await.unwrap();
+    client.write_frame(&notify(2, "fast")).await.unwrap();
+    assert_eq!(read_ack(&mut client).await, 2);
+
+    // the limit is reached, frame 4 is not read until frame 1 or 3 is done
+    client.write_frame(&notify(3, "slow")).await.unwrap();
+    client.write_frame(&notify(4, "fast")).await.unwrap();
+    recorded(&recorder, "notify 3").await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!recorder.events().contains(&"notify 4".to_string()));
+
+    let mut acks = vec![
+        read_ack(&mut client).await,
+        read_ack(&mut client).await,
+        read_ack(&mut client).await,
+    ];
+    assert_eq!(acks[0], 1);
+    acks.sort_unstable();
+    assert_eq!(acks, vec![1, 3, 4]);
+
+    drop(shutdown);
+    running.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn should_acknowledge_notify_frames_in_order_without_async() {
+    let recorder = Recorder {
+        delay: Duration::from_millis(50),
+        ..Recorder::default()
+    };
+    let (addr, shutdown, running) = start(ConnectionSettings::default(), recorder.clone()).await;
+
+    let mut client = handshake(addr, "pipelining").await;
+    client.write_frame(&notify(1, "slow")).await.unwrap();
+    client.write_frame(&notify(2, "fast")).await.unwrap();
+    assert_eq!(read_ack(&mut client).await, 1);
+    assert_eq!(read_ack(&mut client).await, 2);
+
+    drop(shutdown);
+    running.await.unwrap().unwrap();
+}
+
+fn assert_agent_disconnect(frame: Option<Frame>) {
+    match frame {
+        Some(frame @ Frame::AgentDisconnect { .. }) => {
+            assert_eq!(frame.disconnect_status().unwrap().0, 0);
+        }
+        other => panic!("expected AGENT-DISCONNECT, got {:?}", other),
+    }
+}
+
+/// Wait until the handler records `event`.
+async fn recorded(recorder: &Recorder, event: &str) {
+    while !recorder.events().iter().any(|e| e == event) {
+        tokio::time::sleep(Duration::from_millis(1)).await;
+    }
+}
+
+#[tokio::test]
+async fn should_acknowledge_notify_frames_in_progress_on_shutdown() {
+    let recorder = Recorder {
+        delay: Duration::from_millis(100),
+        ..Recorder::default()
+    };
+    let (addr, shutdown, running) = start(ConnectionSettings::default(), recorder.clone()).await;
+
+    let mut client = handshake(addr, "pipelining,async").await;
+    client.write_frame(&notify(3, "on-request")).await.unwrap();
+    recorded(&recorder, "notify 3").await;
+    shutdown.send(()).unwrap();
+
+    match client.read_frame().await.unwrap() {
+        Some(Frame::Ack { header, .. }) => assert_eq!(header.frame_id, 3),
+        other => panic!("expected ACK, got {:?}", other),
+    }
+    assert_agent_disconnect(client.read_frame().await.unwrap());
+    running.await.unwrap().unwrap();
+    assert_eq!(recorder.events().last().unwrap(), "shutdown");
+}
+
+#[tokio::test]
+async fn should_give_up_on_notify_frames_after_the_drain_timeout() {
+    let recorder = Recorder {
+        delay: Duration::from_secs(60),
+        ..Recorder::default()
+    };
+    let settings = ConnectionSettings {
+        drain_timeout: Duration::from_millis(10),
+        ..ConnectionSettings::default()
+    };
+    let (addr, shutdown, running) = start(settings, recorder.clone()).await;
+
+    let mut client = handshake(addr, "pipelining,async").await;
+    client.write_frame(&notify(4, "on-request")).await.unwrap();
+    recorded(&recorder, "notify 4").await;
+    shutdown.send(()).unwrap();
+
+    assert_agent_disconnect(client.read_frame().await.unwrap());
+    running.await.unwrap().unwrap();
+
+    // the NOTIFY task was aborted, its clone of the handler dropped, before
+    // the handler was shut down
+    assert_eq!(Arc::strong_count(&recorder.events), 1);
+    assert_eq!(recorder.events().last().unwrap(), "shutdown");
+}
@@ -0,0 +1,147 @@
+use haproxy_spoa_rust::store::{Eviction, Retain, SpanStore, StoreFull, StoreSettings};
+use std::time::{Duration, Instant};
+
+fn store(capacity: usize, eviction: Eviction) -> SpanStore<Vec<String>> {
+    SpanStore::new(StoreSettings {
+        ttl: Duration::from_secs(10),
+        capacity,
+        eviction,
+        shards: 1,
+        ..StoreSettings::default()
+    })
+}
+
+/// Record `event` for the request `key`, kept unless `event` is `end`.
+fn event(
+    store: &SpanStore<Vec<String>>,
+    key: &str,
+    event: &str,
+) -> Result<Option<String>, StoreFull> {
+    let (_, evicted) = store.update(key, event, |events| {
+        events.push(event.to_string());
+        let retain = if event == "end" {
+            Retain::Remove
+        } else {
+            Retain::Keep
+        };
+        ((), retain)
+    })?;
+    Ok(evicted.map(|request| request.key))
+}
+
+#[test]
+fn should_remove_requests_once_done() {
+    let store = store(10, Eviction::Oldest);
+    event(&store, "a", "start").unwrap();
+    event(&store, "a", "headers").unwrap();
+    assert_eq!(store.len(), 1);
+    event(&store, "a", "end").unwrap();
+    assert!(store.is_empty());
+}
+
+#[test]
+fn should_expire_requests_after_their_last_message() {
+    let store = store(10, Eviction::Oldest);
+    event(&store, "a", "start").unwrap();
+    event(&store, "b", "start").unwrap();
+    event(&store, "a", "headers").unwrap();
+
+    assert!(store.expire(Instant::now()).is_empty());
+
+    let expired = store.expire(Instant::now() + Duration::from_secs(11));
+    let summary: Vec<(String, String, Vec<String>)> = expired
+        .into_iter()
+        .map(|request| (request.key, request.last_event, request.value))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            (
+                "b".to_string(),
+                "start".to_string(),
+                vec!["start".to_string()]
+            ),
+            (
+                "a".to_string(),
+                "headers".to_string(),
+                vec!["start".to_string(), "headers".to_string()]
+            ),
+        ]
+    );
+    assert!(store.is_empty());
+}
+
+#[test]
+fn should_evict_the_request_closest_to_its_deadline_when_full() {
+    let store = store(2, Eviction::Oldest);
+    event(&store, "a", "start").unwrap();
+    event(&store, "b", "start").unwrap();
+    event(&store, "a", "headers").unwrap();
+
+    assert_eq!(event(&store, "c", "start"), Ok(Some("b".to_string())));
+    assert_eq!(store.len(), 2);
+    // stored requests are updated without evicting any other one
+    assert_eq!(event(&store, "a", "end"), Ok(None));
+}
+
+#[test]
+fn should_reject_new_requests_when_full() {
+    let store = store(1, Eviction::Reject);
+    event(&store, "a", "start").unwrap();
+    assert_eq!(event(&store, "b", "start"), Err(StoreFull));
+    assert_eq!(event(&store, "a", "headers"), Ok(None));
+    assert_eq!(store.len(), 1);
+
+    assert_eq!("oldest".parse(), Ok(Eviction::Oldest));
+    assert!("random".parse::<Eviction>().is_err());
+}
+
+#[test]
+fn should_share_capacity_between_shards() {
+    let store: SpanStore<Vec<String>> = SpanStore::new(StoreSettings {
+        capacity: 8,
+        shards: 4,
+        ..StoreSettings::default()
+    });
+    for i in 0..100 {
+        event(&store, &format!("req-{}", i), "start").unwrap();
+    }
+    assert_eq!(store.len(), 8);
+}
+
+#[test]
+fn should_never_exceed_the_capacity_whatever_the_number_of_shards() {
+    for (capacity, shards) in [(3, 32), (10, 4), (1, 2)] {
+        let store: SpanStore<Vec<String>> = SpanStore::new(StoreSettings {
+            capacity,
+            shards,
+            ..StoreSettings::default()
+        });
+        for i in 0..100 {
+            event(&store, &format!("req-{}", i), "start").unwrap();
+        }
+        assert_eq!(store.len(), capacity, "{} shards", shards);
+    }
+}
+
+#[test]
+#[should_panic(expected = "span store capacity must be at least 1")]
+fn should_refuse_a_store_without_capacity() {
+    store(0, Eviction::Oldest);
+}
+
+#[test]
+fn should_drain_every_request() {
+    let store: SpanStore<Vec<String>> = SpanStore::new(StoreSettings {
+        shards: 4,
+        ..StoreSettings::default()
+    });
+    for i in 0..10 {
+        event(&store, &format!("req-{}", i), "start").unwrap();
+    }
+    let mut keys: Vec<String> = store.drain().into_iter().map(|r| r.key).collect();
+    keys.sort();
+    assert_eq!(keys.len(), 10);
+    assert_eq!(keys[0], "req-0");
+    assert!(store.is_empty());
+}